@@ -0,0 +1,115 @@
+//! Files that are clean even if the engine flags them, e.g. known false positives, see
+//! `--allow-hash-file`.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+
+pub(crate) type Hash = [u8; 32];
+
+#[derive(Debug, Default)]
+pub struct AllowList {
+    hashes: HashSet<Hash>,
+}
+
+impl AllowList {
+    /// Reads SHA-256 hashes, one hex encoded hash per line. Anything after the hash is ignored,
+    /// so `sha256sum` output can be used as is. Empty lines and `#` comments are skipped.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {path:?} allowed hashes"))?;
+
+        let mut hashes = HashSet::new();
+        for (number, line) in content.lines().enumerate() {
+            let Some(hash) = line.split_whitespace().next() else {
+                continue;
+            };
+            if hash.starts_with('#') {
+                continue;
+            }
+
+            match parse_hash(hash) {
+                Some(hash) => hashes.insert(hash),
+                None => bail!("{path:?}:{}: {hash:?} is not a SHA-256 hash", number + 1),
+            };
+        }
+
+        Ok(Self { hashes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Hashes the file, so it's meant for files that were already flagged.
+    pub fn allows(&self, path: &Path) -> io::Result<bool> {
+        if self.hashes.is_empty() {
+            return Ok(false);
+        }
+
+        Ok(self.hashes.contains(&file_hash(path)?))
+    }
+
+    /// Same as [`AllowList::allows`] for content that isn't on disk.
+    pub fn allows_data(&self, data: &[u8]) -> bool {
+        !self.hashes.is_empty() && self.hashes.contains(&Hash::from(Sha256::digest(data)))
+    }
+}
+
+/// SHA-256 of the file content.
+pub(crate) fn file_hash(path: &Path) -> io::Result<Hash> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+fn parse_hash(hex: &str) -> Option<Hash> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::scanner::EICAR;
+
+    #[test]
+    fn allowed_hash_lets_eicar_through() {
+        let dir = TempDir::new("rvfs-allow").unwrap();
+        let eicar = dir.path().join("eicar.com");
+        fs::write(&eicar, EICAR).unwrap();
+        let other = dir.path().join("other");
+        fs::write(&other, "other").unwrap();
+
+        // The way sha256sum prints it
+        let allow_file = dir.path().join("allowed");
+        let hashes = "# known test file\n\
+            275a021bbfb6489e54d471899f7db9d1663fc695ec2fe2a2c4538aabf651fd0f  eicar.com\n";
+        fs::write(&allow_file, hashes).unwrap();
+
+        let allow_list = AllowList::load(&allow_file).unwrap();
+        assert_eq!(allow_list.len(), 1);
+        assert!(allow_list.allows(&eicar).unwrap());
+        assert!(allow_list.allows_data(EICAR.as_bytes()));
+        assert!(!allow_list.allows(&other).unwrap());
+
+        assert!(!AllowList::default().allows(&eicar).unwrap());
+    }
+}
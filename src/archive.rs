@@ -0,0 +1,88 @@
+//! Sizes archives declare for their content, so archive bombs are caught before they're unpacked.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ZIP_LOCAL_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_END_MAGIC: &[u8] = b"PK\x05\x06";
+const ZIP_CENTRAL_MAGIC: &[u8] = b"PK\x01\x02";
+
+/// End of central directory record without the trailing comment.
+const ZIP_END_SIZE: usize = 22;
+const ZIP_MAX_COMMENT_SIZE: usize = u16::MAX as usize;
+/// Central directory file header without the name, extra field and comment.
+const ZIP_CENTRAL_SIZE: usize = 46;
+
+/// Total unpacked size declared by a zip or gzip file, `None` for other files.
+///
+/// Gzip keeps the size modulo 4GiB and zip64 entries are counted as 4GiB, so the size is a lower
+/// bound that can't be inflated by a crafted header.
+pub fn declared_size(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let mut magic = Vec::with_capacity(4);
+    (&mut file).take(4).read_to_end(&mut magic)?;
+
+    if magic.starts_with(ZIP_LOCAL_MAGIC) {
+        zip_size(&mut file).map(Some)
+    } else if magic.starts_with(GZIP_MAGIC) {
+        gzip_size(&mut file).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Size of the last member, it's stored in the last 4 bytes.
+fn gzip_size(file: &mut File) -> io::Result<u64> {
+    let mut size = [0; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut size)?;
+
+    Ok(u64::from(u32::from_le_bytes(size)))
+}
+
+/// Sum of the sizes in the central directory, which the end record at the end of the file
+/// points to.
+fn zip_size(file: &mut File) -> io::Result<u64> {
+    let length = file.seek(SeekFrom::End(0))?;
+    let tail_size = length.min((ZIP_END_SIZE + ZIP_MAX_COMMENT_SIZE) as u64);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::End(-(tail_size as i64)))?;
+    file.take(tail_size).read_to_end(&mut tail)?;
+
+    let last_end = tail.len().checked_sub(ZIP_END_SIZE);
+    let Some(end) = last_end.and_then(|last_end| {
+        (0..=last_end)
+            .rev()
+            .find(|&position| tail[position..].starts_with(ZIP_END_MAGIC))
+    }) else {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    };
+    let directory_size = u32::from_le_bytes(tail[end + 12..end + 16].try_into().unwrap());
+    let directory_offset = u32::from_le_bytes(tail[end + 16..end + 20].try_into().unwrap());
+
+    let mut directory = Vec::new();
+    file.seek(SeekFrom::Start(u64::from(directory_offset)))?;
+    file.take(u64::from(directory_size))
+        .read_to_end(&mut directory)?;
+
+    let mut size = 0u64;
+    let mut position = 0;
+    while let Some(header) = directory.get(position..position + ZIP_CENTRAL_SIZE) {
+        if !header.starts_with(ZIP_CENTRAL_MAGIC) {
+            break;
+        }
+
+        let field = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        size += u64::from(u32::from_le_bytes(header[24..28].try_into().unwrap()));
+        position += ZIP_CENTRAL_SIZE
+            + usize::from(field(28))
+            + usize::from(field(30))
+            + usize::from(field(32));
+    }
+
+    Ok(size)
+}
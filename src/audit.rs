@@ -0,0 +1,83 @@
+//! Trail of changes made through the proxy and who made them, see `--audit-log`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use fuser::Request;
+use log::error;
+
+use crate::error::FuseError;
+
+/// Process a request came from.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+}
+
+/// Appends one line per change: seconds since the epoch, operation, uid, gid and pid of the
+/// caller, proxy path, new proxy path of a rename or `-`, and `ok` or the error, separated by
+/// tabs.
+///
+/// Only the first write through an open file since it was last flushed is recorded, a line per
+/// write call would flood the log.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {path:?} audit log"))?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        operation: &str,
+        caller: Caller,
+        path: &Path,
+        target: Option<&Path>,
+        result: Result<(), &FuseError>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let target = target.map_or("-".into(), Path::to_string_lossy);
+        let result = result.map_or_else(|err| err.to_string(), |()| "ok".to_owned());
+        let line = format!(
+            "{timestamp}\t{operation}\t{}\t{}\t{}\t{}\t{target}\t{result}\n",
+            caller.uid,
+            caller.gid,
+            caller.pid,
+            path.display()
+        );
+
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to record {operation} of {path:?} to the audit log: {err}");
+        }
+    }
+}
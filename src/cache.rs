@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Formatter},
+    hash::Hash,
+    str::FromStr,
+};
+
+use anyhow::bail;
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used entry.
+    #[default]
+    Lru,
+    /// Evicts the least frequently used entry, the least recently used one wins ties.
+    Lfu,
+}
+
+impl EvictionPolicy {
+    fn rank<V>(self, entry: &Entry<V>) -> (u64, u64) {
+        match self {
+            EvictionPolicy::Lru => (0, entry.last_use),
+            EvictionPolicy::Lfu => (entry.uses, entry.last_use),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            other => bail!("{other:?} is not a valid eviction policy(expected lru or lfu)"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "entries: {}, hits: {}, misses: {}, evictions: {}",
+            self.entries, self.hits, self.misses, self.evictions
+        )
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    uses: u64,
+    last_use: u64,
+}
+
+/// Key-value cache that never holds more than `capacity` entries.
+///
+/// Entries are additionally indexed by their eviction rank, so picking a victim doesn't require
+/// a scan over the whole cache.
+pub struct BoundedCache<K, V> {
+    capacity: usize,
+    policy: EvictionPolicy,
+    entries: HashMap<K, Entry<V>>,
+    ranks: BTreeMap<(u64, u64), K>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq + Clone, V> BoundedCache<K, V> {
+    pub fn new(capacity: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            entries: HashMap::with_capacity(capacity),
+            ranks: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    /// Drops all entries, stats are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.ranks.clear();
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.clock += 1;
+
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        self.ranks.remove(&self.policy.rank(entry));
+        entry.uses += 1;
+        entry.last_use = self.clock;
+        self.ranks.insert(self.policy.rank(entry), key.clone());
+
+        self.stats.hits += 1;
+        Some(&entry.value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.clock += 1;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.ranks.remove(&self.policy.rank(entry));
+            entry.value = value;
+            entry.uses += 1;
+            entry.last_use = self.clock;
+            self.ranks.insert(self.policy.rank(entry), key);
+            return;
+        }
+
+        while self.entries.len() >= self.capacity {
+            let Some((_, victim)) = self.ranks.pop_first() else {
+                break;
+            };
+
+            self.entries.remove(&victim);
+            self.stats.evictions += 1;
+        }
+
+        let entry = Entry {
+            value,
+            uses: 1,
+            last_use: self.clock,
+        };
+        self.ranks.insert(self.policy.rank(&entry), key.clone());
+        self.entries.insert(key, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut cache = BoundedCache::new(2, EvictionPolicy::Lru);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+
+        let expected = CacheStats {
+            entries: 2,
+            hits: 3,
+            misses: 1,
+            evictions: 1,
+        };
+        assert_eq!(cache.stats(), expected);
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequently_used() {
+        let mut cache = BoundedCache::new(2, EvictionPolicy::Lfu);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.get(&"a");
+        cache.get(&"a");
+        // Used last, but less often than "a"
+        cache.get(&"b");
+
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        // "c" was never looked up, so it goes even though it was inserted after "a" was used
+        cache.insert("d", 4);
+        assert_eq!(cache.get(&"c"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"d"), Some(&4));
+
+        let expected = CacheStats {
+            entries: 2,
+            hits: 6,
+            misses: 2,
+            evictions: 2,
+        };
+        assert_eq!(cache.stats(), expected);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut cache = BoundedCache::new(0, EvictionPolicy::Lru);
+        cache.insert("a", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
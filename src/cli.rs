@@ -1,25 +1,285 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
 use fuser::MountOption;
+use log::LevelFilter;
+
+use rvfs::{Config, IdMap, ScanMode};
 
 const HELP: &str = "\
 Proxy VFS that focuses on forbitting access malwares
 
-Usage: rvfs [OPTIONS] DEVICE MOUNTPOINT
+Usage: rvfs [OPTIONS] DEVICE... MOUNTPOINT
+       rvfs --check [OPTIONS]
+
+With several devices each one shows up in MOUNTPOINT under its file name, e.g. `rvfs a.img
+b.img /mnt/proxy` exposes /mnt/proxy/a.img and /mnt/proxy/b.img. Nothing can be created next to
+them.
+
+FIFOs, sockets and device nodes on DEVICE are listed, but opening them fails with ENOTSUP.
+
+Every DEVICE item has a read-only user.rvfs.origin extended attribute with its origin path,
+e.g. `getfattr -n user.rvfs.origin MOUNTPOINT/file`. Extended attributes of DEVICE aren't
+exposed.
 
 OPTIONS
        -h  print help.
 
+       --check
+           don't mount anything, only load and compile the signature databases, scan the EICAR
+           test file from disk and from memory and print the signature count and the verdicts.
+           Exits with a non-zero status if ClamAV can't be set up or EICAR isn't detected.
+
+       --json
+           print the --check results and the .rvfs-stats file content as JSON, e.g. to gate CI
+           on `jq .eicar_detected`.
+
        -o OPTION[,OPTION...]
-           mount options(see mount.fuse(8) for list of all options).
+           mount options(see mount.fuse(8) for list of all options). Besides the generic ones,
+           max_read=N, blksize=N and the SELinux context options are known, anything else is
+           passed to fusermount as is with a warning.
+
+       --strict-options
+           reject unknown or malformed mount options instead of warning about them.
+
+       -f, --foreground
+           stay in the foreground(default).
+
+       -d, --daemon
+           run in the background once DEVICE is mounted.
+
+       --pid-file PATH
+           write the process id to PATH, it's removed on unmount.
+
+       --ready-fd N
+           write a newline to the already open fd N and close it once the proxy is mounted and
+           serves requests. Directories are scanned on first access, so this happens right
+           away even for large DEVICE trees.
+
+       --auto-update SECONDS
+           run freshclam(1) every SECONDS, e.g. 3600, and reload the signatures once it
+           downloaded new ones. Only for systems without a freshclam service of their own.
+
+       --metrics-addr ADDR
+           serve Prometheus metrics at http://ADDR/metrics and a liveness check at
+           http://ADDR/health, e.g. 127.0.0.1:9464. Only available if built with the metrics
+           feature.
+
+       --log-file PATH
+           append logs to PATH instead of writing them to stderr. The file is never truncated,
+           rotate it with copytruncate.
+
+       --log-level off|error|warn|info|debug|trace
+           most verbose level that is logged(default: trace for debug builds, info otherwise).
+
+       --scan-cache-size ENTRIES
+           maximum number of scan verdicts kept in memory(default: 4096, 0 disables the cache).
+
+       --scan-cache-policy lru|lfu
+           eviction policy of the scan cache(default: lru).
+
+       --source-type loop|dir|bind
+           what DEVICE is(default: loop):
+               loop    a device or an image file, mounted through a loop device
+               dir     a directory that is used in place without mounting anything, takes a
+                       single DEVICE; --read-only changes are rejected by the proxy only
+               bind    directories that are bind mounted, several ones can be combined
+
+       --uid-map SRC:DST
+           report items owned by SRC uid on DEVICE as owned by DST uid, e.g. 1000:0 for an
+           image made on another system. Owners set through the proxy are translated back.
+           Can be repeated, uids without a mapping are reported as they are.
+
+       --gid-map SRC:DST
+           same as --uid-map for gids.
+
+       --read-only
+           reject all changes with EROFS, regardless of the mount options.
+
+       --overlay
+           mount DEVICE read-only and keep all changes in a temporary writable layer.
+
+       --overlay-size BYTES
+           keep the --overlay writable layer in memory, in a tmpfs of BYTES size, instead of a
+           temporary directory on disk. Writes beyond it fail with ENOSPC.
+
+       --work-dir PATH
+           create the temporary origin mount point and the --overlay writable layer in PATH
+           (default: /mnt, or the system temporary directory if /mnt isn't writable).
+
+       --unmount-timeout SECONDS
+           how long to wait for open files to be closed on Ctrl-C before reporting the mount
+           as busy(default: 5). Running scans get as long again to finish before the unmount.
+
+       --leave-block-stub
+           show a read-only NAME.rvfs-blocked file in place of each blocked file that explains
+           the detection.
+
+       --max-scan-size BYTES
+           skip scanning files larger than BYTES(default: no limit).
+
+       --oversized-policy allow|block
+           what happens to files skipped because of --max-scan-size(default: allow).
+
+       --scan-timeout SECONDS
+           give up on scans that take longer than SECONDS, e.g. because a malformed file keeps
+           the engine busy, and apply --timeout-policy instead(default: no limit). The abandoned
+           scan keeps running in the background until the engine is done with it.
+
+       --timeout-policy allow|block
+           what happens to files whose scan hit --scan-timeout(default: block).
+
+       --scan-rate N
+           start at most N scans per second, so exploring large directories doesn't take over
+           the CPU. Up to N scans start at once, the following ones wait for their turn. Cached
+           verdicts aren't limited(default: no limit).
+
+       --scan-types TYPE[,TYPE...]
+           scan only files of the listed types and treat all others as clean(default: all).
+           Types are detected by the leading bytes of a file, not its name:
+               exec       ELF, PE, Mach-O binaries and #! scripts
+               archive    zip, gzip, bzip2, xz, zstd, 7z, rar, tar, cab and OLE2 documents
+               all        every file
+
+       --max-archive-depth N
+           unpack archives nested at most N levels deep(default: 16).
+
+       --max-archive-files N
+           unpack at most N files from a single archive(default: 10000).
+
+       --max-archive-ratio R
+           block zip and gzip files that declare more than R times their own size of content,
+           e.g. archive bombs(default: 100, 0 disables the check).
+
+           Archives that go over any of the limits are blocked.
+
+       --allow-hash-file PATH
+           treat files whose SHA-256 hash is listed in PATH as clean even if they're detected,
+           e.g. known false positives. PATH has one hex encoded hash per line, anything after
+           the hash is ignored, so sha256sum(1) output works as is. Empty lines and lines
+           starting with # are skipped. Files are hashed only once they're detected. The file
+           is read again on SIGHUP.
+
+       --detections-log PATH
+           append a line to PATH for every detection: seconds since the epoch, DEVICE path of
+           the file and the signature name, separated by tabs.
+
+       --audit-log PATH
+           append a line to PATH for every create, mkdir, write, unlink, rmdir and rename made
+           through the proxy: seconds since the epoch, operation, uid, gid and pid of the
+           caller, path, new path of a rename or -, and ok or the error, separated by tabs.
+           Writes are recorded once per open file until it's flushed. Off by default.
+
+       --exclude PATTERN
+           don't scan files whose path relative to DEVICE root matches the glob PATTERN, e.g.
+           '*.log' or 'cache/**'. Can be repeated, a file is excluded if any pattern matches.
+
+       --write-buffer BYTES
+           coalesce contiguous writes of an open file in a buffer of BYTES size before writing
+           them to DEVICE(default: 0, disabled). A file written whole within the buffer is
+           scanned in memory, so infected content never reaches DEVICE.
+
+       --scan-threads N
+           scan up to N files of a listed directory at once(default: number of CPUs).
+
+       --attr-passthrough
+           stat DEVICE files on every getattr, so changes made to DEVICE outside of the proxy
+           show up right away at the cost of a syscall.
+
+       --max-open-files N
+           keep at most N DEVICE files open, the least recently used ones are closed and
+           reopened on their next use(default: 0, no limit).
+
+       --max-inodes N
+           keep at most N items in memory, the least recently used ones that aren't open and
+           have no items of their own are dropped and listed from DEVICE again on their next
+           lookup(default: 0, no limit).
+
+       --max-read-chunk BYTES
+           read DEVICE files in chunks of at most BYTES, so a large read of a short file
+           allocates only what it needs(default: 131072).
+
+       --scan-mode eager|lazy
+           scan files when their directory is listed(eager, default) or the first time they're
+           opened(lazy). Lazily blocked files stay listed, but can't be opened.
+
+       --scan-new-only
+           trust files that are already on DEVICE, e.g. an image that was scanned offline, and
+           scan only content created or modified through the proxy. Listing is as fast as with
+           --no-scan. Conflicts with --scan-mode.
+
+       --allow-exec-scan
+           scan a file right before it's executed even if it was trusted without a scan, e.g.
+           because of --scan-new-only, --exclude or --scan-types, and deny execution of
+           infected ones with EACCES. Lazily scanned files are always scanned on first open.
+
+       --verify-checksums
+           keep a SHA-256 of every file taken on its first open, right after the scan in lazy
+           mode, and fail later opens with EIO if the content changed on DEVICE behind the
+           proxy's back. Changes made through the proxy take a new checksum. Every open of a
+           file that isn't open already reads it as a whole, so it's off by default.
+
+       --state-file PATH
+           save the known items and their scan verdicts to PATH on unmount and restore them on
+           the next mount, so unchanged files aren't scanned again. The state is ignored if
+           signatures or scan options changed since.
+
+       --no-scan
+           don't scan anything and treat all files as clean, ClamAV isn't even loaded. Meant for
+           debugging and measuring the proxy overhead.
+
+       --entry-ttl SECONDS
+           how long the kernel caches looked up names, fractions are allowed(default: 1).
+
+       --attr-ttl SECONDS
+           how long the kernel caches attributes, fractions are allowed(default: 1).
+
+           Longer TTLs save requests on read-heavy workloads, but changes made to DEVICE behind
+           the proxy's back, e.g. with --attr-passthrough or --watch, show up only once the
+           cached entries expire. 0 disables caching.
+
+       --follow-mounts
+           list directories of file systems that are mounted inside DEVICE, e.g. nested bind
+           or loop mounts. By default the proxy stays on DEVICE's own file system like
+           find -xdev: such mountpoints show up as empty directories.
+
+       --watch
+           watch origin directories with inotify and pick up items created or removed on the
+           origin behind the proxy's back before the next lookup, instead of only when the
+           directory is listed again. If the inotify watch limit is reached, the remaining
+           directories are synced only when listed.
+
+FILES
+       MOUNTPOINT/.rvfs-stats
+           read-only file with scan statistics since mount: scanned files, verdicts, time spent
+           scanning and scan cache stats. It's a JSON object with --json.
+
+       MOUNTPOINT/.rvfs-control
+           takes commands on write, one per line:
+               rescan PATH   scan PATH subtree again, PATH is relative to MOUNTPOINT.
+           Newly detected files are dropped from the proxy.
+
+SIGNALS
+       SIGINT, SIGTERM
+           unmount once all open files are closed and running scans finish.
+
+       SIGHUP
+           reload signature databases and allowed hashes without remounting.
 ";
 
 #[derive(Debug)]
 pub struct Cli {
-    pub device: PathBuf,
+    /// Only validate the scanner setup, no devices or mountpoint are given then.
+    pub check: bool,
+    /// Devices with the proxy subdirectory each one is exposed in, empty for a single device.
+    pub sources: Vec<(PathBuf, PathBuf)>,
     pub mountpoint: PathBuf,
     pub options: Vec<MountOption>,
+    pub config: Config,
+    pub daemon: bool,
+    pub pid_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+    pub log_level: Option<LevelFilter>,
 }
 
 impl Cli {
@@ -31,24 +291,327 @@ impl Cli {
             std::process::exit(0);
         }
 
+        let check = pargs.contains("--check");
+
+        let strict_options = pargs.contains("--strict-options");
         let mut options = Vec::new();
         while let Some(raw_options) = pargs
             .opt_value_from_str::<&str, String>("-o")
             .context("Unable to get mount options")?
         {
             for option in raw_options.split(',').flat_map(str::split_whitespace) {
-                options.push(mount_option_from_str(option))
+                let option = mount_option_from_str(option);
+                if let MountOption::CUSTOM(custom) = &option {
+                    if let Err(err) = check_custom_option(custom) {
+                        if strict_options {
+                            bail!("{err:#}");
+                        }
+                        // The logger isn't set up yet
+                        eprintln!("warning: {err:#}, passing it to fusermount as is");
+                    }
+                }
+                options.push(option);
             }
         }
 
-        let device = pargs
-            .free_from_str::<PathBuf>()
-            .context("Unable to get device path")?;
-        ensure!(device.exists(), "{:?} device path doesn't exists", device);
+        let foreground = pargs.contains(["-f", "--foreground"]);
+        let daemon = pargs.contains(["-d", "--daemon"]);
+        ensure!(
+            !(foreground && daemon),
+            "--foreground and --daemon are mutually exclusive"
+        );
+
+        let pid_file = pargs
+            .opt_value_from_str("--pid-file")
+            .context("Unable to get pid file path")?;
+
+        let log_file = pargs
+            .opt_value_from_str("--log-file")
+            .context("Unable to get log file path")?;
+        let log_level = pargs
+            .opt_value_from_str("--log-level")
+            .context("Unable to get log level")?;
+
+        let mut config = Config::default();
+
+        if let Some(scan_cache_size) = pargs
+            .opt_value_from_str("--scan-cache-size")
+            .context("Unable to get scan cache size")?
+        {
+            config.scan_cache_size = scan_cache_size;
+        }
+
+        if let Some(scan_cache_policy) = pargs
+            .opt_value_from_str("--scan-cache-policy")
+            .context("Unable to get scan cache policy")?
+        {
+            config.scan_cache_policy = scan_cache_policy;
+        }
+
+        config.read_only = pargs.contains("--read-only");
+        config.overlay = pargs.contains("--overlay");
+        config.leave_block_stub = pargs.contains("--leave-block-stub");
+        config.attr_passthrough = pargs.contains("--attr-passthrough");
+        config.no_scan = pargs.contains("--no-scan");
+        config.watch = pargs.contains("--watch");
+        config.follow_mounts = pargs.contains("--follow-mounts");
+        config.allow_exec_scan = pargs.contains("--allow-exec-scan");
+        config.verify_checksums = pargs.contains("--verify-checksums");
+        config.json = pargs.contains("--json");
+
+        if let Some(source_type) = pargs
+            .opt_value_from_str("--source-type")
+            .context("Unable to get source type")?
+        {
+            config.source_type = source_type;
+        }
+
+        if let Some(unmount_timeout) = pargs
+            .opt_value_from_str("--unmount-timeout")
+            .context("Unable to get unmount timeout")?
+        {
+            config.unmount_timeout = Duration::from_secs(unmount_timeout);
+        }
+
+        config.overlay_size = pargs
+            .opt_value_from_str("--overlay-size")
+            .context("Unable to get overlay size")?;
+        ensure!(
+            config.overlay || config.overlay_size.is_none(),
+            "--overlay-size requires --overlay"
+        );
+
+        config.work_dir = pargs
+            .opt_value_from_str("--work-dir")
+            .context("Unable to get work directory")?;
+
+        config.max_scan_size = pargs
+            .opt_value_from_str("--max-scan-size")
+            .context("Unable to get max scan size")?;
+
+        if let Some(oversized_policy) = pargs
+            .opt_value_from_str("--oversized-policy")
+            .context("Unable to get oversized policy")?
+        {
+            config.oversized_policy = oversized_policy;
+        }
+
+        config.scan_timeout = pargs
+            .opt_value_from_fn("--scan-timeout", parse_seconds)
+            .context("Unable to get scan timeout")?;
+        if config
+            .scan_timeout
+            .is_some_and(|scan_timeout| scan_timeout.is_zero())
+        {
+            bail!("Scan timeout must be above 0");
+        }
+
+        if let Some(timeout_policy) = pargs
+            .opt_value_from_str("--timeout-policy")
+            .context("Unable to get timeout policy")?
+        {
+            config.timeout_policy = timeout_policy;
+        }
+
+        config.scan_rate = pargs
+            .opt_value_from_str("--scan-rate")
+            .context("Unable to get scan rate")?;
+        ensure!(
+            config.scan_rate != Some(0),
+            "--scan-rate must be at least 1"
+        );
+
+        if let Some(scan_types) = pargs
+            .opt_value_from_str("--scan-types")
+            .context("Unable to get scan types")?
+        {
+            config.scan_types = scan_types;
+        }
+
+        if let Some(max_depth) = pargs
+            .opt_value_from_str("--max-archive-depth")
+            .context("Unable to get max archive depth")?
+        {
+            config.archive_limits.max_depth = max_depth;
+        }
+
+        if let Some(max_files) = pargs
+            .opt_value_from_str("--max-archive-files")
+            .context("Unable to get max archive files")?
+        {
+            config.archive_limits.max_files = max_files;
+        }
+
+        if let Some(max_ratio) = pargs
+            .opt_value_from_str::<_, f64>("--max-archive-ratio")
+            .context("Unable to get max archive ratio")?
+        {
+            ensure!(max_ratio >= 0.0, "--max-archive-ratio can't be negative");
+            config.archive_limits.max_ratio = max_ratio;
+        }
+
+        if let Some(entry_ttl) = pargs
+            .opt_value_from_fn("--entry-ttl", parse_seconds)
+            .context("Unable to get entry TTL")?
+        {
+            config.entry_ttl = entry_ttl;
+        }
+
+        if let Some(attr_ttl) = pargs
+            .opt_value_from_fn("--attr-ttl", parse_seconds)
+            .context("Unable to get attribute TTL")?
+        {
+            config.attr_ttl = attr_ttl;
+        }
+
+        config.allow_hash_file = pargs
+            .opt_value_from_str("--allow-hash-file")
+            .context("Unable to get allowed hashes file path")?;
+
+        config.detections_log = pargs
+            .opt_value_from_str("--detections-log")
+            .context("Unable to get detections log path")?;
+
+        config.audit_log = pargs
+            .opt_value_from_str("--audit-log")
+            .context("Unable to get audit log path")?;
+
+        config.uid_map = IdMap::new(
+            pargs
+                .values_from_str("--uid-map")
+                .context("Unable to get uid mappings")?,
+        )
+        .context("Invalid uid mappings")?;
+        config.gid_map = IdMap::new(
+            pargs
+                .values_from_str("--gid-map")
+                .context("Unable to get gid mappings")?,
+        )
+        .context("Invalid gid mappings")?;
+
+        config.exclude = pargs
+            .values_from_str("--exclude")
+            .context("Unable to get exclude patterns")?;
+
+        if let Some(write_buffer) = pargs
+            .opt_value_from_str("--write-buffer")
+            .context("Unable to get write buffer size")?
+        {
+            config.write_buffer = write_buffer;
+        }
+
+        if let Some(scan_threads) = pargs
+            .opt_value_from_str("--scan-threads")
+            .context("Unable to get scan threads count")?
+        {
+            ensure!(scan_threads > 0, "--scan-threads must be at least 1");
+            config.scan_threads = scan_threads;
+        }
+
+        if let Some(max_open_files) = pargs
+            .opt_value_from_str("--max-open-files")
+            .context("Unable to get max open files")?
+        {
+            config.max_open_files = max_open_files;
+        }
+
+        if let Some(max_inodes) = pargs
+            .opt_value_from_str("--max-inodes")
+            .context("Unable to get max inodes")?
+        {
+            config.max_inodes = max_inodes;
+        }
+
+        if let Some(max_read_chunk) = pargs
+            .opt_value_from_str("--max-read-chunk")
+            .context("Unable to get max read chunk")?
+        {
+            ensure!(max_read_chunk > 0, "--max-read-chunk must be at least 1");
+            config.max_read_chunk = max_read_chunk;
+        }
+
+        let scan_new_only = pargs.contains("--scan-new-only");
+        if let Some(scan_mode) = pargs
+            .opt_value_from_str("--scan-mode")
+            .context("Unable to get scan mode")?
+        {
+            ensure!(!scan_new_only, "--scan-new-only conflicts with --scan-mode");
+            config.scan_mode = scan_mode;
+        }
+        if scan_new_only {
+            config.scan_mode = ScanMode::NewOnly;
+        }
+
+        config.state_file = pargs
+            .opt_value_from_str("--state-file")
+            .context("Unable to get state file path")?;
+
+        config.ready_fd = pargs
+            .opt_value_from_str("--ready-fd")
+            .context("Unable to get readiness fd")?;
+        if let Some(ready_fd) = config.ready_fd {
+            ensure!(
+                unsafe { libc::fcntl(ready_fd, libc::F_GETFD) } != -1,
+                "{ready_fd} readiness fd isn't open"
+            );
+        }
+
+        config.auto_update = pargs
+            .opt_value_from_fn("--auto-update", parse_seconds)
+            .context("Unable to get auto update interval")?;
+        if config
+            .auto_update
+            .is_some_and(|interval| interval.is_zero())
+        {
+            bail!("Auto update interval must be above 0");
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            config.metrics_addr = pargs
+                .opt_value_from_str("--metrics-addr")
+                .context("Unable to get metrics address")?;
+        }
 
-        let mountpoint = pargs
-            .free_from_str::<PathBuf>()
-            .context("Unable to get mountpoint path")?;
+        let mut free = pargs.finish();
+        if let Some(unknown) = free
+            .iter()
+            .find(|arg| arg.to_string_lossy().starts_with('-'))
+        {
+            bail!("Unknown {unknown:?} option");
+        }
+
+        if check {
+            ensure!(
+                !config.no_scan,
+                "--check and --no-scan are mutually exclusive"
+            );
+            ensure!(
+                free.is_empty(),
+                "--check takes no device and mountpoint paths"
+            );
+
+            return Ok(Cli {
+                check,
+                sources: Vec::new(),
+                mountpoint: PathBuf::new(),
+                options,
+                config,
+                daemon,
+                pid_file,
+                log_file,
+                log_level,
+            });
+        }
+
+        ensure!(free.len() >= 2, "Unable to get device and mountpoint paths");
+
+        let mountpoint = PathBuf::from(free.pop().unwrap());
+        let devices: Vec<_> = free.into_iter().map(PathBuf::from).collect();
+        for device in &devices {
+            ensure!(device.exists(), "{:?} device path doesn't exists", device);
+        }
+        let sources = device_subdirs(devices)?;
 
         ensure!(
             mountpoint.exists(),
@@ -57,9 +620,15 @@ impl Cli {
         );
 
         Ok(Cli {
-            device,
+            check,
+            sources,
             mountpoint,
             options,
+            config,
+            daemon,
+            pid_file,
+            log_file,
+            log_level,
         })
     }
 }
@@ -88,3 +657,114 @@ pub fn mount_option_from_str(s: &str) -> MountOption {
         x => MountOption::CUSTOM(x.into()),
     }
 }
+
+/// Options without a [`MountOption`] variant that fusermount and the kernel understand.
+const CUSTOM_OPTIONS: [&str; 6] = [
+    "max_read",
+    "blksize",
+    "context",
+    "fscontext",
+    "defcontext",
+    "rootcontext",
+];
+
+/// Custom options that take a number.
+const NUMERIC_OPTIONS: [&str; 2] = ["max_read", "blksize"];
+
+/// Catches typos in options that would otherwise reach fusermount unnoticed, e.g. `alow_other`.
+fn check_custom_option(option: &str) -> anyhow::Result<()> {
+    let (key, value) = option.split_once('=').unwrap_or((option, ""));
+    ensure!(
+        CUSTOM_OPTIONS.contains(&key),
+        "unknown mount option {key:?}"
+    );
+    ensure!(!value.is_empty(), "{key} mount option takes a value");
+
+    if NUMERIC_OPTIONS.contains(&key) {
+        value
+            .parse::<u32>()
+            .with_context(|| format!("{key} mount option takes a number, got {value:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Non-negative number of seconds, e.g. `0.5`.
+fn parse_seconds(s: &str) -> anyhow::Result<Duration> {
+    let seconds: f64 = s
+        .parse()
+        .with_context(|| format!("{s:?} is not a number"))?;
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("{s:?} is not a valid duration"))
+}
+
+/// Pairs devices with the proxy subdirectories they're exposed in, a single device is the root.
+/// Devices with the same file name get a `-N` suffix.
+fn device_subdirs(devices: Vec<PathBuf>) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    if devices.len() == 1 {
+        return Ok(devices
+            .into_iter()
+            .map(|device| (device, PathBuf::new()))
+            .collect());
+    }
+
+    let mut sources: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(devices.len());
+    for device in devices {
+        let name = device
+            .file_name()
+            .with_context(|| format!("{device:?} device path has no file name"))?;
+
+        let mut subdir = PathBuf::from(name);
+        let mut suffix = 1;
+        while sources.iter().any(|(_, taken)| *taken == subdir) {
+            suffix += 1;
+            subdir = PathBuf::from(format!("{}-{suffix}", name.to_string_lossy()));
+        }
+
+        sources.push((device, subdir));
+    }
+
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_options_are_checked() {
+        for allowed in [
+            "max_read=131072",
+            "blksize=4096",
+            "context=system_u:object_r:tmp_t:s0",
+            "rootcontext=system_u:object_r:tmp_t:s0",
+        ] {
+            assert!(
+                check_custom_option(allowed).is_ok(),
+                "{allowed:?} was rejected"
+            );
+        }
+
+        for rejected in [
+            "alow_other",
+            "max_read",
+            "max_read=",
+            "max_read=big",
+            "blksize=-1",
+            "context",
+        ] {
+            assert!(
+                check_custom_option(rejected).is_err(),
+                "{rejected:?} was allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn ttl_seconds_are_parsed() {
+        assert_eq!(parse_seconds("3600").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_seconds("0.25").unwrap(), Duration::from_millis(250));
+        for rejected in ["-1", "soon", "inf"] {
+            assert!(parse_seconds(rejected).is_err(), "{rejected:?} was allowed");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    net::SocketAddr, num::NonZeroUsize, os::fd::RawFd, path::PathBuf, thread, time::Duration,
+};
+
+use crate::{
+    cache::EvictionPolicy,
+    idmap::IdMap,
+    origin::SourceType,
+    scanner::{ArchiveLimits, OversizedPolicy, ScanMode, ScanTypes, TimeoutPolicy},
+};
+
+pub const DEFAULT_SCAN_CACHE_SIZE: usize = 4096;
+pub const DEFAULT_UNMOUNT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_READ_CHUNK: usize = 128 * 1024;
+
+/// Proxy settings that aren't passed to FUSE as mount options.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub source_type: SourceType,
+    /// Maximum amount of scan verdicts kept in memory, `0` disables the cache.
+    pub scan_cache_size: usize,
+    pub scan_cache_policy: EvictionPolicy,
+    /// Owners of origin items as they're reported in the proxy.
+    pub uid_map: IdMap,
+    /// Groups of origin items as they're reported in the proxy.
+    pub gid_map: IdMap,
+    /// Reject all changes, the origin is mounted read-only.
+    pub read_only: bool,
+    /// Keep the origin read-only and write all changes into a temporary upper layer.
+    pub overlay: bool,
+    /// Keep the overlay upper layer in a tmpfs of this many bytes instead of on disk.
+    pub overlay_size: Option<u64>,
+    /// Where the origin mount point and the overlay upper layer are created, `None` means /mnt
+    /// or the system temporary directory.
+    pub work_dir: Option<PathBuf>,
+    /// How long to wait for open files to be closed before giving up on unmounting.
+    pub unmount_timeout: Duration,
+    /// Leave an explanation stub in place of files blocked because of a detection.
+    pub leave_block_stub: bool,
+    /// Files larger than this many bytes aren't scanned, `None` means no limit.
+    pub max_scan_size: Option<u64>,
+    pub oversized_policy: OversizedPolicy,
+    /// Scans that take longer are abandoned, `None` means no limit.
+    pub scan_timeout: Option<Duration>,
+    pub timeout_policy: TimeoutPolicy,
+    /// Most engine scans started per second, `None` means no limit.
+    pub scan_rate: Option<u32>,
+    /// Kinds of files that are scanned, others are treated as clean.
+    pub scan_types: ScanTypes,
+    pub archive_limits: ArchiveLimits,
+    /// SHA-256 hashes of files that are clean even if they're detected.
+    pub allow_hash_file: Option<PathBuf>,
+    /// File every detection is appended to.
+    pub detections_log: Option<PathBuf>,
+    /// File every change made through the proxy is appended to, along with who made it.
+    pub audit_log: Option<PathBuf>,
+    /// Glob patterns of origin-relative paths that are never scanned.
+    pub exclude: Vec<String>,
+    /// Size of per-handle buffers that coalesce contiguous writes, `0` disables buffering.
+    pub write_buffer: usize,
+    /// How many items of a listed directory are scanned at once.
+    pub scan_threads: usize,
+    /// Re-stat the origin on every getattr instead of serving cached attributes.
+    pub attr_passthrough: bool,
+    /// Maximum amount of origin files kept open at once, `0` means no limit.
+    pub max_open_files: usize,
+    /// Maximum amount of items kept in the inode graph, `0` means no limit.
+    pub max_inodes: usize,
+    /// Largest buffer allocated at once for a read, larger reads are done in chunks of it.
+    pub max_read_chunk: usize,
+    pub scan_mode: ScanMode,
+    /// Scan files opened for execution even if they were trusted without a scan.
+    pub allow_exec_scan: bool,
+    /// Refuse to open files whose content changed on the origin since they were first opened.
+    pub verify_checksums: bool,
+    /// The stats file and `--check` print JSON instead of text.
+    pub json: bool,
+    /// Where the inode graph is kept between mounts.
+    pub state_file: Option<PathBuf>,
+    /// Treat every file as clean without initializing ClamAV.
+    pub no_scan: bool,
+    /// Sync the proxy with origin changes made behind its back as they happen.
+    pub watch: bool,
+    /// List directories of file systems mounted inside the origin instead of leaving them empty.
+    pub follow_mounts: bool,
+    /// How long the kernel caches names looked up in the proxy.
+    pub entry_ttl: Duration,
+    /// How long the kernel caches item attributes.
+    pub attr_ttl: Duration,
+    /// How often freshclam is run to update signatures, `None` leaves updates to the system.
+    pub auto_update: Option<Duration>,
+    /// Fd a newline is written to once the proxy is mounted, it's closed afterwards.
+    pub ready_fd: Option<RawFd>,
+    /// Where metrics are served over HTTP, always `None` without the metrics feature.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            source_type: SourceType::default(),
+            scan_cache_size: DEFAULT_SCAN_CACHE_SIZE,
+            scan_cache_policy: EvictionPolicy::default(),
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            read_only: false,
+            overlay: false,
+            overlay_size: None,
+            work_dir: None,
+            unmount_timeout: DEFAULT_UNMOUNT_TIMEOUT,
+            leave_block_stub: false,
+            max_scan_size: None,
+            oversized_policy: OversizedPolicy::default(),
+            scan_timeout: None,
+            scan_rate: None,
+            timeout_policy: TimeoutPolicy::default(),
+            scan_types: ScanTypes::default(),
+            archive_limits: ArchiveLimits::default(),
+            allow_hash_file: None,
+            detections_log: None,
+            audit_log: None,
+            exclude: Vec::new(),
+            write_buffer: 0,
+            scan_threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            attr_passthrough: false,
+            max_open_files: 0,
+            max_inodes: 0,
+            max_read_chunk: DEFAULT_MAX_READ_CHUNK,
+            scan_mode: ScanMode::default(),
+            allow_exec_scan: false,
+            verify_checksums: false,
+            json: false,
+            state_file: None,
+            no_scan: false,
+            watch: false,
+            follow_mounts: false,
+            entry_ttl: DEFAULT_TTL,
+            attr_ttl: DEFAULT_TTL,
+            auto_update: None,
+            ready_fd: None,
+            metrics_addr: None,
+        }
+    }
+}
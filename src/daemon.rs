@@ -0,0 +1,48 @@
+use std::{fs, io, os::fd::AsRawFd, path::Path, process};
+
+use anyhow::Context;
+
+/// Detaches the process from the terminal, only the daemon returns from here.
+///
+/// Double fork: the first child starts a new session, the second one can never reacquire a
+/// controlling terminal. Both parents exit right away without running destructors, so mounts
+/// they share with the daemon stay in place.
+pub fn daemonize() -> anyhow::Result<()> {
+    fork_and_exit_parent().context("Failed to fork")?;
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("Failed to start a new session");
+    }
+
+    fork_and_exit_parent().context("Failed to fork")?;
+
+    redirect_stdio().context("Failed to redirect standard streams")
+}
+
+pub fn write_pid_file(path: &Path) -> anyhow::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+        .with_context(|| format!("Failed to write {path:?} pid file"))
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect_stdio() -> io::Result<()> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Audit trail of detections, see `--detections-log`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::error;
+
+/// Appends one line per detection: seconds since the epoch, origin path and signature name,
+/// separated by tabs.
+pub struct DetectionLog {
+    file: Mutex<File>,
+}
+
+impl DetectionLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {path:?} detections log"))?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Failing to record a detection doesn't change the verdict, the file stays blocked.
+    pub fn record(&self, item: &Path, signature: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let line = format!("{timestamp}\t{}\t{signature}\n", item.display());
+
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!(
+                "Failed to record {signature} detection in {item:?} to the detections log: {err}"
+            );
+        }
+    }
+}
@@ -18,6 +18,7 @@ impl FuseError {
     pub const PERMISSION_DENIED: Self = FuseError(libc::EACCES);
     pub const BAD_ADDRESS: Self = FuseError(libc::EFAULT);
     pub const FILE_EXISTS: Self = FuseError(libc::EEXIST);
+    pub const BUSY: Self = FuseError(libc::EBUSY);
     pub const NO_SUCH_DEVICE: Self = FuseError(libc::ENODEV);
     pub const NOT_DIRECTORY: Self = FuseError(libc::ENOTDIR);
     pub const IS_DIRECTORY: Self = FuseError(libc::EISDIR);
@@ -26,14 +27,43 @@ impl FuseError {
     pub const ILLEGAL_SEEK: Self = FuseError(libc::ESPIPE);
     pub const READ_ONLY_FILE_SYSTEM: Self = FuseError(libc::EROFS);
     pub const NOT_IMPLEMENTED: Self = FuseError(libc::ENOSYS);
+    pub const CROSS_DEVICE_LINK: Self = FuseError(libc::EXDEV);
+    pub const NO_SPACE: Self = FuseError(libc::ENOSPC);
+    pub const NOT_SUPPORTED: Self = FuseError(libc::EOPNOTSUPP);
+    pub const OUT_OF_MEMORY: Self = FuseError(libc::ENOMEM);
+    pub const TOO_MANY_OPEN_FILES: Self = FuseError(libc::EMFILE);
+    pub const FILE_TABLE_OVERFLOW: Self = FuseError(libc::ENFILE);
+    pub const TOO_MANY_SYMLINKS: Self = FuseError(libc::ELOOP);
+    pub const NAME_TOO_LONG: Self = FuseError(libc::ENAMETOOLONG);
+    pub const STALE_HANDLE: Self = FuseError(libc::ESTALE);
 
     pub const DIRECTORY_NOT_EMPTY: Self = FuseError(libc::ENOTEMPTY);
+    pub const NO_DATA: Self = FuseError(libc::ENODATA);
+    pub const OUT_OF_RANGE: Self = FuseError(libc::ERANGE);
 
     pub const BAD_FD: Self = FuseError(libc::EBADF);
 
     pub fn last() -> Self {
         let error = io::Error::last_os_error();
-        Self(error.raw_os_error().unwrap())
+        Self::from_origin_errno(error.raw_os_error().unwrap())
+    }
+
+    /// Errno of a failed origin operation, `ESTALE` becomes EIO since the kernel doesn't expect
+    /// it from FUSE.
+    fn from_origin_errno(errno: c_int) -> Self {
+        match errno {
+            libc::ESTALE => FuseError::IO,
+            errno => FuseError(errno),
+        }
+    }
+
+    /// Whether the error could mean the whole origin is gone rather than a single item, see
+    /// [`crate::origin::OriginHealth`].
+    pub(crate) fn hints_lost_origin(&self) -> bool {
+        matches!(
+            self.0,
+            libc::ENODEV | libc::ENOTCONN | libc::ESTALE | libc::ENOENT | libc::EIO
+        )
     }
 }
 
@@ -49,6 +79,7 @@ impl AsRef<str> for FuseError {
             FuseError::PERMISSION_DENIED => "Permission denied",
             FuseError::BAD_ADDRESS => "Bad address",
             FuseError::FILE_EXISTS => "File exists",
+            FuseError::BUSY => "Device or resource busy",
             FuseError::NO_SUCH_DEVICE => "No such device",
             FuseError::NOT_DIRECTORY => "Not a directory",
             FuseError::IS_DIRECTORY => "Is a directory",
@@ -57,7 +88,18 @@ impl AsRef<str> for FuseError {
             FuseError::ILLEGAL_SEEK => "Illegal seek",
             FuseError::READ_ONLY_FILE_SYSTEM => "Read-only file system",
             FuseError::NOT_IMPLEMENTED => "Function is not implemented",
+            FuseError::CROSS_DEVICE_LINK => "Invalid cross-device link",
+            FuseError::NO_SPACE => "No space left on device",
+            FuseError::NOT_SUPPORTED => "Operation not supported",
+            FuseError::OUT_OF_MEMORY => "Cannot allocate memory",
+            FuseError::TOO_MANY_OPEN_FILES => "Too many open files",
+            FuseError::FILE_TABLE_OVERFLOW => "Too many open files in system",
+            FuseError::TOO_MANY_SYMLINKS => "Too many levels of symbolic links",
+            FuseError::NAME_TOO_LONG => "File name too long",
+            FuseError::STALE_HANDLE => "Stale file handle",
             FuseError::DIRECTORY_NOT_EMPTY => "Directory is not empty",
+            FuseError::NO_DATA => "No data available",
+            FuseError::OUT_OF_RANGE => "Numerical result out of range",
             _ => "UNKNOWN",
         }
     }
@@ -75,6 +117,33 @@ impl From<c_int> for FuseError {
     }
 }
 
+/// Errno of OS errors is kept as is, other errors are mapped by their kind.
+impl From<io::Error> for FuseError {
+    fn from(error: io::Error) -> Self {
+        if let Some(errno) = error.raw_os_error() {
+            return FuseError::from_origin_errno(errno);
+        }
+
+        match error.kind() {
+            io::ErrorKind::NotFound => FuseError::NO_EXIST,
+            io::ErrorKind::PermissionDenied => FuseError::PERMISSION_DENIED,
+            io::ErrorKind::AlreadyExists => FuseError::FILE_EXISTS,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => FuseError::INVALID_ARGUMENT,
+            io::ErrorKind::Unsupported => FuseError::NOT_IMPLEMENTED,
+            io::ErrorKind::NotADirectory => FuseError::NOT_DIRECTORY,
+            io::ErrorKind::IsADirectory => FuseError::IS_DIRECTORY,
+            io::ErrorKind::DirectoryNotEmpty => FuseError::DIRECTORY_NOT_EMPTY,
+            io::ErrorKind::ReadOnlyFilesystem => FuseError::READ_ONLY_FILE_SYSTEM,
+            io::ErrorKind::CrossesDevices => FuseError::CROSS_DEVICE_LINK,
+            io::ErrorKind::ResourceBusy => FuseError::BUSY,
+            io::ErrorKind::FileTooLarge => FuseError::FILE_TOO_LARGE,
+            io::ErrorKind::StorageFull => FuseError::NO_SPACE,
+            io::ErrorKind::OutOfMemory => FuseError::OUT_OF_MEMORY,
+            _ => FuseError::IO,
+        }
+    }
+}
+
 impl From<FuseError> for c_int {
     fn from(value: FuseError) -> Self {
         value.0
@@ -82,3 +151,78 @@ impl From<FuseError> for c_int {
 }
 
 impl Error for FuseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_errno() {
+        let kinds = [
+            (io::ErrorKind::NotFound, FuseError::NO_EXIST),
+            (
+                io::ErrorKind::PermissionDenied,
+                FuseError::PERMISSION_DENIED,
+            ),
+            (io::ErrorKind::AlreadyExists, FuseError::FILE_EXISTS),
+            (io::ErrorKind::InvalidInput, FuseError::INVALID_ARGUMENT),
+            (io::ErrorKind::InvalidData, FuseError::INVALID_ARGUMENT),
+            (io::ErrorKind::Unsupported, FuseError::NOT_IMPLEMENTED),
+            (io::ErrorKind::NotADirectory, FuseError::NOT_DIRECTORY),
+            (io::ErrorKind::IsADirectory, FuseError::IS_DIRECTORY),
+            (
+                io::ErrorKind::DirectoryNotEmpty,
+                FuseError::DIRECTORY_NOT_EMPTY,
+            ),
+            (
+                io::ErrorKind::ReadOnlyFilesystem,
+                FuseError::READ_ONLY_FILE_SYSTEM,
+            ),
+            (io::ErrorKind::CrossesDevices, FuseError::CROSS_DEVICE_LINK),
+            (io::ErrorKind::ResourceBusy, FuseError::BUSY),
+            (io::ErrorKind::FileTooLarge, FuseError::FILE_TOO_LARGE),
+            (io::ErrorKind::StorageFull, FuseError::NO_SPACE),
+            (io::ErrorKind::OutOfMemory, FuseError::OUT_OF_MEMORY),
+            (io::ErrorKind::UnexpectedEof, FuseError::IO),
+        ];
+
+        for (kind, expected) in kinds {
+            assert_eq!(FuseError::from(io::Error::from(kind)), expected, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn os_errors_keep_their_errno() {
+        let error = io::Error::from_raw_os_error(libc::ENAMETOOLONG);
+        assert_eq!(FuseError::from(error), FuseError::NAME_TOO_LONG);
+
+        // The kernel doesn't expect ESTALE from FUSE
+        let error = io::Error::from_raw_os_error(libc::ESTALE);
+        assert_eq!(FuseError::from(error), FuseError::IO);
+    }
+
+    #[test]
+    fn named_errors_have_messages() {
+        let messages = [
+            (FuseError::OUT_OF_MEMORY, "Cannot allocate memory"),
+            (FuseError::TOO_MANY_OPEN_FILES, "Too many open files"),
+            (
+                FuseError::FILE_TABLE_OVERFLOW,
+                "Too many open files in system",
+            ),
+            (
+                FuseError::TOO_MANY_SYMLINKS,
+                "Too many levels of symbolic links",
+            ),
+            (FuseError::NAME_TOO_LONG, "File name too long"),
+            (FuseError::NO_SPACE, "No space left on device"),
+        ];
+
+        for (error, message) in messages {
+            assert_eq!(error.as_ref(), message);
+            assert_eq!(error.to_string(), message);
+        }
+
+        assert_eq!(FuseError::from(libc::EDOM).as_ref(), "UNKNOWN");
+    }
+}
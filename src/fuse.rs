@@ -1,28 +1,22 @@
-use std::{
-    ffi::OsStr,
-    io::{Seek, SeekFrom, Write},
-    os::unix::fs::FileExt,
-    time::{Duration, SystemTime},
-};
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path, time::SystemTime};
 
 use fuser::{
-    FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+    consts, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLseek, ReplyOpen, ReplyPoll, ReplyStatfs,
+    ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
 use libc::c_int;
-use log::{debug, error, trace};
+use log::{debug, error};
 
 use crate::{error::FuseError, rfs::Rfs};
 
-const DEFUALT_TTL: Duration = Duration::from_secs(1);
-
 macro_rules! fuse_reply_error {
-    ($result:expr, $reply:ident, $message:expr) => {
+    ($rfs:ident, $result:expr, $reply:ident, $message:expr) => {
         match $result {
             Ok(val) => val,
             Err(err) => {
                 error!("{}: {}({})", line!(), $message, err.as_ref());
-                $reply.error(err.into());
+                $reply.error($rfs.reply_error(err).into());
                 return;
             }
         }
@@ -30,61 +24,82 @@ macro_rules! fuse_reply_error {
 }
 
 macro_rules! fuse_reply_last_error {
-    ($result:expr, $reply:ident, $message:expr) => {
+    ($rfs:ident, $result:expr, $reply:ident, $message:expr) => {
         match $result {
             Ok(val) => val,
             Err(err) => {
                 error!("{}: {}({err})", line!(), $message);
-                $reply.error(FuseError::last().into());
+                $reply.error($rfs.reply_error(FuseError::from(err)).into());
                 return;
             }
         }
     };
 }
 
+/// Replies EIO once the origin is gone, it was logged when it got lost.
+macro_rules! ensure_origin_available {
+    ($rfs:ident, $reply:ident) => {
+        if let Err(err) = $rfs.ensure_origin_available() {
+            $reply.error(err.into());
+            return;
+        }
+    };
+}
+
 impl Filesystem for Rfs {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
         debug!("Initialization...");
+        // O_TRUNC is passed to open rather than turned into a separate setattr
+        if config
+            .add_capabilities(consts::FUSE_ATOMIC_O_TRUNC)
+            .is_err()
+        {
+            debug!("Kernel doesn't support atomic O_TRUNC");
+        }
         self.init();
         Ok(())
     }
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let read_view = self.inode_list();
+        ensure_origin_available!(self, reply);
 
-        let (parent_node, _) = read_view.find_by_id(parent).unwrap();
+        self.sync_watched();
 
-        let (_, inode) = fuse_reply_error!(
-            read_view
-                .find_child_by_name(parent_node, name)
-                .ok_or(FuseError::NO_EXIST),
+        let (attr, generation) = fuse_reply_error!(
+            self,
+            self.lookup_entry(parent, name),
             reply,
-            format!("Can't find item with {name:?} name")
+            format!("Can't find item with {name:?} name in {parent} directory")
         );
 
-        reply.entry(&DEFUALT_TTL, &inode.attr, 0);
+        reply.entry(&self.entry_ttl(), &attr, generation);
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inode_list_write().forget(ino, nlookup);
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let read_view = self.inode_list();
+        ensure_origin_available!(self, reply);
 
-        let (_, inode) = fuse_reply_error!(
-            read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST),
+        let attr = fuse_reply_error!(
+            self,
+            self.current_attr(ino),
             reply,
-            format!("Can't find inode with {ino} ino")
+            format!("Can't get attributes of {ino} ino")
         );
 
-        reply.attr(&Duration::new(0, 0), &inode.attr)
+        reply.attr(&self.attr_ttl(), &attr)
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         ctime: Option<SystemTime>,
@@ -95,14 +110,51 @@ impl Filesystem for Rfs {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_writable(),
+            reply,
+            "setattr is rejected on a read-only mount"
+        );
+
+        if let Some(size) = size {
+            fuse_reply_error!(
+                self,
+                self.truncate(ino, size),
+                reply,
+                format!("Failed to truncate inode with {ino} ino")
+            );
+        }
+
         let mut write_view = self.inode_list_write();
 
         let (_, inode) = fuse_reply_error!(
+            self,
             write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST),
             reply,
             format!("Cannot find inode with {ino} ino")
         );
 
+        if let Some(mode) = mode {
+            fuse_reply_error!(
+                self,
+                self.chmod(inode, req.uid(), mode),
+                reply,
+                format!("Failed to change mode of inode with {ino} ino")
+            );
+        }
+
+        if uid.is_some() || gid.is_some() {
+            fuse_reply_error!(
+                self,
+                self.chown(inode, req.uid(), uid, gid),
+                reply,
+                format!("Failed to change owner of inode with {ino} ino")
+            );
+        }
+
         if let Some(atime) = atime {
             let time = match atime {
                 TimeOrNow::SpecificTime(time) => time,
@@ -129,34 +181,91 @@ impl Filesystem for Rfs {
             inode.attr.crtime = crtime;
         }
 
-        reply.attr(&DEFUALT_TTL, &(inode.attr).clone())
+        reply.attr(&self.attr_ttl(), &(inode.attr).clone())
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
+        ensure_origin_available!(self, reply);
+
+        let created = self.create(name, parent, mode, umask, FileType::Directory);
+        let result = created.as_ref().map(|_| ());
+        self.audit("mkdir", req.into(), (parent, Some(name)), None, result);
+
         let attr = fuse_reply_error!(
-            self.create(name, parent, mode, FileType::Directory),
+            self,
+            created,
             reply,
             format!("Can't create directory with {parent} parent")
         );
 
-        reply.entry(&DEFUALT_TTL, &attr, 0);
+        let generation = self.remember_entry(attr.ino);
+        reply.entry(&self.entry_ttl(), &attr, generation);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        let attr = fuse_reply_error!(
+            self,
+            self.symlink(parent, link_name, target),
+            reply,
+            format!("Can't create {link_name:?} symlink with {parent} parent")
+        );
+
+        let generation = self.remember_entry(attr.ino);
+        reply.entry(&self.entry_ttl(), &attr, generation);
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        ensure_origin_available!(self, reply);
+
+        let target = fuse_reply_error!(
+            self,
+            self.read_link(ino),
+            reply,
+            format!("Can't read link with {ino} ino")
+        );
+
+        reply.data(target.as_os_str().as_bytes())
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_writable(),
+            reply,
+            "unlink is rejected on a read-only mount"
+        );
+
         let ino = {
             let read_view = self.inode_list();
 
-            let (parent_node, _) = read_view.find_by_id(parent).unwrap();
+            let (parent_node, _) = fuse_reply_error!(
+                self,
+                read_view.find_by_id(parent).ok_or(FuseError::NO_EXIST),
+                reply,
+                format!("Can't find parent with {parent} ino")
+            );
 
             let (_, inode) = fuse_reply_error!(
+                self,
                 read_view
                     .find_child_by_name(parent_node, name)
                     .ok_or(FuseError::NO_EXIST),
@@ -164,7 +273,7 @@ impl Filesystem for Rfs {
                 format!("Can't find inode with {parent} parent and {name:?} name")
             );
 
-            if inode.attr.kind != FileType::RegularFile {
+            if inode.attr.kind == FileType::Directory {
                 reply.error(FuseError::IS_DIRECTORY.into());
                 return;
             }
@@ -172,22 +281,37 @@ impl Filesystem for Rfs {
             inode.attr.ino
         };
 
-        fuse_reply_error!(
-            self.remove(ino),
-            reply,
-            format!("Failed to remove {ino} file")
-        );
+        let removed = self.remove(parent, name);
+        let result = removed.as_ref().map(|_| ());
+        self.audit("unlink", req.into(), (parent, Some(name)), None, result);
+
+        fuse_reply_error!(self, removed, reply, format!("Failed to remove {ino} file"));
 
         reply.ok()
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_writable(),
+            reply,
+            "rmdir is rejected on a read-only mount"
+        );
+
         let ino = {
             let inode_list = self.inode_list();
 
-            let (parent_node, _) = inode_list.find_by_id(parent).unwrap();
+            let (parent_node, _) = fuse_reply_error!(
+                self,
+                inode_list.find_by_id(parent).ok_or(FuseError::NO_EXIST),
+                reply,
+                format!("Can't find parent with {parent} ino")
+            );
 
-            let (_, inode) = fuse_reply_error!(
+            let (node_index, inode) = fuse_reply_error!(
+                self,
                 inode_list
                     .find_child_by_name(parent_node, name)
                     .ok_or(FuseError::NO_EXIST),
@@ -200,7 +324,13 @@ impl Filesystem for Rfs {
                 return;
             }
 
-            if inode_list.childs(parent_node).count() > 0 {
+            let empty = fuse_reply_error!(
+                self,
+                self.is_empty_dir(&inode_list, node_index),
+                reply,
+                format!("Can't list {name:?} directory")
+            );
+            if !empty {
                 reply.error(FuseError::DIRECTORY_NOT_EMPTY.into()); // We have to delete only empty folders
                 return;
             }
@@ -208,23 +338,61 @@ impl Filesystem for Rfs {
             inode.attr.ino
         };
 
-        fuse_reply_error!(self.remove(ino), reply, "Failed to remove {ino} directory");
+        let removed = self.remove(parent, name);
+        let result = removed.as_ref().map(|_| ());
+        self.audit("rmdir", req.into(), (parent, Some(name)), None, result);
+
+        fuse_reply_error!(
+            self,
+            removed,
+            reply,
+            format!("Failed to remove {ino} directory")
+        );
 
         reply.ok()
     }
 
-    fn rename(
+    fn link(
         &mut self,
         _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        let attr = fuse_reply_error!(
+            self,
+            self.link(ino, newparent, newname),
+            reply,
+            format!("Can't link {ino} ino as {newname:?} in {newparent} directory")
+        );
+
+        let generation = self.remember_entry(attr.ino);
+        reply.entry(&self.entry_ttl(), &attr, generation);
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
+        ensure_origin_available!(self, reply);
+
+        let renamed = self.rename(parent, name, newparent, newname, flags);
+        let result = renamed.as_ref().map(|_| ());
+        let target = Some((newparent, newname));
+        self.audit("rename", req.into(), (parent, Some(name)), target, result);
+
         fuse_reply_error!(
-            self.rename(parent, name, newparent, newname),
+            self,
+            renamed,
             reply,
             format!("Failed to rename item {name:?} with {parent} parent to  {newname:?} newname with {newparent} newparent")
         );
@@ -232,27 +400,20 @@ impl Filesystem for Rfs {
         reply.ok()
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        let (_, read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => (libc::R_OK, true, false),
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        ensure_origin_available!(self, reply);
 
-        let fh = match self.allocate_fh(ino, read, write) {
-            Ok(fh) => fh,
-            Err(error) => {
-                error!("Cannot allocated fh for {ino}: open exit with {}", error);
-                reply.error(error.into());
-                return;
-            }
-        };
+        let fh = fuse_reply_error!(
+            self,
+            self.open_fh(ino, flags, req.uid(), req.gid()),
+            reply,
+            format!(
+                "Failed to open {ino} ino with {flags:#o} flags for {} uid",
+                req.uid()
+            )
+        );
 
-        reply.opened(fh, 0);
+        reply.opened(fh, self.open_flags(ino));
     }
 
     fn read(
@@ -266,42 +427,28 @@ impl Filesystem for Rfs {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let read_view = self.inode_list();
+        ensure_origin_available!(self, reply);
 
-        let (_, inode) = fuse_reply_error!(
-            read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST),
+        let offset = fuse_reply_error!(
+            self,
+            u64::try_from(offset).map_err(|_| FuseError::INVALID_ARGUMENT),
             reply,
-            format!("Cannot find inode with {ino} ino")
+            format!("Invalid {offset} read offset")
         );
 
-        let file = fuse_reply_error!(
-            self.open_file(inode, fh, true, false),
+        let data = fuse_reply_error!(
+            self,
+            self.read_file(ino, fh, offset, size as usize),
             reply,
-            format!(
-                "Failed to open file with inode {} and {fh} fh",
-                inode.attr.ino
-            )
+            format!("Failed to read {size} bytes from {ino} inode with {fh} fh")
         );
 
-        let offset = u64::try_from(offset).unwrap();
-        let amount = usize::min(
-            usize::try_from(inode.attr.size.saturating_sub(offset)).unwrap(),
-            usize::try_from(size).unwrap(),
-        );
-        let mut buf = vec![0; amount];
-
-        fuse_reply_last_error!(
-            file.read_exact_at(&mut buf, offset),
-            reply,
-            format!("Failed to read {amount} bytes from file")
-        );
-
-        reply.data(&buf)
+        reply.data(&data)
     }
 
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -311,119 +458,393 @@ impl Filesystem for Rfs {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        let mut write_view = self.inode_list_write();
-        let (_, inode) = fuse_reply_error!(
-            write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST),
-            reply,
-            format!("Cannot find inode with {ino} ino")
-        );
+        ensure_origin_available!(self, reply);
 
-        let mut file = fuse_reply_error!(
-            self.open_file(inode, fh, false, true),
-            reply,
-            format!("Failed to open file with {ino} inode and {fh} fh")
-        );
+        if self.is_control_file(ino) {
+            fuse_reply_error!(
+                self,
+                self.control(data),
+                reply,
+                format!(
+                    "Failed to run {:?} control command",
+                    String::from_utf8_lossy(data)
+                )
+            );
 
-        fuse_reply_last_error!(
-            file.seek(SeekFrom::Start(offset as u64)),
-            reply,
-            format!("File seek failed for file with {ino} inode")
-        );
+            reply.written(data.len() as u32);
+            return;
+        }
 
-        let written = fuse_reply_last_error!(
-            file.write(data),
+        let offset = fuse_reply_error!(
+            self,
+            u64::try_from(offset).map_err(|_| FuseError::INVALID_ARGUMENT),
             reply,
-            format!("Failed to write data to file with {ino} inode")
+            format!("Invalid {offset} write offset")
         );
 
-        let attr = &mut inode.attr;
-        if data.len() + offset as usize > attr.size as usize {
-            attr.size = (data.len() + offset as usize) as u64;
+        // Recording every write would flood the log, only the first one since the last flush is
+        let first_write = self.is_first_write(fh);
+        let written = self.write_file(ino, fh, offset, data);
+        if first_write {
+            let result = written.as_ref().map(|_| ());
+            self.audit("write", req.into(), (ino, None), None, result);
         }
 
-        let time_now = SystemTime::now();
-        attr.ctime = time_now;
-        attr.mtime = time_now;
+        let written = fuse_reply_error!(
+            self,
+            written,
+            reply,
+            format!("Failed to write data to file with {ino} inode and {fh} fh")
+        );
 
         reply.written(written as u32)
     }
 
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        // close() is the last chance to tell the writer about write errors and infected files
+        fuse_reply_error!(
+            self,
+            self.flush_file(ino, fh),
+            reply,
+            format!("Failed to flush {fh} fh of {ino} inode")
+        );
+
+        reply.ok()
+    }
+
     fn release(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        let mut write_view = self.inode_list_write();
+        // The last chance to write buffered data and scan it, the handle is closed right after
+        let scanned = self.scan_written(ino, fh);
 
-        let (_, inode) = fuse_reply_error!(
-            write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST),
+        fuse_reply_error!(
+            self,
+            self.release_fh(ino, fh),
             reply,
-            format!("Cannot find inode with {ino} ino")
+            format!("Failed to release {fh} fh of {ino} inode")
         );
 
-        if let Some(open_handlers) = inode.open_handles.as_mut() {
-            open_handlers.count = open_handlers.count.saturating_sub(1);
-
-            if open_handlers.count == 0 {
-                inode.open_handles = None;
-            }
-        }
+        fuse_reply_error!(
+            self,
+            scanned,
+            reply,
+            format!("Written content of {ino} inode is rejected")
+        );
 
         reply.ok()
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        let (dir, id) = {
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.flush_fh(ino, fh),
+            reply,
+            format!("Failed to write buffered data of {ino} inode")
+        );
+
+        {
             let read_view = self.inode_list();
 
             let (_, inode) = fuse_reply_error!(
+                self,
                 read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST),
                 reply,
                 format!("Cannot find inode with {ino} ino")
             );
 
-            (inode.origin_path.clone(), inode.attr.ino)
+            if inode.virtual_file.is_some() {
+                reply.ok();
+                return;
+            }
+        }
+
+        let file = fuse_reply_error!(
+            self,
+            self.open_file(ino, fh, false, false),
+            reply,
+            format!("Failed to open file with {ino} inode and {fh} fh")
+        );
+
+        let result = if datasync {
+            file.sync_data()
+        } else {
+            file.sync_all()
         };
 
-        self.add_folder(dir, id).unwrap();
+        fuse_reply_last_error!(
+            self,
+            result,
+            reply,
+            format!("Failed to sync file with {ino} inode")
+        );
 
-        reply.opened(id, 0);
+        reply.ok()
     }
 
-    fn readdir(
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_writable(),
+            reply,
+            "fallocate is rejected on a read-only mount"
+        );
+
+        fuse_reply_error!(
+            self,
+            self.allocate(ino, fh, offset, length, mode),
+            reply,
+            format!("Failed to fallocate {length} bytes of {ino} inode with {mode:#x} mode")
+        );
+
+        reply.ok()
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        let position = fuse_reply_error!(
+            self,
+            self.seek(ino, fh, offset, whence),
+            reply,
+            format!("Failed to seek {ino} inode to {offset} with {whence} whence")
+        );
+
+        reply.offset(position)
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        let value = fuse_reply_error!(
+            self,
+            self.xattr(ino, name),
+            reply,
+            format!("No {name:?} extended attribute of {ino} inode")
+        );
+
+        reply_xattr(reply, &value, size)
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        ensure_origin_available!(self, reply);
+
+        let names = fuse_reply_error!(
+            self,
+            self.xattr_names(ino),
+            reply,
+            format!("Can't list extended attributes of {ino} inode")
+        );
+
+        reply_xattr(reply, &names, size)
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_xattr_writable(name),
+            reply,
+            format!("Can't set {name:?} extended attribute of {ino} inode")
+        );
+
+        reply.ok()
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_xattr_writable(name),
+            reply,
+            format!("Can't remove {name:?} extended attribute of {ino} inode")
+        );
+
+        reply.ok()
+    }
+
+    /// Only the virtual files are waited on, see [`crate::poll::PollWaiters`].
+    fn poll(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
         _fh: u64,
+        kh: u64,
+        events: u32,
+        flags: u32,
+        reply: ReplyPoll,
+    ) {
+        let revents = fuse_reply_error!(
+            self,
+            self.poll_events(ino, kh, flags),
+            reply,
+            format!("Failed to poll {ino} inode for {events:#x} events")
+        );
+
+        reply.poll(revents)
+    }
+
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        match self.block_map(ino, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(err.into()),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.ensure_writable(),
+            reply,
+            "copy_file_range is rejected on a read-only mount"
+        );
+
+        if flags != 0 {
+            reply.error(FuseError::INVALID_ARGUMENT.into());
+            return;
+        }
+
+        let (Ok(offset_in), Ok(offset_out)) = (u64::try_from(offset_in), u64::try_from(offset_out))
+        else {
+            reply.error(FuseError::INVALID_ARGUMENT.into());
+            return;
+        };
+
+        // Copied amount is replied as u32
+        let len = len.min(u64::from(u32::MAX));
+        let copied = fuse_reply_error!(
+            self,
+            self.copy_range(
+                (ino_in, fh_in, offset_in),
+                (ino_out, fh_out, offset_out),
+                len
+            ),
+            reply,
+            format!("Failed to copy {len} bytes from {ino_in} to {ino_out} inode")
+        );
+
+        reply.written(copied as u32)
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        ensure_origin_available!(self, reply);
+
+        let fh = fuse_reply_error!(
+            self,
+            self.open_dir(ino),
+            reply,
+            format!("Cannot open directory with {ino} ino")
+        );
+
+        reply.opened(fh, 0);
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        fuse_reply_error!(
+            self,
+            self.release_dir(fh),
+            reply,
+            format!("Failed to release {fh} handle of {ino} directory")
+        );
+
+        reply.ok()
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let inode_list = self.inode_list();
+        ensure_origin_available!(self, reply);
 
-        let (node_index, _) = fuse_reply_error!(
-            inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST),
+        let entries = fuse_reply_error!(
+            self,
+            self.dir_entries(ino, fh, offset),
             reply,
-            format!("Cannot find inode with {ino} ino")
+            format!("Failed to list directory with {ino} inode")
         );
 
-        for (i, child) in inode_list
-            .childs(node_index)
-            .enumerate()
-            .skip(offset as usize)
-            .map(|(i, item)| (i + 1, item))
-        {
-            trace!("Replying readdir with: {:?}", child.proxy_path);
-            if reply.add(
-                child.attr.ino,
-                i as i64,
-                child.attr.kind,
-                child.proxy_path.file_name().unwrap_or("..".as_ref()),
-            ) {
+        for entry in entries {
+            if reply.add(entry.ino, entry.offset, entry.kind, &entry.name) {
                 break;
             }
         }
@@ -431,27 +852,75 @@ impl Filesystem for Rfs {
         reply.ok()
     }
 
-    fn access(&mut self, _req: &Request<'_>, ino: u64, _mask: i32, reply: ReplyEmpty) {
-        let read_view = self.inode_list();
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        ensure_origin_available!(self, reply);
 
-        let _ = fuse_reply_error!(
-            read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST),
+        fuse_reply_error!(
+            self,
+            self.sync_dir(ino, datasync),
             reply,
-            format!("Cannot find inode with {ino} ino")
+            format!("Failed to sync {ino} directory")
+        );
+
+        reply.ok()
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        ensure_origin_available!(self, reply);
+
+        let stats = fuse_reply_error!(
+            self,
+            self.origin_statfs(),
+            reply,
+            "Failed to get origin file system statistics"
+        );
+
+        reply.statfs(
+            stats.f_blocks,
+            stats.f_bfree,
+            stats.f_bavail,
+            stats.f_files,
+            stats.f_ffree,
+            u32::try_from(stats.f_bsize).unwrap_or(u32::MAX),
+            u32::try_from(stats.f_namemax).unwrap_or(u32::MAX),
+            u32::try_from(stats.f_frsize).unwrap_or(u32::MAX),
+        );
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        ensure_origin_available!(self, reply);
+
+        fuse_reply_error!(
+            self,
+            self.check_access(ino, req.uid(), req.gid(), mask),
+            reply,
+            format!(
+                "Access {mask:#o} to {ino} ino is denied for {} uid",
+                req.uid()
+            )
         );
         reply.ok();
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         flags: i32,
         reply: ReplyCreate,
     ) {
+        ensure_origin_available!(self, reply);
+
         let (read, write) = match flags & libc::O_ACCMODE {
             libc::O_RDONLY => (true, false),
             libc::O_WRONLY => (false, true),
@@ -462,13 +931,36 @@ impl Filesystem for Rfs {
             }
         };
 
+        let created = self.create(name, parent, mode, umask, FileType::RegularFile);
+        let result = created.as_ref().map(|_| ());
+        self.audit("create", req.into(), (parent, Some(name)), None, result);
+
         let attr = fuse_reply_error!(
-            self.create(name, parent, mode, FileType::RegularFile),
+            self,
+            created,
             reply,
             format!("Can't create file from {parent} directory")
         );
 
-        let fh = self.allocate_fh(attr.ino, read, write).unwrap();
-        reply.created(&DEFUALT_TTL, &attr, 0, fh, 0);
+        let fh = fuse_reply_error!(
+            self,
+            self.allocate_fh(attr.ino, read, write),
+            reply,
+            format!("Can't open created {} file", attr.ino)
+        );
+        let generation = self.remember_entry(attr.ino);
+        reply.created(&self.entry_ttl(), &attr, generation, fh, 0);
+    }
+}
+
+/// Replies with the size of `data` if the caller asks for it with a `0` `size`, or with `data`
+/// itself if it fits in `size` bytes.
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(FuseError::OUT_OF_RANGE.into());
+    } else {
+        reply.data(data);
     }
 }
@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io,
+    os::unix::{fs::FileExt, io::AsRawFd},
+};
+
+use fuser::FileType;
+
+/// Origin file opened through the proxy.
+#[derive(Debug)]
+pub struct FileHandle {
+    /// Ino of the item the handle was opened for.
+    pub ino: u64,
+    /// `None` while the file is closed to stay under the open files limit.
+    file: Option<File>,
+    pub read: bool,
+    pub write: bool,
+    /// Data was written through the handle since it was last scanned.
+    pub written: bool,
+    /// Contiguous writes that aren't on the origin yet.
+    buffer: Vec<u8>,
+    /// File offset of the first buffered byte.
+    buffer_offset: u64,
+    /// Tick of the last activation, the smallest one is closed first.
+    last_used: u64,
+}
+
+impl FileHandle {
+    pub fn new(ino: u64, file: File, read: bool, write: bool) -> Self {
+        Self {
+            ino,
+            file: Some(file),
+            read,
+            write,
+            written: false,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Open file of the handle, fails if it was closed and not activated again.
+    pub fn file(&self) -> io::Result<&File> {
+        self.file
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    /// Swaps a stale file for a fresh one of the same item, buffered data goes to the new one.
+    pub fn replace_file(&mut self, file: File) -> io::Result<()> {
+        let file_slot = self
+            .file
+            .as_mut()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        *file_slot = file;
+
+        Ok(())
+    }
+
+    /// Writes `data` at `offset`, contiguous writes are coalesced in a buffer of up to `capacity`
+    /// bytes, `0` writes straight to the file.
+    ///
+    /// Space for buffered data is allocated up front, so a full device fails the write that
+    /// doesn't fit instead of a later flush. Returns the amount of written bytes, less than `data`
+    /// if the device filled up midway.
+    pub fn write_at(&mut self, data: &[u8], offset: u64, capacity: usize) -> io::Result<usize> {
+        let contiguous = self.buffer_offset + self.buffer.len() as u64 == offset;
+        if !self.buffer.is_empty() && (!contiguous || self.buffer.len() + data.len() > capacity) {
+            self.flush_buffer()?;
+        }
+
+        if data.len() >= capacity {
+            return write_partially(self.file()?, data, offset);
+        }
+
+        if reserve(self.file()?, offset, data.len()).is_err() {
+            // Out of space or the origin can't allocate ahead, the write goes straight to the file
+            // and reports as much as fits
+            self.flush_buffer()?;
+            return write_partially(self.file()?, data, offset);
+        }
+
+        if self.buffer.is_empty() {
+            self.buffer_offset = offset;
+        }
+        let buffered = self.buffer.len();
+        self.buffer.extend_from_slice(data);
+
+        if self.buffer.len() >= capacity {
+            if let Err(err) = self.flush_buffer() {
+                // The write fails as a whole, earlier buffered data stays for the next flush
+                self.buffer.truncate(buffered);
+                return Err(err);
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    /// Whether some written data is still in the buffer.
+    pub fn has_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Buffered data if it's the whole content of the file: it starts at the beginning and the
+    /// file has nothing past it.
+    pub fn buffered_content(&self) -> io::Result<Option<&[u8]>> {
+        if self.buffer.is_empty() || self.buffer_offset != 0 {
+            return Ok(None);
+        }
+
+        let len = self.file()?.metadata()?.len();
+        Ok((len <= self.buffer.len() as u64).then_some(self.buffer.as_slice()))
+    }
+
+    /// Drops buffered data without writing it.
+    pub fn discard_buffer(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Writes buffered data to the file.
+    pub fn flush_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.file()?
+            .write_all_at(&self.buffer, self.buffer_offset)?;
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+/// Allocates `len` bytes at `offset` without changing the file size.
+fn reserve(file: &File, offset: u64, len: usize) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let result =
+        unsafe { libc::fallocate(fd, libc::FALLOC_FL_KEEP_SIZE, offset as i64, len as i64) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Writes as much of `data` as the file takes, a failure after some bytes went through ends up
+/// as a short write.
+fn write_partially(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match file.write_at(&data[written..], offset + written as u64) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(count) => written += count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) if written > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(written)
+}
+
+/// Open files by the fh handed out to the kernel, every open gets its own handle.
+///
+/// At most `max_open` origin files are kept open, the least recently used handles are closed
+/// when more are needed and reopened once they're used again.
+#[derive(Debug)]
+pub struct HandleTable {
+    handles: HashMap<u64, FileHandle>,
+    last_fh: u64,
+    /// `0` means no limit.
+    max_open: usize,
+    open: usize,
+    tick: u64,
+}
+
+impl HandleTable {
+    pub fn new(max_open: usize) -> Self {
+        Self {
+            handles: HashMap::new(),
+            last_fh: 0,
+            max_open,
+            open: 0,
+            tick: 0,
+        }
+    }
+
+    /// Stores a handle and returns its fh, `0` is never handed out.
+    pub fn insert(&mut self, mut handle: FileHandle) -> u64 {
+        self.make_room(&[]);
+
+        self.tick += 1;
+        handle.last_used = self.tick;
+        if handle.file.is_some() {
+            self.open += 1;
+        }
+
+        self.last_fh += 1;
+        self.handles.insert(self.last_fh, handle);
+
+        self.last_fh
+    }
+
+    /// Makes sure the file of the handle is open, reopening it with `reopen` if it was closed.
+    ///
+    /// Handles in `pinned` are in use by the caller and are never closed to make room.
+    pub fn activate(
+        &mut self,
+        fh: u64,
+        pinned: &[u64],
+        reopen: impl FnOnce(&FileHandle) -> io::Result<File>,
+    ) -> io::Result<()> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let handle = self
+            .handles
+            .get_mut(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        handle.last_used = tick;
+        if handle.file.is_some() {
+            return Ok(());
+        }
+
+        let mut pinned = pinned.to_vec();
+        pinned.push(fh);
+        self.make_room(&pinned);
+
+        let handle = self.handles.get_mut(&fh).unwrap();
+        handle.file = Some(reopen(handle)?);
+        self.open += 1;
+
+        Ok(())
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&FileHandle> {
+        self.handles.get(&fh)
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut FileHandle> {
+        self.handles.get_mut(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<FileHandle> {
+        let handle = self.handles.remove(&fh)?;
+        if handle.file.is_some() {
+            self.open -= 1;
+        }
+
+        Some(handle)
+    }
+
+    /// Writes data buffered by every handle of the item with `ino` ino.
+    pub fn flush_ino(&mut self, ino: u64) -> io::Result<()> {
+        self.handles
+            .values_mut()
+            .filter(|handle| handle.ino == ino)
+            .try_for_each(FileHandle::flush_buffer)
+    }
+
+    /// Closes all handles, buffered data is written on a best-effort basis.
+    pub fn clear(&mut self) {
+        for handle in self.handles.values_mut() {
+            let _ = handle.flush_buffer();
+        }
+        self.handles.clear();
+        self.open = 0;
+    }
+
+    /// Closes the least recently used files until one more can be opened without going over
+    /// the limit. Files with unwritten buffered data that can't be written are kept open.
+    fn make_room(&mut self, pinned: &[u64]) {
+        while self.max_open != 0 && self.open >= self.max_open {
+            let Some(handle) = self
+                .handles
+                .iter_mut()
+                .filter(|(fh, handle)| handle.file.is_some() && !pinned.contains(fh))
+                .map(|(_, handle)| handle)
+                .min_by_key(|handle| handle.last_used)
+            else {
+                return;
+            };
+
+            if handle.flush_buffer().is_err() {
+                return; // the file stays open over the limit rather than losing data
+            }
+
+            handle.file = None;
+            self.open -= 1;
+        }
+    }
+}
+
+/// Item of a directory listing, see [`DirHandle`].
+#[derive(Debug)]
+pub struct DirEntry {
+    pub ino: u64,
+    /// Offset a listing continues from after this entry.
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+/// Open directory, its listing is taken when it's read from the start and served from memory
+/// for the following batches.
+#[derive(Debug)]
+pub struct DirHandle {
+    /// Ino of the directory the handle was opened for.
+    pub ino: u64,
+    /// Entries sorted by offset, `None` until the first read.
+    pub entries: Option<Vec<DirEntry>>,
+}
+
+/// Open directories by the fh handed out to the kernel.
+#[derive(Debug, Default)]
+pub struct DirHandleTable {
+    handles: HashMap<u64, DirHandle>,
+    last_fh: u64,
+}
+
+impl DirHandleTable {
+    /// Stores a handle of a directory and returns its fh, `0` is never handed out.
+    pub fn insert(&mut self, ino: u64) -> u64 {
+        self.last_fh += 1;
+        self.handles
+            .insert(self.last_fh, DirHandle { ino, entries: None });
+
+        self.last_fh
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut DirHandle> {
+        self.handles.get_mut(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<DirHandle> {
+        self.handles.remove(&fh)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{ffi::CString, fs, os::unix::ffi::OsStrExt, path::Path};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Size limited tmpfs, unmounted on drop.
+    pub(crate) struct Tmpfs(CString);
+
+    impl Tmpfs {
+        pub(crate) fn mount(target: &Path, size: &str) -> io::Result<Self> {
+            let target = CString::new(target.as_os_str().as_bytes()).unwrap();
+            let options = CString::new(format!("size={size}")).unwrap();
+            let result = unsafe {
+                libc::mount(
+                    c"tmpfs".as_ptr(),
+                    target.as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    0,
+                    options.as_ptr().cast(),
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self(target))
+        }
+    }
+
+    impl Drop for Tmpfs {
+        fn drop(&mut self) {
+            unsafe { libc::umount(self.0.as_ptr()) };
+        }
+    }
+
+    #[test]
+    fn buffered_write_reports_full_device() {
+        let dir = TempDir::new("rvfs-enospc").unwrap();
+        let _tmpfs = match Tmpfs::mount(dir.path(), "64k") {
+            Ok(tmpfs) => tmpfs,
+            Err(err) => {
+                eprintln!("Skipped, tmpfs can't be mounted: {err}");
+                return;
+            }
+        };
+
+        let path = dir.path().join("data");
+        let file = File::create(&path).unwrap();
+        let mut handle = FileHandle::new(0, file, false, true);
+
+        // Every chunk fits in the buffer, none of them would touch the device without reserving
+        let chunk = [1; 4096];
+        let mut accepted = 0;
+        let err = loop {
+            match handle.write_at(&chunk, accepted, 1 << 20) {
+                Ok(count) => accepted += count as u64,
+                Err(err) => break err,
+            }
+            assert!(
+                accepted <= 64 << 10,
+                "more was accepted than the device holds"
+            );
+        };
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+        // What was reported as written makes it to the file
+        handle.flush_buffer().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), accepted);
+    }
+}
@@ -0,0 +1,119 @@
+//! Ownership translation between the origin and the proxy, see `--uid-map` and `--gid-map`.
+//!
+//! Ids without a mapping are the same on both sides.
+
+use std::str::FromStr;
+
+use anyhow::{ensure, Context};
+
+/// Id of the origin and the id it's reported as in the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    pub origin: u32,
+    pub proxy: u32,
+}
+
+impl FromStr for IdMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (origin, proxy) = s
+            .split_once(':')
+            .with_context(|| format!("{s:?} is not a valid id mapping(expected SRC:DST)"))?;
+
+        Ok(Self {
+            origin: origin
+                .parse()
+                .with_context(|| format!("{origin:?} is not a valid id"))?,
+            proxy: proxy
+                .parse()
+                .with_context(|| format!("{proxy:?} is not a valid id"))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    mappings: Vec<IdMapping>,
+}
+
+impl IdMap {
+    /// Every id can be mapped only once in each direction, so ownership set through the proxy
+    /// translates back unambiguously.
+    pub fn new(mappings: Vec<IdMapping>) -> anyhow::Result<Self> {
+        for (index, mapping) in mappings.iter().enumerate() {
+            for other in &mappings[..index] {
+                ensure!(
+                    other.origin != mapping.origin,
+                    "{} id is mapped more than once",
+                    mapping.origin
+                );
+                ensure!(
+                    other.proxy != mapping.proxy,
+                    "More than one id is mapped to {}",
+                    mapping.proxy
+                );
+            }
+        }
+
+        Ok(Self { mappings })
+    }
+
+    /// Id an origin item is reported with.
+    pub fn to_proxy(&self, id: u32) -> u32 {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.origin == id)
+            .map_or(id, |mapping| mapping.proxy)
+    }
+
+    /// Id that is stored on the origin for an id set through the proxy.
+    pub fn to_origin(&self, id: u32) -> u32 {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.proxy == id)
+            .map_or(id, |mapping| mapping.origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(origin: u32, proxy: u32) -> IdMapping {
+        IdMapping { origin, proxy }
+    }
+
+    #[test]
+    fn mapping_is_parsed_from_src_dst() {
+        assert_eq!("1000:0".parse::<IdMapping>().unwrap(), mapping(1000, 0));
+
+        for invalid in ["1000", "1000:", ":0", "a:0", "1000:-1", "1000:0:0"] {
+            assert!(
+                invalid.parse::<IdMapping>().is_err(),
+                "{invalid:?} was parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn ids_translate_both_ways() {
+        let id_map = IdMap::new(vec![mapping(1000, 0), mapping(1001, 1)]).unwrap();
+
+        assert_eq!(id_map.to_proxy(1000), 0);
+        assert_eq!(id_map.to_proxy(1001), 1);
+        assert_eq!(id_map.to_origin(0), 1000);
+        assert_eq!(id_map.to_origin(1), 1001);
+
+        // Unmapped ids are the same on both sides
+        assert_eq!(id_map.to_proxy(2000), 2000);
+        assert_eq!(id_map.to_origin(2000), 2000);
+    }
+
+    #[test]
+    fn duplicate_mappings_are_rejected() {
+        assert!(IdMap::new(vec![mapping(1000, 0), mapping(1000, 1)]).is_err());
+        assert!(IdMap::new(vec![mapping(1000, 0), mapping(1001, 0)]).is_err());
+        assert!(IdMap::new(vec![mapping(1000, 0), mapping(0, 1000)]).is_ok());
+    }
+}
@@ -1,30 +1,282 @@
 use std::{
-    ffi::OsStr,
-    fs::File,
-    os::fd::{FromRawFd, RawFd},
+    collections::HashMap,
     path::{Path, PathBuf},
     time::SystemTime,
 };
 
 use fuser::{FileAttr, FileType};
 use petgraph::{prelude::*, visit::Walker};
+use serde::{Deserialize, Serialize};
 
 #[derive(Default)]
 pub struct InodeList {
-    pub list: Graph<Inode, ()>,
+    /// Stable, so removing an item doesn't move other items to different indices. Edges lead from
+    /// directories to their items and carry the readdir offset of the item.
+    pub list: StableGraph<Inode, u64>,
+    /// Nodes of every ino, one per name of a hard linked item. The first one holds the attributes
+    /// of the item, the next one takes over once it's removed.
+    index: HashMap<u64, Vec<NodeIndex>>,
+    /// Highest ino handed out so far.
+    last_ino: u64,
+    /// Inos of removed items along with the generation they had, handed out again before new ones.
+    free_inos: Vec<(u64, u64)>,
+    /// Inos of removed items the kernel still references, along with the references left and the
+    /// generation. They're freed once the kernel forgets them, so forget can't hit a new item.
+    forgotten_later: HashMap<u64, (u64, u64)>,
+    /// Highest readdir offset handed out so far, offsets are never reused.
+    last_offset: u64,
+    /// Clock of [`Inode::last_used`], ticks on every use.
+    tick: u64,
 }
 
 impl InodeList {
+    /// Inserts the root item, it keeps its ino and has no parent.
+    pub fn insert_root(&mut self, node: Inode) -> NodeIndex {
+        let ino = node.attr.ino;
+        let node = self.list.add_node(node);
+        self.index.insert(ino, vec![node]);
+        self.last_ino = self.last_ino.max(ino);
+
+        node
+    }
+
     pub fn insert(&mut self, mut node: Inode, parent_node: NodeIndex) -> FileAttr {
-        let node_id = self.list.node_count() as u64 + 1;
+        let (node_id, generation) = self.allocate_ino();
         node.attr.ino = node_id;
+        node.generation = generation;
+        node.last_used = self.next_tick();
+        let attr = node.attr;
+        let node = self.list.add_node(node);
+        self.index.insert(node_id, vec![node]);
+
+        self.add_entry(parent_node, node);
+        attr
+    }
+
+    /// Inserts another name of an existing item, so it keeps the ino of the item.
+    pub fn insert_link(&mut self, mut node: Inode, parent_node: NodeIndex) -> FileAttr {
+        node.last_used = self.next_tick();
+        if let Some((_, item)) = self.find_by_id(node.attr.ino) {
+            node.generation = item.generation;
+        }
         let attr = node.attr;
         let node = self.list.add_node(node);
+        self.index.entry(attr.ino).or_default().push(node);
 
-        self.list.add_edge(parent_node, node, ());
+        self.add_entry(parent_node, node);
         attr
     }
 
+    pub fn remove(&mut self, node_index: NodeIndex) -> Option<Inode> {
+        let node = self.list.remove_node(node_index)?;
+        let ino = node.attr.ino;
+
+        if let Some(names) = self.index.get_mut(&ino) {
+            let was_first = names.first() == Some(&node_index);
+            // Another name of a hard linked item takes over, along with the references to it
+            names.retain(|&index| index != node_index);
+
+            match names.first() {
+                Some(&next) if was_first => {
+                    let next = &mut self.list[next];
+                    next.lookups += node.lookups;
+                    next.open_handles += node.open_handles;
+                }
+                Some(_) => {}
+                None => {
+                    self.index.remove(&ino);
+                    if node.lookups > 0 {
+                        self.forgotten_later
+                            .insert(ino, (node.lookups, node.generation));
+                    } else {
+                        self.free_inos.push((ino, node.generation));
+                    }
+                }
+            }
+        }
+
+        Some(node)
+    }
+
+    /// Regular files of a subtree that have an origin, along with their parent nodes.
+    pub fn regular_files(&self, node_index: NodeIndex) -> Vec<(NodeIndex, NodeIndex)> {
+        Dfs::new(&self.list, node_index)
+            .iter(&self.list)
+            .filter(|&index| {
+                let node = &self.list[index];
+                node.attr.kind == FileType::RegularFile && node.virtual_file.is_none()
+            })
+            .filter_map(|index| {
+                let parent = self.list.neighbors_directed(index, Incoming).next()?;
+                Some((index, parent))
+            })
+            .collect()
+    }
+
+    /// Items below a node, each one comes after its parent.
+    pub fn descendants(&self, node_index: NodeIndex) -> impl Iterator<Item = (NodeIndex, &Inode)> {
+        Dfs::new(&self.list, node_index)
+            .iter(&self.list)
+            .filter(move |&index| index != node_index)
+            .map(|index| (index, &self.list[index]))
+    }
+
+    /// Moves paths of everything below a renamed item from its old prefixes to its current paths.
+    pub fn rebase_descendants(
+        &mut self,
+        node_index: NodeIndex,
+        old_proxy_path: &Path,
+        old_origin_path: &Path,
+    ) {
+        let node = &self.list[node_index];
+        let (proxy_path, origin_path) = (node.proxy_path.clone(), node.origin_path.clone());
+
+        let descendants: Vec<_> = self
+            .descendants(node_index)
+            .map(|(index, _)| index)
+            .collect();
+
+        for index in descendants {
+            let inode = &mut self.list[index];
+
+            if let Ok(relative) = inode.proxy_path.strip_prefix(old_proxy_path) {
+                inode.proxy_path = proxy_path.join(relative);
+            }
+            if let Ok(relative) = inode.origin_path.strip_prefix(old_origin_path) {
+                inode.origin_path = origin_path.join(relative);
+            }
+        }
+    }
+
+    /// Removes an item together with everything below it.
+    pub fn remove_tree(&mut self, node_index: NodeIndex) {
+        let nodes: Vec<_> = Dfs::new(&self.list, node_index).iter(&self.list).collect();
+
+        for node in nodes {
+            let _ = self.remove(node);
+        }
+    }
+
+    /// Takes an item out of its directory, it stays reachable by its ino until it's removed.
+    pub fn detach(&mut self, node_index: NodeIndex) {
+        if let Some(edge) = self
+            .parent(node_index)
+            .and_then(|parent| self.list.find_edge(parent, node_index))
+        {
+            self.list.remove_edge(edge);
+        }
+    }
+
+    /// Whether a hard linked item has names other than the one at `node_index`.
+    pub fn has_other_names(&self, node_index: NodeIndex) -> bool {
+        let ino = self.list[node_index].attr.ino;
+
+        self.index
+            .get(&ino)
+            .is_some_and(|names| names.iter().any(|&index| index != node_index))
+    }
+
+    /// Adds an item to a directory after all of its current items.
+    fn add_entry(&mut self, parent_node: NodeIndex, node_index: NodeIndex) {
+        self.last_offset += 1;
+        self.list
+            .add_edge(parent_node, node_index, self.last_offset);
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Counts a reference the kernel got to an item with an entry reply, the item isn't evicted
+    /// until the kernel forgets it.
+    pub fn remember(&mut self, inode: u64) {
+        let tick = self.next_tick();
+        if let Some((_, node)) = self.find_by_id_mut(inode) {
+            node.lookups += 1;
+            node.last_used = tick;
+        }
+    }
+
+    /// Drops `nlookup` references the kernel had to an item, the ino of a removed item is freed
+    /// once the last one is gone.
+    pub fn forget(&mut self, inode: u64, nlookup: u64) {
+        let tick = self.next_tick();
+        if let Some((_, node)) = self.find_by_id_mut(inode) {
+            node.lookups = node.lookups.saturating_sub(nlookup);
+            node.last_used = tick;
+            return;
+        }
+
+        if let Some((lookups, generation)) = self.forgotten_later.get_mut(&inode) {
+            *lookups = lookups.saturating_sub(nlookup);
+            if *lookups == 0 {
+                let generation = *generation;
+                self.forgotten_later.remove(&inode);
+                self.free_inos.push((inode, generation));
+            }
+        }
+    }
+
+    /// Drops the least recently used items until at most `max` are left, returns how many were
+    /// dropped. Items of `keep_dir` stay, it's the directory that is being listed.
+    ///
+    /// Only leaf items that can be listed from the origin again are dropped: ones in the lower
+    /// layer that aren't open, aren't referenced by the kernel and have no items of their own.
+    pub fn evict(&mut self, max: usize, keep_dir: NodeIndex) -> usize {
+        let excess = self.list.node_count().saturating_sub(max);
+        if excess == 0 {
+            return 0;
+        }
+
+        let mut candidates: Vec<_> = self
+            .list
+            .node_indices()
+            .filter(|&index| {
+                let node = &self.list[index];
+                node.lookups == 0
+                    && node.open_handles == 0
+                    && node.layer == Layer::Lower
+                    && node.virtual_file.is_none()
+                    && self.list.neighbors(index).next().is_none()
+                    && self.parent(index).is_some_and(|parent| parent != keep_dir)
+            })
+            .map(|index| (self.list[index].last_used, index))
+            .collect();
+        candidates.sort_unstable();
+
+        candidates
+            .into_iter()
+            .take(excess)
+            .filter_map(|(_, index)| self.remove(index))
+            .count()
+    }
+
+    /// Picks an ino along with its generation, a reused ino gets the next generation, so the
+    /// kernel and NFS clients can tell the new item from the removed one.
+    fn allocate_ino(&mut self) -> (u64, u64) {
+        match self.free_inos.pop() {
+            Some((ino, generation)) => (ino, generation.wrapping_add(1)),
+            None => {
+                self.last_ino += 1;
+                (self.last_ino, 0)
+            }
+        }
+    }
+
+    /// Generation of the item with `inode` ino, see [`Inode::generation`].
+    pub fn generation(&self, inode: u64) -> u64 {
+        self.find_by_id(inode)
+            .map_or(0, |(_, node)| node.generation)
+    }
+
+    /// Updates link count of all names of the item with `inode` ino.
+    pub fn set_nlink(&mut self, inode: u64, nlink: u32) {
+        for &node_index in self.index.get(&inode).into_iter().flatten() {
+            self.list[node_index].attr.nlink = nlink;
+        }
+    }
+
     pub fn find_child_by_name<P: AsRef<Path>>(
         &self,
         parent_node: NodeIndex,
@@ -33,16 +285,15 @@ impl InodeList {
         self.list
             .neighbors(parent_node)
             .map(|index| (index, self.list.node_weight(index).unwrap()))
-            .find(|(_, node)| {
-                node.proxy_path.file_name().unwrap_or(OsStr::new("..")) == name.as_ref().as_os_str()
-            })
+            .find(|(_, node)| node.proxy_path.file_name() == Some(name.as_ref().as_os_str()))
     }
 
     pub fn find_by_id(&self, inode: u64) -> Option<(NodeIndex, &Inode)> {
-        Bfs::new(&self.list, NodeIndex::default())
-            .iter(&self.list)
-            .map(|index| (index, self.list.node_weight(index).unwrap()))
-            .find(|(_, node)| node.attr.ino == inode)
+        let &node_index = self.index.get(&inode)?.first()?;
+
+        self.list
+            .node_weight(node_index)
+            .map(|node| (node_index, node))
     }
 
     pub fn find_child_by_name_mut<P: AsRef<Path>>(
@@ -57,7 +308,7 @@ impl InodeList {
                     .list
                     .node_weight(node_index)
                     .expect("should be safe to unwrap as we within the valid index range");
-                node.proxy_path.file_name().unwrap_or(OsStr::new("..")) == name.as_ref().as_os_str()
+                node.proxy_path.file_name() == Some(name.as_ref().as_os_str())
             })
             .map(|node_index| {
                 (
@@ -70,23 +321,69 @@ impl InodeList {
     }
 
     pub fn find_by_id_mut(&mut self, inode: u64) -> Option<(NodeIndex, &mut Inode)> {
-        Bfs::new(&self.list, NodeIndex::default())
-            .iter(&self.list)
-            .find(|&node_index| {
-                let node = self
-                    .list
-                    .node_weight(node_index)
-                    .expect("should be safe to unwrap as we within the valid index range");
-                node.attr.ino == inode
-            })
-            .map(|node_index| {
-                (
-                    node_index,
-                    self.list
-                        .node_weight_mut(node_index)
-                        .expect("should be safe to unwrap as we within the valid index range"),
-                )
-            })
+        let &node_index = self.index.get(&inode)?.first()?;
+
+        self.list
+            .node_weight_mut(node_index)
+            .map(|node| (node_index, node))
+    }
+
+    /// Moves an item from `parent_node` directory to `new_parent_node` one.
+    pub fn reparent(
+        &mut self,
+        node_index: NodeIndex,
+        parent_node: NodeIndex,
+        new_parent_node: NodeIndex,
+    ) {
+        if let Some(edge) = self.list.find_edge(parent_node, node_index) {
+            let _ = self.list.remove_edge(edge);
+        }
+
+        self.add_entry(new_parent_node, node_index);
+
+        if parent_node != new_parent_node && self.list[node_index].attr.kind == FileType::Directory
+        {
+            self.link_subdir(parent_node, false);
+            self.link_subdir(new_parent_node, true);
+        }
+    }
+
+    /// Counts a subdirectory that was added to or removed from a directory in its link count,
+    /// `..` of the subdirectory is a link to it.
+    ///
+    /// File systems that don't count subdirectories report a single link, it's kept as is.
+    pub fn link_subdir(&mut self, dir_node: NodeIndex, added: bool) {
+        let Some(dir) = self.list.node_weight_mut(dir_node) else {
+            return;
+        };
+
+        if dir.attr.nlink >= 2 {
+            dir.attr.nlink = match added {
+                true => dir.attr.nlink.saturating_add(1),
+                false => (dir.attr.nlink - 1).max(2),
+            };
+        }
+    }
+
+    /// Directory an item is in, `None` for the root.
+    pub fn parent(&self, node_index: NodeIndex) -> Option<NodeIndex> {
+        self.list.neighbors_directed(node_index, Incoming).next()
+    }
+
+    /// Items of a directory that come after `offset` in a listing, along with their offsets.
+    ///
+    /// An item keeps its offset while it stays in the directory, so a listing that is read in
+    /// several batches neither skips nor repeats items when others are added or removed meanwhile.
+    pub fn entries_after(&self, parent_node: NodeIndex, offset: u64) -> Vec<(u64, &Inode)> {
+        let mut entries: Vec<_> = self
+            .list
+            .edges(parent_node)
+            .filter(|edge| *edge.weight() > offset)
+            .map(|edge| (*edge.weight(), &self.list[edge.target()]))
+            .collect();
+        entries.sort_unstable_by_key(|&(offset, _)| offset);
+
+        entries
     }
 
     pub fn childs(&self, parent_node: NodeIndex) -> impl Iterator<Item = &Inode> {
@@ -94,6 +391,21 @@ impl InodeList {
             .neighbors(parent_node)
             .map(|index| self.list.node_weight(index).unwrap())
     }
+
+    /// Proxy paths of all items that have open handles.
+    pub fn open_paths(&self) -> Vec<PathBuf> {
+        self.list
+            .node_weights()
+            .filter(|node| node.open_handles > 0)
+            .map(|node| node.proxy_path.clone())
+            .collect()
+    }
+
+    pub fn close_all(&mut self) {
+        for node in self.list.node_weights_mut() {
+            node.open_handles = 0;
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -175,8 +487,8 @@ impl FileAttrBuilder {
         self
     }
 
-    pub fn with_gid(mut self, uid: u32) -> Self {
-        self.uid = uid;
+    pub fn with_gid(mut self, gid: u32) -> Self {
+        self.gid = gid;
         self
     }
 
@@ -216,16 +528,25 @@ impl FileAttrBuilder {
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone)]
-pub struct OpenedHandlers {
-    pub fh: RawFd,
-    pub count: u64,
+/// Content of a file that exists only in the proxy and has no origin.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VirtualFile {
+    /// Explanation left in place of a blocked file.
+    BlockStub(Vec<u8>),
+    /// Scan statistics, generated on every read.
+    ScanStats,
+    /// Takes commands on write, reads return the command grammar.
+    Control,
 }
 
-impl Drop for OpenedHandlers {
-    fn drop(&mut self) {
-        drop(unsafe { File::from_raw_fd(self.fh) });
-    }
+/// Overlay layer an item lives in, always [`Layer::Lower`] without an overlay.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Layer {
+    /// The origin device.
+    #[default]
+    Lower,
+    /// The writable overlay directory.
+    Upper,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -233,7 +554,35 @@ pub struct Inode {
     pub proxy_path: PathBuf,
     pub origin_path: PathBuf,
     pub attr: FileAttr,
-    pub open_handles: Option<OpenedHandlers>,
+    /// Number of handles in the handle table that are open for the item.
+    pub open_handles: u64,
+    /// Removed from its directory while open, it's dropped with its origin on the last release.
+    pub unlinked: bool,
+    /// References the kernel holds after entry replies, dropped by forget.
+    pub lookups: u64,
+    /// Tick of the last use, the least recently used items are evicted first.
+    pub last_used: u64,
+    /// Tells items that got the same ino apart, it grows every time a freed ino is reused.
+    pub generation: u64,
+    pub layer: Layer,
+    /// Target of a symlink.
+    pub link_target: Option<PathBuf>,
+    pub virtual_file: Option<VirtualFile>,
+    pub scan_state: ScanState,
+    /// SHA-256 of the content as it was on the first open, see `--verify-checksums`.
+    pub checksum: Option<[u8; 32]>,
+}
+
+/// Whether content of an item still has to be scanned, see `--scan-mode`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScanState {
+    /// Scanned already, created through the proxy or never scanned at all.
+    #[default]
+    Done,
+    /// Scanned on the first open.
+    Pending,
+    /// Found infected on open, with the signature name.
+    Infected(String),
 }
 
 impl Inode {
@@ -242,7 +591,141 @@ impl Inode {
             proxy_path: path,
             origin_path,
             attr,
-            open_handles: None,
+            open_handles: 0,
+            unlinked: false,
+            lookups: 0,
+            last_used: 0,
+            generation: 0,
+            layer: Layer::default(),
+            link_target: None,
+            virtual_file: None,
+            scan_state: ScanState::default(),
+            checksum: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use fuser::FUSE_ROOT_ID;
+
+    use super::*;
+
+    fn item(name: &str, kind: FileType) -> Inode {
+        let attr = FileAttrBuilder::new().with_kind(kind).with_nlink(1).build();
+
+        Inode::new(Path::new("/proxy").join(name), PathBuf::new(), attr)
+    }
+
+    /// List with just the root directory, along with its node.
+    fn list_with_root() -> (InodeList, NodeIndex) {
+        let mut list = InodeList::default();
+        let mut root = item("", FileType::Directory);
+        root.attr.ino = FUSE_ROOT_ID;
+        let root_node = list.insert_root(root);
+
+        (list, root_node)
+    }
+
+    fn node_of(list: &InodeList, ino: u64) -> NodeIndex {
+        list.find_by_id(ino).unwrap().0
+    }
+
+    #[test]
+    fn live_items_never_share_an_ino() {
+        let (mut list, root) = list_with_root();
+        let _first = list.insert(item("first", FileType::RegularFile), root);
+        let second = list.insert(item("second", FileType::RegularFile), root);
+        let _third = list.insert(item("third", FileType::RegularFile), root);
+
+        list.remove(node_of(&list, second.ino));
+        let fourth = list.insert(item("fourth", FileType::RegularFile), root);
+
+        let inos: Vec<_> = list.list.node_weights().map(|node| node.attr.ino).collect();
+        let unique: HashSet<_> = inos.iter().collect();
+        assert_eq!(inos.len(), 4);
+        assert_eq!(unique.len(), inos.len());
+
+        let (_, found) = list.find_by_id(fourth.ino).unwrap();
+        assert_eq!(found.proxy_path, Path::new("/proxy/fourth"));
+    }
+
+    #[test]
+    fn reused_ino_gets_next_generation() {
+        let (mut list, root) = list_with_root();
+        let removed = list.insert(item("removed", FileType::RegularFile), root);
+        let generation = list.generation(removed.ino);
+
+        list.remove(node_of(&list, removed.ino));
+        let reused = list.insert(item("reused", FileType::RegularFile), root);
+
+        assert_eq!(reused.ino, removed.ino);
+        assert_eq!(list.generation(reused.ino), generation + 1);
+    }
+
+    #[test]
+    fn ino_is_reused_only_once_forgotten() {
+        let (mut list, root) = list_with_root();
+        let removed = list.insert(item("removed", FileType::RegularFile), root);
+        list.remember(removed.ino);
+        list.remember(removed.ino);
+
+        list.remove(node_of(&list, removed.ino));
+        let other = list.insert(item("other", FileType::RegularFile), root);
+        assert_ne!(other.ino, removed.ino);
+        list.remember(other.ino);
+
+        // A forget of the removed item leaves the new one alone
+        list.forget(removed.ino, 1);
+        assert_eq!(list.find_by_id(other.ino).unwrap().1.lookups, 1);
+        assert_ne!(
+            list.insert(item("early", FileType::RegularFile), root).ino,
+            removed.ino
+        );
+
+        list.forget(removed.ino, 1);
+        let reused = list.insert(item("reused", FileType::RegularFile), root);
+        assert_eq!(reused.ino, removed.ino);
+        assert_eq!(list.generation(reused.ino), 1);
+    }
+
+    #[test]
+    fn hard_link_takes_over_references() {
+        let (mut list, root) = list_with_root();
+        let first = list.insert(item("first", FileType::RegularFile), root);
+        let mut second = item("second", FileType::RegularFile);
+        second.attr.ino = first.ino;
+        list.insert_link(second, root);
+        list.remember(first.ino);
+
+        list.remove(node_of(&list, first.ino));
+        let (_, left) = list.find_by_id(first.ino).unwrap();
+        assert_eq!(left.proxy_path, Path::new("/proxy/second"));
+        assert_eq!(left.lookups, 1);
+    }
+
+    #[test]
+    fn index_finds_every_item_of_a_large_list() {
+        let (mut list, root) = list_with_root();
+        let attrs: Vec<_> = (0..10_000)
+            .map(|number| list.insert(item(&number.to_string(), FileType::RegularFile), root))
+            .collect();
+
+        for (number, attr) in attrs.iter().enumerate() {
+            let (_, found) = list.find_by_id(attr.ino).unwrap();
+            assert_eq!(
+                found.proxy_path,
+                Path::new("/proxy").join(number.to_string())
+            );
+        }
+
+        for attr in attrs.iter().step_by(2) {
+            list.remove(node_of(&list, attr.ino));
+        }
+        for (number, attr) in attrs.iter().enumerate() {
+            assert_eq!(list.find_by_id(attr.ino).is_some(), number % 2 == 1);
         }
     }
 }
@@ -0,0 +1,87 @@
+//! Proxy file system that keeps malware out of reach: every origin file is scanned with ClamAV
+//! before it shows up in the proxy.
+//!
+//! The `rvfs` binary is a thin wrapper around this crate. A proxy is set up with [`Rfs::new`],
+//! here over a directory that is used in place without scanning:
+//!
+//! ```
+//! use std::path::PathBuf;
+//!
+//! use rvfs::{Config, Rfs, SourceType};
+//! use tempdir::TempDir;
+//!
+//! let origin = TempDir::new("rvfs-origin")?;
+//! let config = Config {
+//!     source_type: SourceType::Dir,
+//!     no_scan: true,
+//!     ..Config::default()
+//! };
+//!
+//! let sources = vec![(origin.path().to_path_buf(), PathBuf::new())];
+//! let mut rfs = Rfs::new(sources, PathBuf::from("/mnt/proxy"), config)?;
+//! rfs.init();
+//! assert!(rfs.inode_list().find_by_id(fuser::FUSE_ROOT_ID).is_some());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Other programs mount it the same way the binary does, by driving a FUSE session themselves:
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! use rvfs::{Config, Rfs};
+//!
+//! // A single device is exposed as the proxy root
+//! let sources = vec![(PathBuf::from("disk.img"), PathBuf::new())];
+//! let mountpoint = PathBuf::from("/mnt/proxy");
+//!
+//! let rfs = Rfs::new(sources, mountpoint.clone(), Config::default())?;
+//! let mut session = fuser::Session::new(rfs, &mountpoint, &[])?;
+//! session.run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod allow;
+mod archive;
+mod audit;
+mod cache;
+mod config;
+mod detections;
+mod error;
+mod fuse;
+mod handle;
+mod idmap;
+mod inode;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod origin;
+mod overlay;
+mod poll;
+mod rfs;
+mod scanner;
+mod state;
+mod update;
+mod watcher;
+
+pub use crate::{
+    cache::EvictionPolicy,
+    config::Config,
+    error::FuseError,
+    idmap::{IdMap, IdMapping},
+    inode::InodeList,
+    origin::SourceType,
+    rfs::Rfs,
+    scanner::{
+        ArchiveLimits, ClamAV, OversizedPolicy, ScanMode, ScanTypes, Scanner, TimeoutPolicy,
+    },
+};
+
+// What the binary needs around a mounted session: signature updates, metrics and unmounting once
+// open files and scans are done
+#[cfg(feature = "metrics")]
+pub use crate::metrics::serve as serve_metrics;
+pub use crate::{
+    rfs::wait_for_release,
+    scanner::{check, wait_for_scans},
+    update::spawn as spawn_updates,
+};
@@ -1,48 +1,252 @@
+use std::{
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::Context;
 use fuser::Session;
-use log::debug;
-use simplelog::{Config, LevelFilter, SimpleLogger};
+use log::{debug, error, info, warn};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use simplelog::{Config, LevelFilter, SimpleLogger, WriteLogger};
 
-use rfs::Rfs;
+use rvfs::{FuseError, Rfs};
 
 use crate::cli::Cli;
 
 mod cli;
-mod error;
-mod fuse;
-mod inode;
-mod rfs;
-mod scanner;
+mod daemon;
 
 fn main() {
     let Cli {
-        device,
+        check,
+        sources,
         mountpoint,
         options,
-    } = Cli::parse().unwrap();
+        config,
+        daemon,
+        pid_file,
+        log_file,
+        log_level,
+    } = Cli::parse().unwrap_or_else(|err| fail(format!("{err:#}")));
+
+    if let Err(err) = setup_logger(log_file.as_deref(), log_level) {
+        fail(format!("{err:#}"));
+    }
 
-    setup_logger();
+    if check {
+        if let Err(err) = rvfs::check(&config) {
+            fail(format!("Check failed: {err:#}"));
+        }
+        return;
+    }
+
+    // Before the origin is mounted, so nothing is left mounted behind
+    if let Err(err) = ensure_mountpoint_free(&mountpoint) {
+        fail(err);
+    }
 
     debug!("Mount options: {options:?}");
 
-    let proxy_file_system = Rfs::new(device.clone(), mountpoint.clone()).unwrap();
+    let unmount_timeout = config.unmount_timeout;
+    let auto_update = config.auto_update.filter(|_| !config.no_scan);
+    #[cfg(feature = "metrics")]
+    let metrics_addr = config.metrics_addr;
+    let proxy_file_system = Rfs::new(sources, mountpoint.clone(), config)
+        .unwrap_or_else(|err| fail(format!("Failed to set up the proxy: {err:#}")));
+    let inode_list = proxy_file_system.inode_list_handle();
+    let scanner = proxy_file_system.scanner_handle();
+    let poll_waiters = proxy_file_system.poll_waiters_handle();
     let mut session = Session::new(proxy_file_system, mountpoint.as_ref(), &options)
-        .expect("Failed to create FUSE session");
+        .unwrap_or_else(|err| fail(mount_error(&mountpoint, &err)));
+    poll_waiters.attach(session.notifier());
+
+    // Forked only once mounted, so mount errors still reach the terminal
+    // Exiting skips destructors, the session is dropped first so nothing is left mounted
+    if daemon {
+        if let Err(err) = daemon::daemonize() {
+            drop(session);
+            fail(format!("{err:#}"));
+        }
+    }
+
+    if let Some(pid_file) = pid_file.as_ref() {
+        if let Err(err) = daemon::write_pid_file(pid_file) {
+            drop(session);
+            fail(format!("{err:#}"));
+        }
+    }
+
+    // Threads don't survive the fork, so they're started in the daemon
+    if let Some(interval) = auto_update {
+        if let Err(err) = rvfs::spawn_updates(interval, Arc::clone(&scanner)) {
+            drop(session);
+            fail(format!("{err:#}"));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = metrics_addr {
+        let served =
+            rvfs::serve_metrics(metrics_addr, Arc::clone(&inode_list), Arc::clone(&scanner));
+        if let Err(err) = served {
+            drop(session);
+            fail(format!("{err:#}"));
+        }
+    }
 
     let mut umount = session.unmount_callable();
-    ctrlc::set_handler(move || {
-        umount.unmount().expect("Failed to unmount FUSE mount");
-    })
-    .expect("Failed to set Ctrl-C handler");
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("Failed to set signal handlers");
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Reloading signatures...");
+                if let Err(err) = scanner.reload() {
+                    error!("Failed to reload signatures: {err:#}");
+                }
+                continue;
+            }
+
+            // Open files hold buffered writes, they're flushed once the files are released
+            if let Err(busy) = rvfs::wait_for_release(&inode_list, unmount_timeout) {
+                error!(
+                    "Unable to unmount: {}, {busy:?} are still open",
+                    FuseError::BUSY
+                );
+                continue;
+            }
+
+            match rvfs::wait_for_scans(&*scanner, unmount_timeout) {
+                Ok(0) => {}
+                Ok(drained) => info!("Drained {drained} in-flight scans"),
+                Err(running) => warn!("Unmounting with {running} scans still running"),
+            }
+
+            umount.unmount().expect("Failed to unmount FUSE mount");
+            break;
+        }
+    });
+
+    session.run().unwrap();
+
+    if let Some(pid_file) = pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+}
+
+/// Reports an error that prevents mounting and exits, it's shown without a backtrace.
+fn fail(message: impl Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+/// Rejects a mountpoint something is mounted at already, e.g. another rvfs instance.
+fn ensure_mountpoint_free(mountpoint: &Path) -> Result<(), String> {
+    let mounted = fs::metadata(mountpoint).and_then(|meta| {
+        let parent = fs::metadata(mountpoint.join(".."))?;
+        Ok(meta.dev() != parent.dev())
+    });
 
-    session.run().unwrap()
+    match mounted {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(busy_error(mountpoint)),
+        Err(err) => Err(mount_error(mountpoint, &err)),
+    }
 }
 
-pub fn setup_logger() {
-    let log_level = if cfg!(debug_assertions) {
+fn mount_error(mountpoint: &Path, err: &io::Error) -> String {
+    match err.raw_os_error() {
+        Some(libc::EBUSY) => busy_error(mountpoint),
+        Some(libc::ENOTCONN) => format!(
+            "mountpoint {} is a stale FUSE mount; unmount it with fusermount -u",
+            mountpoint.display()
+        ),
+        _ => format!("Failed to mount {}: {err}", mountpoint.display()),
+    }
+}
+
+fn busy_error(mountpoint: &Path) -> String {
+    format!(
+        "mountpoint {} is busy; is rvfs already running?",
+        mountpoint.display()
+    )
+}
+
+pub fn setup_logger(log_file: Option<&Path>, log_level: Option<LevelFilter>) -> anyhow::Result<()> {
+    let log_level = log_level.unwrap_or(if cfg!(debug_assertions) {
         LevelFilter::Trace
     } else {
         LevelFilter::Info
-    };
+    });
+
+    match log_file {
+        Some(log_file) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+                .with_context(|| format!("Unable to open {log_file:?} log file"))?;
+
+            WriteLogger::init(log_level, Config::default(), file)
+        }
+        None => SimpleLogger::init(log_level, Config::default()),
+    }
+    .context("Unable to set up the logger")
+}
+
+#[cfg(test)]
+mod tests {
+    use sys_mount::{Mount, Unmount, UnmountFlags};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn log_file_gets_written() {
+        let dir = TempDir::new("rvfs-log").unwrap();
+
+        let missing = dir.path().join("missing").join("rvfs.log");
+        let err = setup_logger(Some(&missing), None).unwrap_err();
+        assert!(format!("{err:#}").contains(&*missing.to_string_lossy()));
+
+        let log_file = dir.path().join("rvfs.log");
+        setup_logger(Some(&log_file), Some(LevelFilter::Info)).unwrap();
+        info!("Logged to a file");
+        log::logger().flush();
+
+        let logged = fs::read_to_string(&log_file).unwrap();
+        assert!(logged.contains("Logged to a file"));
+    }
+
+    #[test]
+    fn mounted_mountpoint_is_refused() {
+        let dir = TempDir::new("rvfs-mountpoint").unwrap();
+        assert_eq!(ensure_mountpoint_free(dir.path()), Ok(()));
+
+        let missing = dir.path().join("missing");
+        let err = ensure_mountpoint_free(&missing).unwrap_err();
+        assert!(err.starts_with("Failed to mount"), "{err}");
 
-    SimpleLogger::init(log_level, Config::default()).expect("Failed to setup logger");
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("Skipped, mounting needs root");
+            return;
+        }
+        // Stands for a proxy that is mounted there already
+        let mount = Mount::builder()
+            .fstype("tmpfs")
+            .mount("tmpfs", dir.path())
+            .unwrap();
+        let err = ensure_mountpoint_free(dir.path());
+        mount.unmount(UnmountFlags::DETACH).unwrap();
+        assert_eq!(err, Err(busy_error(dir.path())));
+        assert!(busy_error(dir.path()).contains("is rvfs already running?"));
+    }
 }
@@ -0,0 +1,120 @@
+//! Prometheus metrics over HTTP, see `--metrics-addr`.
+//!
+//! A single thread answers one request at a time, scrapes are rare and cheap, so a full HTTP
+//! server isn't worth its weight.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+
+use crate::{inode::InodeList, scanner::Scanner};
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves `GET /metrics` and `GET /health` on `addr` from a background thread.
+pub fn serve(
+    addr: SocketAddr,
+    inode_list: Arc<RwLock<InodeList>>,
+    scanner: Arc<dyn Scanner>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to listen on {addr} for metrics"))?;
+    info!("Serving metrics on http://{addr}/metrics");
+
+    thread::Builder::new()
+        .name("rvfs-metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| respond(stream, &inode_list, &*scanner));
+                if let Err(err) = result {
+                    warn!("Failed to serve a metrics request: {err}");
+                }
+            }
+        })
+        .context("Failed to spawn metrics thread")?;
+
+    Ok(())
+}
+
+fn respond(
+    mut stream: TcpStream,
+    inode_list: &RwLock<InodeList>,
+    scanner: &dyn Scanner,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(&stream);
+    reader.read_line(&mut request_line)?;
+    // Headers are of no interest, but the client expects them to be read
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics(inode_list, scanner)),
+        ["GET", "/health"] => ("200 OK", "ok\n".to_owned()),
+        ["GET", _] => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn metrics(inode_list: &RwLock<InodeList>, scanner: &dyn Scanner) -> String {
+    let mut out = String::new();
+
+    if let Some(stats) = scanner.stats() {
+        stats.write_metrics(&mut out);
+    }
+
+    let gauges = {
+        let inode_list = inode_list.read().unwrap();
+        let open_handles: u64 = inode_list
+            .list
+            .node_weights()
+            .map(|inode| inode.open_handles)
+            .sum();
+
+        [
+            (
+                "rvfs_open_handles",
+                "Files open through the proxy.",
+                open_handles,
+            ),
+            (
+                "rvfs_inodes",
+                "Items known to the proxy.",
+                inode_list.list.node_count() as u64,
+            ),
+            (
+                "rvfs_signatures",
+                "Signatures loaded by the engine.",
+                u64::from(scanner.signature_count()),
+            ),
+        ]
+    };
+    for (name, help, value) in gauges {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+        );
+    }
+
+    out
+}
@@ -0,0 +1,254 @@
+//! Ways to get at the origin the proxy serves, see `--source-type`.
+
+use std::{
+    env, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{bail, ensure, Context};
+use log::{debug, error, warn};
+use sys_mount::{Mount, MountFlags, Unmount, UnmountFlags};
+use tempdir::TempDir;
+
+use crate::error::FuseError;
+
+/// Where the origin and the overlay upper layer are created without `--work-dir`.
+const DEFAULT_WORK_DIR: &str = "/mnt";
+
+/// What the sources are and how they become the origin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SourceType {
+    /// Device or image files, mounted through loop devices.
+    #[default]
+    Loop,
+    /// A single directory that is used in place, nothing is mounted.
+    Dir,
+    /// Directories bind mounted into the origin, they can be combined like devices.
+    Bind,
+}
+
+impl FromStr for SourceType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "loop" => Ok(SourceType::Loop),
+            "dir" => Ok(SourceType::Dir),
+            "bind" => Ok(SourceType::Bind),
+            other => bail!("{other:?} is not a valid source type(expected loop, dir or bind)"),
+        }
+    }
+}
+
+/// Root of the origin.
+pub(crate) enum OriginRoot {
+    /// Temporary directory the sources are mounted in, it's removed on unmount.
+    Mounted(TempDir),
+    /// Source directory used in place, it's left alone on unmount.
+    Dir(PathBuf),
+}
+
+impl OriginRoot {
+    pub fn path(&self) -> &Path {
+        match self {
+            OriginRoot::Mounted(dir) => dir.path(),
+            OriginRoot::Dir(dir) => dir,
+        }
+    }
+}
+
+impl AsRef<Path> for OriginRoot {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+/// Tells when the origin is gone underneath the proxy, e.g. its device was unmounted or its
+/// directory removed. Once it's lost every operation fails with EIO until the proxy is remounted.
+#[derive(Debug)]
+pub(crate) struct OriginHealth {
+    root: PathBuf,
+    /// Device of the root when the proxy was set up, `None` if it couldn't be checked.
+    dev: Option<u64>,
+    lost: AtomicBool,
+}
+
+impl OriginHealth {
+    /// The origin root has to be mounted already.
+    pub fn new(root: &Path) -> Self {
+        let dev = match fs::metadata(root) {
+            Ok(metadata) => Some(metadata.dev()),
+            Err(err) => {
+                warn!("Failed to stat the origin root, a lost origin won't be detected: {err}");
+                None
+            }
+        };
+
+        Self {
+            root: root.to_path_buf(),
+            dev,
+            lost: AtomicBool::new(false),
+        }
+    }
+
+    pub fn ensure_available(&self) -> Result<(), FuseError> {
+        match self.lost.load(Ordering::Relaxed) {
+            true => Err(FuseError::IO),
+            false => Ok(()),
+        }
+    }
+
+    /// Error a failed operation is replied with. Errors that could come from a missing origin
+    /// become EIO once the origin root can't be reached either or isn't on the same device
+    /// anymore, which marks the origin as lost. Otherwise they're about a single item, e.g. a
+    /// device file without a driver, and are kept as is.
+    pub fn reply_error(&self, err: FuseError) -> FuseError {
+        if self.lost.load(Ordering::Relaxed) {
+            return FuseError::IO;
+        }
+
+        let Some(dev) = self.dev.filter(|_| err.hints_lost_origin()) else {
+            return err;
+        };
+        let root_gone = fs::metadata(&self.root).map_or(true, |metadata| metadata.dev() != dev);
+        if !root_gone {
+            return err;
+        }
+
+        if !self.lost.swap(true, Ordering::Relaxed) {
+            error!(
+                "ORIGIN {:?} IS GONE({err}), every operation fails with EIO until the proxy is \
+                 remounted",
+                self.root
+            );
+        }
+
+        FuseError::IO
+    }
+}
+
+/// Sets up the origin root with each source in its subdirectory, a single source can be the
+/// root itself with an empty subdirectory.
+///
+/// `RDONLY` in `flags` keeps mounted sources read-only, a directory used in place relies on the
+/// proxy rejecting changes.
+pub(crate) fn mount_sources(
+    source_type: SourceType,
+    sources: &[(PathBuf, PathBuf)],
+    flags: MountFlags,
+    work_dir: Option<&Path>,
+) -> anyhow::Result<(OriginRoot, Vec<Mount>)> {
+    if source_type == SourceType::Dir {
+        let [(source, _)] = sources else {
+            bail!("Only a single source can be used in place, bind mount several ones instead");
+        };
+        ensure!(source.is_dir(), "{source:?} isn't a directory");
+
+        let root = source
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {source:?}"))?;
+        debug!("Using {root:?} as the origin in place");
+
+        return Ok((OriginRoot::Dir(root), Vec::new()));
+    }
+
+    let file_name = match sources {
+        [(source, _)] => source
+            .file_name()
+            .expect("mount point is expected to be valid Path")
+            .to_str()
+            .unwrap(),
+        _ => "rvfs",
+    };
+    let origin_mount = work_temp_dir(work_dir, file_name)?;
+    debug!("Real mount point: {:?}", origin_mount.as_ref());
+
+    let mut mounts: Vec<Mount> = Vec::with_capacity(sources.len());
+    for (source, subdir) in sources {
+        let target = origin_mount.path().join(subdir);
+        let mount = fs::create_dir_all(&target)
+            .with_context(|| format!("Failed to create {target:?} mount point"))
+            .and_then(|()| mount_source(source_type, source, &target, flags));
+
+        match mount {
+            Ok(mount) => mounts.push(mount),
+            Err(err) => {
+                for mount in mounts.iter().rev() {
+                    let _ = mount.unmount(UnmountFlags::DETACH);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    Ok((OriginRoot::Mounted(origin_mount), mounts))
+}
+
+/// Creates a temporary directory in `work_dir`. Without one it goes to /mnt, or to the system
+/// temporary directory if /mnt isn't usable.
+pub(crate) fn work_temp_dir(work_dir: Option<&Path>, prefix: &str) -> anyhow::Result<TempDir> {
+    if let Some(work_dir) = work_dir {
+        return TempDir::new_in(work_dir, prefix)
+            .with_context(|| format!("Failed to create {prefix} directory in {work_dir:?}"));
+    }
+
+    TempDir::new_in(DEFAULT_WORK_DIR, prefix)
+        .or_else(|err| {
+            let temp_dir = env::temp_dir();
+            debug!("Unable to use {DEFAULT_WORK_DIR}: {err}, falling back to {temp_dir:?}");
+            TempDir::new_in(temp_dir, prefix)
+        })
+        .with_context(|| {
+            format!(
+                "Failed to create {prefix} directory in {DEFAULT_WORK_DIR} or the temp directory"
+            )
+        })
+}
+
+fn mount_source(
+    source_type: SourceType,
+    source: &Path,
+    target: &Path,
+    flags: MountFlags,
+) -> anyhow::Result<Mount> {
+    let mount = match source_type {
+        SourceType::Bind => {
+            ensure!(source.is_dir(), "{source:?} isn't a directory");
+            Mount::builder()
+                .flags(MountFlags::BIND)
+                .mount(source, target)
+        }
+        _ => Mount::builder()
+            .explicit_loopback()
+            .flags(flags)
+            .mount(source, target),
+    };
+    let mount = match mount {
+        Ok(mount) => mount,
+        // A device that is mounted already can't be mounted read-write once more
+        Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+            return Err(err).with_context(|| {
+                format!("Failed to mount {source:?} to {target:?}, is it mounted already?")
+            });
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to mount {source:?} to {target:?}"))
+        }
+    };
+
+    // The kernel ignores other flags of a new bind mount, they're applied by a remount
+    if source_type == SourceType::Bind && flags.contains(MountFlags::RDONLY) {
+        let remount = Mount::builder()
+            .flags(MountFlags::BIND | MountFlags::REMOUNT | flags)
+            .mount(source, target);
+        if let Err(err) = remount {
+            let _ = mount.unmount(UnmountFlags::DETACH);
+            return Err(err).with_context(|| format!("Failed to make {target:?} read-only"));
+        }
+    }
+
+    Ok(mount)
+}
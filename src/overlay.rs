@@ -0,0 +1,89 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::{error, info};
+use sys_mount::{Mount, Unmount, UnmountFlags};
+use tempdir::TempDir;
+
+/// Writable layer placed over a read-only origin.
+///
+/// Modified origin files are copied up into `upper` first, new files are created there directly.
+/// Deleted origin files are remembered as whiteouts, so they stay hidden in the proxy while the
+/// origin itself is never touched.
+pub struct Overlay {
+    upper: TempDir,
+    /// tmpfs the upper layer lives in, so changes are kept in memory only.
+    tmpfs: Option<Mount>,
+    whiteouts: HashSet<PathBuf>,
+}
+
+impl Overlay {
+    /// Keeps the upper layer in a tmpfs of `tmpfs_size` bytes if there is one, writes past it
+    /// fail with `ENOSPC`.
+    pub fn new(upper: TempDir, tmpfs_size: Option<u64>) -> anyhow::Result<Self> {
+        let tmpfs = tmpfs_size
+            .map(|size| {
+                let data = format!("size={size},mode=0755");
+                Mount::builder()
+                    .fstype("tmpfs")
+                    .data(&data)
+                    .mount("tmpfs", upper.path())
+                    .with_context(|| format!("Failed to mount tmpfs at {:?}", upper.path()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            upper,
+            tmpfs,
+            whiteouts: HashSet::new(),
+        })
+    }
+
+    /// Unmounts the tmpfs of the upper layer, its content is gone afterwards.
+    pub fn unmount(&self) {
+        let Some(tmpfs) = self.tmpfs.as_ref() else {
+            return;
+        };
+
+        match tmpfs.unmount(UnmountFlags::DETACH) {
+            Ok(()) => info!("Unmounted overlay tmpfs {:?}", self.upper.path()),
+            Err(err) => error!(
+                "Failed to unmount overlay tmpfs {:?}: {err}",
+                self.upper.path()
+            ),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.upper.path()
+    }
+
+    /// Upper layer location of an item, `relative` is a path relative to the proxy root.
+    pub fn upper_path<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+        self.upper.path().join(relative)
+    }
+
+    /// Creates missing upper layer directories above `upper_path`.
+    pub fn prepare_parent(&self, upper_path: &Path) -> std::io::Result<()> {
+        match upper_path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_whiteout<P: AsRef<Path>>(&self, relative: P) -> bool {
+        self.whiteouts.contains(relative.as_ref())
+    }
+
+    pub fn add_whiteout(&mut self, relative: PathBuf) {
+        self.whiteouts.insert(relative);
+    }
+
+    pub fn remove_whiteout<P: AsRef<Path>>(&mut self, relative: P) {
+        self.whiteouts.remove(relative.as_ref());
+    }
+}
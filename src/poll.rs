@@ -0,0 +1,58 @@
+//! Wakes readers polling the virtual files once there is something new to read in them.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use fuser::Notifier;
+use log::{debug, warn};
+
+/// Kernel poll handles of the virtual files, keyed by the handle.
+///
+/// The files are always readable, so new data is reported with `POLLPRI` the way sysfs
+/// attributes do it: a reader polls for it and re-reads the file once woken.
+#[derive(Default)]
+pub struct PollWaiters {
+    /// Set once the session is created, nobody can poll before that.
+    notifier: OnceLock<Notifier>,
+    /// `true` once the handle was woken and the next poll reports new data.
+    handles: Mutex<HashMap<u64, bool>>,
+}
+
+impl PollWaiters {
+    /// Hands over the notifier of the session serving the proxy, wake-ups are dropped until then.
+    pub fn attach(&self, notifier: Notifier) {
+        if self.notifier.set(notifier).is_err() {
+            warn!("Poll notifier is already attached");
+        }
+    }
+
+    /// Returns whether there is new data for `kh` since its last poll and, with `schedule`,
+    /// keeps it to be woken on the next change.
+    pub(crate) fn poll(&self, kh: u64, schedule: bool) -> bool {
+        let mut handles = self.handles.lock().unwrap();
+        let notified = handles.remove(&kh).unwrap_or_default();
+
+        if schedule {
+            handles.insert(kh, false);
+        }
+
+        notified
+    }
+
+    /// Wakes every waiting reader, handles of files closed in the meantime are just dropped.
+    /// Without a notifier the change is still reported by the next poll.
+    pub(crate) fn notify(&self) {
+        let notifier = self.notifier.get();
+
+        let mut handles = self.handles.lock().unwrap();
+        for (kh, notified) in handles.iter_mut().filter(|(_, notified)| !**notified) {
+            match notifier.map_or(Ok(()), |notifier| notifier.poll(*kh)) {
+                Ok(()) => *notified = true,
+                Err(err) => debug!("Failed to wake {kh} poll handle: {err}"),
+            }
+        }
+        handles.retain(|_, notified| *notified);
+    }
+}
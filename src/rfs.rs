@@ -1,420 +1,5135 @@
 use std::{
-    ffi::OsStr,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
     fs,
-    fs::{read_dir, DirEntry, File},
-    mem::ManuallyDrop,
+    fs::{read_dir, File},
+    io::{ErrorKind, Write},
     ops::Add,
-    os::{
-        fd::{FromRawFd, IntoRawFd},
-        unix::fs::{MetadataExt, PermissionsExt},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, symlink, FileExt, FileTypeExt, MetadataExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd, RawFd},
     },
-    path::{Path, PathBuf},
-    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::{Duration, SystemTime},
+    panic::{self, AssertUnwindSafe},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context;
 use clamav_rs::engine::ScanResult;
-use fuser::{FileAttr, FileType};
+use fuser::{consts, FileAttr, FileType, FUSE_ROOT_ID};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use log::{debug, error, info, trace, warn};
 use petgraph::stable_graph::NodeIndex;
-use sys_mount::{Mount, Unmount, UnmountFlags};
-use tempdir::TempDir;
+use sys_mount::{Mount, MountFlags, Unmount, UnmountFlags};
 
 use crate::{
+    allow,
+    audit::{AuditLog, Caller},
+    config::Config,
+    detections::DetectionLog,
     error::FuseError,
-    inode::{FileAttrBuilder, Inode, InodeList, OpenedHandlers},
-    scanner::ClamAV,
+    handle::{DirEntry, DirHandleTable, FileHandle, HandleTable},
+    inode::{FileAttrBuilder, Inode, InodeList, Layer, ScanState, VirtualFile},
+    origin::{self, OriginHealth, OriginRoot},
+    overlay::Overlay,
+    poll::PollWaiters,
+    scanner::{ClamAV, NullScanner, ScanMode, Scanner},
+    state::{State, StateItem, STATE_VERSION},
+    watcher::Watcher,
 };
 
 type FuseResult<T> = Result<T, FuseError>;
 
+/// Permission bits of a file mode, including setuid, setgid and sticky bits.
+const PERMISSION_BITS: u32 = 0o7777;
+
+/// Longest name in bytes most file systems take, `NAME_MAX` of Linux.
+const NAME_MAX: usize = 255;
+
+/// Linux gives up on resolving a path after this many symlinks.
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// Suffix of stubs that explain why a file is missing from the proxy.
+const BLOCK_STUB_SUFFIX: &str = ".rvfs-blocked";
+
+/// Name of the proxy root file with scan statistics.
+const STATS_FILE_NAME: &str = ".rvfs-stats";
+
+/// Name of the proxy root file that takes commands.
+const CONTROL_FILE_NAME: &str = ".rvfs-control";
+
+/// Prefix of the name an origin file is moved aside to when it's removed while still open.
+const UNLINKED_PREFIX: &str = ".rvfs-unlinked-";
+
+/// Read-only extended attribute with the path an item has on the origin.
+const ORIGIN_XATTR: &str = "user.rvfs.origin";
+
+/// `__FMODE_EXEC` the kernel sets in open flags of files opened by `execve`.
+const FMODE_EXEC: i32 = 0x20;
+
+/// Returned on reads of the control file.
+const CONTROL_USAGE: &str = "\
+Write one command per line:
+    rescan PATH    scan PATH subtree again and drop newly detected items,
+                   PATH is relative to the proxy root, `/` is the root itself
+";
+
 pub struct Rfs {
-    inode_list: RwLock<InodeList>,
+    inode_list: Arc<RwLock<InodeList>>,
+    handles: HandleTable,
+    dir_handles: DirHandleTable,
     proxy_mount: PathBuf,
-    origin_mount: TempDir,
-    mount: Mount,
-    clamav: ClamAV,
+    /// Root of the origin: the device itself or, with several devices, the directory they're
+    /// mounted in. A directory source used in place is the root as is.
+    origin_mount: OriginRoot,
+    origin_health: OriginHealth,
+    mounts: Vec<Mount>,
+    overlay: Option<Overlay>,
+    scanner: Arc<dyn Scanner>,
+    /// Items that are trusted and never scanned.
+    exclude: GlobSet,
+    /// Set with `--watch`.
+    watcher: Option<Watcher>,
+    /// Devices of the origin root, the sources and the overlay upper layer. Directories on other
+    /// ones are mounted inside the origin and aren't listed, see `--follow-mounts`.
+    source_devs: HashSet<u64>,
+    detections: Option<DetectionLog>,
+    audit: Option<AuditLog>,
+    /// Readers polling the virtual files.
+    poll_waiters: Arc<PollWaiters>,
+    config: Config,
+}
+
+/// Origin directory entry that is added to the proxy once it's scanned.
+struct ListedItem {
+    origin_path: PathBuf,
+    proxy_path: PathBuf,
+    attr: FileAttr,
+    link_target: Option<PathBuf>,
+    /// Excluded items and symlinks aren't scanned, neither are any items in lazy scan mode.
+    scan: bool,
+    scan_state: ScanState,
 }
 
 impl Rfs {
-    pub fn new(source: PathBuf, mount_point: PathBuf) -> anyhow::Result<Self> {
-        let clamav = ClamAV::new().with_context(|| "Failed to create ClamAV scanner")?;
+    /// Mounts each source device in its subdirectory of the origin, a single device can be
+    /// mounted as the origin root with an empty subdirectory.
+    pub fn new(
+        sources: Vec<(PathBuf, PathBuf)>,
+        mount_point: PathBuf,
+        config: Config,
+    ) -> anyhow::Result<Self> {
+        let scanner: Arc<dyn Scanner> = if config.no_scan {
+            warn!("Scanning is disabled, all files are treated as clean");
+            Arc::new(NullScanner)
+        } else {
+            Arc::new(ClamAV::new(&config).with_context(|| "Failed to create ClamAV scanner")?)
+        };
 
-        let file_name = source
-            .file_name()
-            .expect("mount point is expected to be valid Path")
-            .to_str()
-            .unwrap();
-        let origin_mount = TempDir::new_in("/mnt", file_name).unwrap();
-        debug!("Real mount point: {:?}", origin_mount.as_ref());
+        let mut exclude = GlobSetBuilder::new();
+        for pattern in &config.exclude {
+            exclude.add(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid {pattern:?} exclude pattern"))?,
+            );
+        }
+        let exclude = exclude
+            .build()
+            .with_context(|| "Failed to build exclude patterns")?;
+
+        let detections = config
+            .detections_log
+            .as_deref()
+            .map(DetectionLog::open)
+            .transpose()?;
+        let audit = config
+            .audit_log
+            .as_deref()
+            .map(AuditLog::open)
+            .transpose()?;
+
+        let file_name = match sources.as_slice() {
+            [(source, _)] => source
+                .file_name()
+                .expect("mount point is expected to be valid Path")
+                .to_str()
+                .unwrap(),
+            _ => "rvfs",
+        };
+
+        let flags = if config.overlay || config.read_only {
+            MountFlags::RDONLY
+        } else {
+            MountFlags::empty()
+        };
+
+        let (origin_mount, mounts) = origin::mount_sources(
+            config.source_type,
+            &sources,
+            flags,
+            config.work_dir.as_deref(),
+        )?;
+
+        let overlay = if config.overlay {
+            let overlay =
+                origin::work_temp_dir(config.work_dir.as_deref(), &format!("{file_name}-upper"))
+                    .with_context(|| "Failed to create overlay upper layer")
+                    .and_then(|upper| {
+                        debug!("Overlay upper layer: {:?}", upper.path());
+                        Overlay::new(upper, config.overlay_size)
+                    });
+
+            match overlay {
+                Ok(overlay) => Some(overlay),
+                Err(err) => {
+                    for mount in mounts.iter().rev() {
+                        let _ = mount.unmount(UnmountFlags::DETACH);
+                    }
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+
+        let source_devs = [origin_mount.path().to_path_buf()]
+            .into_iter()
+            .chain(
+                sources
+                    .iter()
+                    .map(|(_, subdir)| origin_mount.path().join(subdir)),
+            )
+            .chain(overlay.as_ref().map(|overlay| overlay.root().to_path_buf()))
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|meta| meta.dev())
+            .collect();
+
+        let origin_health = OriginHealth::new(origin_mount.path());
 
-        let mount = Mount::builder()
-            .explicit_loopback()
-            .mount(source, origin_mount.as_ref())?;
         Ok(Self {
-            inode_list: RwLock::new(InodeList::default()),
+            inode_list: Arc::new(RwLock::new(InodeList::default())),
+            handles: HandleTable::new(config.max_open_files),
+            dir_handles: DirHandleTable::default(),
             proxy_mount: mount_point,
             origin_mount,
-            mount,
-            clamav,
+            origin_health,
+            mounts,
+            overlay,
+            scanner,
+            exclude,
+            watcher: None,
+            source_devs,
+            detections,
+            audit,
+            poll_waiters: Arc::default(),
+            config,
         })
     }
 
-    pub fn inode_list(&self) -> RwLockReadGuard<InodeList> {
+    /// Fails with EIO once the origin is lost, every request that may reach it checks it first.
+    pub fn ensure_origin_available(&self) -> FuseResult<()> {
+        self.origin_health.ensure_available()
+    }
+
+    /// Error a failed request is replied with, errors of a lost origin become EIO.
+    pub fn reply_error(&self, err: FuseError) -> FuseError {
+        self.origin_health.reply_error(err)
+    }
+
+    pub fn inode_list(&self) -> RwLockReadGuard<'_, InodeList> {
         self.inode_list.read().unwrap()
     }
 
-    pub fn inode_list_write(&self) -> RwLockWriteGuard<InodeList> {
+    pub fn inode_list_write(&self) -> RwLockWriteGuard<'_, InodeList> {
         self.inode_list.write().unwrap()
     }
 
+    pub fn max_read_chunk(&self) -> usize {
+        self.config.max_read_chunk
+    }
+
+    pub fn entry_ttl(&self) -> Duration {
+        self.config.entry_ttl
+    }
+
+    pub fn attr_ttl(&self) -> Duration {
+        self.config.attr_ttl
+    }
+
+    /// Shared inode list, for code that runs outside of the FUSE session like signal handlers.
+    pub fn inode_list_handle(&self) -> Arc<RwLock<InodeList>> {
+        Arc::clone(&self.inode_list)
+    }
+
+    /// Shared scanner, so signatures can be reloaded outside of the FUSE session.
+    pub fn scanner_handle(&self) -> Arc<dyn Scanner> {
+        Arc::clone(&self.scanner)
+    }
+
+    /// Readers polling the virtual files, the session notifier is attached to it.
+    pub fn poll_waiters_handle(&self) -> Arc<PollWaiters> {
+        Arc::clone(&self.poll_waiters)
+    }
+
     pub fn init(&mut self) {
+        // The watcher thread is started here rather than on creation, it wouldn't survive
+        // `--daemon` forking in between
+        if self.config.watch {
+            match Watcher::new() {
+                Ok(watcher) => self.watcher = Some(watcher),
+                Err(err) => error!("Origin changes aren't watched: {err:#}"),
+            }
+        }
+
         let attr = self.stat(&self.origin_mount).unwrap();
         let root_ino = 1;
         let attr = attr.with_ino(root_ino).build();
 
         let mut inode_list = self.inode_list.write().unwrap();
 
-        let root_node = inode_list.list.add_node(Inode::new(
+        let root_node = inode_list.insert_root(Inode::new(
             self.proxy_mount.clone(),
             self.origin_mount.path().to_path_buf(),
             attr,
         ));
-        let self_reference = Inode::new(
-            PathBuf::from("."),
-            self.origin_mount.path().to_path_buf(),
-            attr,
-        );
-        inode_list.insert(self_reference, root_node);
 
-        let upper_folder = Inode::new(
-            PathBuf::from(".."),
-            self.proxy_mount.parent().unwrap().to_path_buf(),
-            attr, // TODO: wrong attributes here
-        );
-        inode_list.insert(upper_folder, root_node);
-    }
+        for (name, perm, virtual_file) in [
+            (STATS_FILE_NAME, 0o444, VirtualFile::ScanStats),
+            (CONTROL_FILE_NAME, 0o600, VirtualFile::Control),
+        ] {
+            let virtual_attr = FileAttrBuilder::new()
+                .with_kind(FileType::RegularFile)
+                .with_perm(perm)
+                .with_nlink(1)
+                .with_uid(attr.uid)
+                .with_gid(attr.gid)
+                .with_blksize(attr.blksize)
+                .build();
 
-    fn stat<P: AsRef<Path>>(&self, item: P) -> FuseResult<FileAttrBuilder> {
-        debug!("Stat with {:?}", item.as_ref());
+            let mut inode = Inode::new(self.proxy_mount.join(name), PathBuf::new(), virtual_attr);
+            inode.virtual_file = Some(virtual_file);
+            inode_list.insert(inode, root_node);
+        }
+        drop(inode_list);
 
-        let file = File::open(item).map_err(|_| FuseError::last())?;
-        let meta = file.metadata().map_err(|_| FuseError::last())?;
+        if let Some(state_file) = self.config.state_file.as_ref() {
+            match State::load(state_file) {
+                Ok(state) => self.restore_state(state, root_node),
+                Err(err) => info!("Starting without a saved state: {err:#}"),
+            }
+        }
 
-        Ok(FileAttrBuilder::new()
-            .with_size(meta.size())
-            .with_blocks(meta.blocks())
-            .with_atime(
-                SystemTime::UNIX_EPOCH
-                    .add(Duration::from_secs(u64::try_from(meta.atime()).unwrap())),
-            )
-            .with_mtime(
-                SystemTime::UNIX_EPOCH
-                    .add(Duration::from_secs(u64::try_from(meta.mtime()).unwrap())),
-            )
-            .with_ctime(
-                SystemTime::UNIX_EPOCH
-                    .add(Duration::from_secs(u64::try_from(meta.ctime()).unwrap())),
-            )
-            .with_crtime(meta.created().map_err(|_| FuseError::last())?)
-            .with_kind(std_file_type_to_fuse_file_type(meta.file_type()))
-            .with_perm(u16::try_from(meta.permissions().mode()).unwrap())
-            .with_nlink(u32::try_from(meta.nlink()).unwrap())
-            .with_uid(meta.uid())
-            .with_gid(meta.gid())
-            .with_rdev(u32::try_from(meta.rdev()).unwrap())
-            .with_blksize(u32::try_from(meta.blksize()).unwrap())
-            .with_flags(0))
+        // Directories are listed and scanned on first access, so the proxy is usable right away
+        info!("{:?} is mounted and ready", self.proxy_mount);
+        if let Some(ready_fd) = self.config.ready_fd.take() {
+            notify_ready(ready_fd);
+        }
     }
 
-    pub fn create(
-        &mut self,
-        name: &OsStr,
-        parent_ino: u64,
-        mode: u32,
-        kind: FileType,
-    ) -> FuseResult<FileAttr> {
-        let mut inode_list = self.inode_list.write().unwrap();
+    /// Settings that decide whether a file is scanned and how its verdict turns out, verdicts
+    /// of a saved state are valid only with the same ones.
+    fn scan_settings(&self) -> String {
+        format!(
+            "exclude={:?} max_scan_size={:?} oversized_policy={:?} scan_types={:?} \
+             archive_limits={:?} allow_hash_file={:?} scan_timeout={:?} timeout_policy={:?} \
+             scan_mode={:?}",
+            self.config.exclude,
+            self.config.max_scan_size,
+            self.config.oversized_policy,
+            self.config.scan_types,
+            self.config.archive_limits,
+            self.config.allow_hash_file,
+            self.config.scan_timeout,
+            self.config.timeout_policy,
+            self.config.scan_mode
+        )
+    }
 
-        let (parent_node, parent_inode) = inode_list
-            .find_by_id(parent_ino)
-            .ok_or(FuseError::NO_EXIST)?;
+    /// Puts items of a previous mount back into the graph, items that changed on the origin since
+    /// are left out for `add_folder` to scan again.
+    fn restore_state(&self, state: State, root_node: NodeIndex) {
+        if state.signature_count != self.scanner.signature_count()
+            || state.scan_settings != self.scan_settings()
+        {
+            info!("Saved state was made with other signatures or scan settings, ignoring it");
+            return;
+        }
 
-        if inode_list.find_child_by_name(parent_node, name).is_some() {
-            return Err(FuseError::FILE_EXISTS);
-        };
+        let mut inode_list = self.inode_list_write();
+        let mut nodes = HashMap::from([(PathBuf::new(), root_node)]);
+        // Inos of the saved state to the new ones, hard linked names share an ino
+        let mut inos = HashMap::new();
+        let mut restored = 0;
 
-        let proxy_path = parent_inode.proxy_path.join(name);
-        let origin_path = parent_inode.origin_path.join(name);
+        for item in state.items {
+            let Some(&parent_node) = item.path.parent().and_then(|parent| nodes.get(parent)) else {
+                continue;
+            };
 
-        match kind {
-            FileType::RegularFile => {
-                if let Err(err) = File::create(&origin_path) {
-                    error!("Failed to create {origin_path:?} file: {err}");
-                    return Err(FuseError::last());
-                }
+            let origin_path = self.origin_mount.path().join(&item.path);
+            let Ok(attr) = self.stat(&origin_path).map(FileAttrBuilder::build) else {
+                continue;
+            };
+            let unchanged = match attr.kind {
+                FileType::Directory => item.directory,
+                _ => !item.directory && attr.size == item.size && attr.mtime == item.mtime,
+            };
+            if !unchanged {
+                debug!("{origin_path:?} changed since the state was saved");
+                continue;
             }
-            FileType::Directory => {
-                if let Err(err) = fs::create_dir(&origin_path) {
-                    error!("Failed to create {origin_path:?} directory: {err}");
-                    return Err(FuseError::last());
+
+            let mut inode = Inode::new(self.proxy_mount.join(&item.path), origin_path, attr);
+            if attr.kind == FileType::Symlink {
+                let Ok(target) = fs::read_link(&inode.origin_path) else {
+                    continue;
+                };
+                if self
+                    .ensure_link_contained(&inode.origin_path, &target)
+                    .is_err()
+                {
+                    continue;
                 }
+                inode.link_target = Some(target);
             }
-            _ => {
-                error!("{kind:?} creating is not implemented!");
-                return Err(FuseError::NOT_IMPLEMENTED);
-            }
-        };
-
-        let attr = FileAttrBuilder::new()
-            .with_kind(kind)
-            .with_perm(mode as u16)
-            .build();
+            inode.scan_state = item.scan_state;
 
-        let inode = Inode::new(proxy_path, origin_path, attr);
-        let attr = inode_list.insert(inode, parent_node);
-
-        Ok(attr)
-    }
+            let attr = match inos.get(&item.ino) {
+                Some(&ino) => {
+                    inode.attr.ino = ino;
+                    inode_list.insert_link(inode, parent_node)
+                }
+                None => inode_list.insert(inode, parent_node),
+            };
+            inos.insert(item.ino, attr.ino);
 
-    fn insert_item(&mut self, item: PathBuf, parent_node: NodeIndex) -> FuseResult<()> {
-        let proxy_path = self.origin_path_to_proxy_path(&item);
-        match self.clamav.scan(&item) {
-            Ok(scan_result) => match scan_result {
-                ScanResult::Clean => {}
-                ScanResult::Whitelisted => {
-                    warn!("{item:?} is whitelisted")
-                }
-                ScanResult::Virus(_) => {
-                    error!("{item:?} is a virus!!!");
-                    return Err(FuseError::OPERATION_NOT_PERMITTED);
+            if attr.kind == FileType::Directory {
+                if let Some((node_index, _)) = inode_list.find_by_id(attr.ino) {
+                    nodes.insert(item.path, node_index);
                 }
-            },
-            Err(err) => {
-                error!("Failed to scan {:?} file: {err}", item);
-                return Err(FuseError::IO);
             }
+            restored += 1;
         }
 
-        let attr = self.stat(&item)?.build();
-
-        trace!("Added {:?} item", proxy_path);
-        let inode = Inode::new(proxy_path, item, attr);
-
-        let mut inode_list = self.inode_list.write().unwrap();
-        inode_list.insert(inode, parent_node);
-        Ok(())
+        info!("Restored {restored} items from the saved state");
     }
 
-    pub fn add_folder<P: AsRef<Path>>(&mut self, folder: P, ino: u64) -> FuseResult<()> {
-        trace!("Adding folder: {:?}...", folder.as_ref());
-        let (items, parent_node) = {
-            let inode_list = self.inode_list.read().unwrap();
+    /// Saves origin items known to the proxy, items of the temporary overlay layer are skipped.
+    fn save_state(&self, path: &Path) -> anyhow::Result<()> {
+        let inode_list = self.inode_list();
+        let (root_node, _) = inode_list
+            .find_by_id(FUSE_ROOT_ID)
+            .context("There is no root item")?;
 
-            let parent_node = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?.0;
-
-            let items = read_dir(folder)
-                .map_err(|_| FuseError::last())?
-                .filter_map(|item| match item {
-                    Ok(item) => {
-                        if inode_list.childs(parent_node).any(|child| {
-                            child.origin_path.file_name().unwrap_or(OsStr::new(".."))
-                                == item.file_name()
-                        }) {
-                            None
-                        } else {
-                            Some(Ok(item))
-                        }
-                    }
-                    Err(err) => {
-                        error!("readdir item error: {err}");
-                        Some(Err(FuseError::last()))
-                    }
+        let items = inode_list
+            .descendants(root_node)
+            .filter(|(_, inode)| inode.virtual_file.is_none() && inode.layer == Layer::Lower)
+            .filter_map(|(_, inode)| {
+                let path = inode.proxy_path.strip_prefix(&self.proxy_mount).ok()?;
+
+                Some(StateItem {
+                    path: path.to_path_buf(),
+                    ino: inode.attr.ino,
+                    directory: inode.attr.kind == FileType::Directory,
+                    size: inode.attr.size,
+                    mtime: inode.attr.mtime,
+                    scan_state: inode.scan_state.clone(),
                 })
-                .collect::<Result<Vec<DirEntry>, FuseError>>()?;
+            })
+            .collect::<Vec<_>>();
+        let count = items.len();
 
-            (items, parent_node)
-        };
+        State {
+            version: STATE_VERSION,
+            signature_count: self.scanner.signature_count(),
+            scan_settings: self.scan_settings(),
+            items,
+        }
+        .save(path)?;
 
-        for item in items {
-            match self.insert_item(item.path(), parent_node) {
-                Ok(()) => {}
-                Err(err) if err == FuseError::OPERATION_NOT_PERMITTED => {
-                    warn!("Operation is not permitted for {:?}", item.path());
-                    continue;
-                }
-                Err(err) => return Err(err),
-            }
+        info!("Saved {count} items to {path:?} state");
+        Ok(())
+    }
+
+    /// Fails on a `--read-only` mount, regardless of what the kernel enforces.
+    pub fn ensure_writable(&self) -> FuseResult<()> {
+        if self.config.read_only {
+            return Err(FuseError::READ_ONLY_FILE_SYSTEM);
         }
 
         Ok(())
     }
 
-    pub fn allocate_fh(&mut self, inode: u64, read: bool, write: bool) -> FuseResult<u64> {
-        let mut write_view = self.inode_list.write().unwrap();
-        let (_, inode) = write_view
-            .find_by_id_mut(inode)
-            .ok_or(FuseError::NO_EXIST)?;
+    /// Checks `mask` of `R_OK`, `W_OK` and `X_OK` against the item's permission bits for a caller.
+    pub fn check_access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> FuseResult<()> {
+        let inode_list = self.inode_list();
+        let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
 
-        let count = if let Some(open_handlers) = inode.open_handles.as_mut() {
-            open_handlers.count += 1;
+        if mask & libc::W_OK != 0 && self.config.read_only {
+            return Err(FuseError::READ_ONLY_FILE_SYSTEM);
+        }
 
-            open_handlers.count
+        if access_allowed(&inode.attr, uid, gid, mask) {
+            Ok(())
         } else {
-            let file = match File::options()
-                .read(read)
-                .write(write)
-                .open(&inode.origin_path)
-            {
-                Ok(file) => file,
-                Err(err) => {
-                    error!("Failed to open {:?}: {err}", inode.origin_path);
-                    return Err(FuseError::last());
-                }
-            };
+            Err(FuseError::PERMISSION_DENIED)
+        }
+    }
 
-            inode.open_handles = Some(OpenedHandlers {
-                fh: file.into_raw_fd(),
-                count: 1,
-            });
+    /// Records a change to the audit log, `name` is an item of `ino` directory, otherwise `ino` is
+    /// the changed item itself. `target` is the new name of a rename.
+    pub fn audit(
+        &self,
+        operation: &str,
+        caller: Caller,
+        (ino, name): (u64, Option<&OsStr>),
+        target: Option<(u64, &OsStr)>,
+        result: Result<(), &FuseError>,
+    ) {
+        let Some(audit) = self.audit.as_ref() else {
+            return;
+        };
 
-            1
+        let inode_list = self.inode_list();
+        let proxy_path = |ino: u64, name: Option<&OsStr>| {
+            let path = inode_list.find_by_id(ino).map_or_else(
+                || PathBuf::from(format!("<{ino} ino>")),
+                |(_, inode)| inode.proxy_path.clone(),
+            );
+            match name {
+                Some(name) => path.join(name),
+                None => path,
+            }
         };
 
-        let fh = (count << 2) | u64::from(read) | (u64::from(write) << 1);
+        let path = proxy_path(ino, name);
+        let target = target.map(|(parent, name)| proxy_path(parent, Some(name)));
+        audit.record(operation, caller, &path, target.as_deref(), result);
+    }
 
-        Ok(fh)
+    /// Whether nothing was written through the handle since it was last flushed.
+    pub fn is_first_write(&self, fh: u64) -> bool {
+        self.handles.get(fh).is_some_and(|handle| !handle.written)
     }
 
-    pub fn open_file(
-        &self,
-        node: &Inode,
-        fh: u64,
-        read: bool,
-        write: bool,
-    ) -> FuseResult<ManuallyDrop<File>> {
-        if read && !fn_check_access_read(fh) {
-            error!("Read is not allowed!");
-            return Err(FuseError::OPERATION_NOT_PERMITTED);
+    /// Content of a file that exists only in the proxy.
+    pub fn virtual_content<'a>(&self, virtual_file: &'a VirtualFile) -> Cow<'a, [u8]> {
+        match virtual_file {
+            VirtualFile::BlockStub(content) => Cow::Borrowed(content),
+            VirtualFile::ScanStats => {
+                let report = match self.config.json {
+                    true => self.scanner.report_json(),
+                    false => self.scanner.report(),
+                };
+                Cow::Owned(report.into_bytes())
+            }
+            VirtualFile::Control => Cow::Borrowed(CONTROL_USAGE.as_bytes()),
         }
+    }
 
-        if write && !fn_check_access_write(fh) {
-            error!("Write is not allowed!");
-            return Err(FuseError::OPERATION_NOT_PERMITTED);
+    /// Poll of a virtual file: it's always readable and reports `POLLPRI` once it changed since
+    /// the last poll of `kh`. Origin files are always ready as if poll wasn't supported.
+    pub fn poll_events(&self, ino: u64, kh: u64, flags: u32) -> FuseResult<u32> {
+        let read_view = self.inode_list();
+        let (_, inode) = read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+        let ready = (libc::POLLIN | libc::POLLRDNORM) as u32;
+        if inode.virtual_file.is_none() {
+            return Ok(ready | (libc::POLLOUT | libc::POLLWRNORM) as u32);
+        }
+
+        let schedule = flags & consts::FUSE_POLL_SCHEDULE_NOTIFY != 0;
+        match self.poll_waiters.poll(kh, schedule) {
+            true => Ok(ready | libc::POLLPRI as u32),
+            false => Ok(ready),
         }
+    }
 
-        let open_handlers = node.open_handles.as_ref().ok_or(FuseError::BAD_FD)?;
+    /// Value of an extended attribute the proxy serves itself, the origin ones aren't exposed.
+    pub fn xattr(&self, ino: u64, name: &OsStr) -> FuseResult<Vec<u8>> {
+        let read_view = self.inode_list();
+        let (_, inode) = read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
 
-        Ok(ManuallyDrop::new(unsafe {
-            File::from_raw_fd(open_handlers.fh)
-        }))
+        match name.to_str() {
+            Some(ORIGIN_XATTR) if inode.virtual_file.is_none() => {
+                Ok(inode.origin_path.as_os_str().as_bytes().to_vec())
+            }
+            _ => Err(FuseError::NO_DATA),
+        }
     }
 
-    fn proxy_path_to_origin_path<P: AsRef<Path>>(&self, item: P) -> PathBuf {
-        self.origin_mount
-            .path()
-            .join(item.as_ref().strip_prefix(&self.proxy_mount).unwrap())
+    /// Names of the extended attributes of an item, each one is followed by a NUL.
+    pub fn xattr_names(&self, ino: u64) -> FuseResult<Vec<u8>> {
+        let read_view = self.inode_list();
+        let (_, inode) = read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+        let mut names = Vec::new();
+        if inode.virtual_file.is_none() {
+            names.extend_from_slice(ORIGIN_XATTR.as_bytes());
+            names.push(0);
+        }
+
+        Ok(names)
     }
 
-    fn origin_path_to_proxy_path<P: AsRef<Path>>(&self, item: P) -> PathBuf {
-        self.proxy_mount
-            .as_path()
-            .join(item.as_ref().strip_prefix(&self.origin_mount).unwrap())
+    /// Extended attributes can't be changed, the proxy ones are read-only and the origin ones
+    /// aren't exposed.
+    pub fn ensure_xattr_writable(&self, name: &OsStr) -> FuseResult<()> {
+        match name.to_str() {
+            Some(ORIGIN_XATTR) => Err(FuseError::OPERATION_NOT_PERMITTED),
+            _ => Err(FuseError::NOT_SUPPORTED),
+        }
     }
 
-    pub fn remove(&mut self, ino: u64) -> FuseResult<()> {
-        let mut inode_view = self.inode_list.write().unwrap();
-        let (node_index, inode) = inode_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+    pub fn is_control_file(&self, ino: u64) -> bool {
+        self.inode_list()
+            .find_by_id(ino)
+            .is_some_and(|(_, inode)| inode.virtual_file == Some(VirtualFile::Control))
+    }
 
-        match inode.attr.kind {
-            FileType::RegularFile => {
-                fs::remove_file(&inode.origin_path).map_err(|_| FuseError::last())?;
-            }
-            FileType::Directory => {
-                fs::remove_dir_all(&inode.origin_path).map_err(|_| FuseError::last())?;
-            }
-            other => {
-                error!("Remove is not implemented for {other:?}");
-                return Err(FuseError::NOT_IMPLEMENTED);
+    /// Runs commands written to the control file, see [`CONTROL_USAGE`].
+    pub fn control(&mut self, commands: &[u8]) -> FuseResult<()> {
+        let commands = std::str::from_utf8(commands).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+
+        for command in commands
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            info!("Control command: {command}");
+
+            match command.split_once(char::is_whitespace) {
+                Some(("rescan", path)) => {
+                    let blocked = self.rescan(Path::new(path.trim()))?;
+                    info!("Rescan of {path} blocked {blocked} items");
+                }
+                _ => {
+                    error!("Unknown control command: {command:?}");
+                    return Err(FuseError::INVALID_ARGUMENT);
+                }
             }
         }
 
-        let _ = inode_view.list.remove_node(node_index);
-
         Ok(())
     }
 
-    pub fn rename(
-        &mut self,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-    ) -> FuseResult<()> {
-        let mut inode_list = self.inode_list.write().unwrap();
-
-        let (parent_node, _) = inode_list.find_by_id(parent).ok_or(FuseError::NO_EXIST)?;
+    /// Scans files of a proxy subtree again, returns the amount of items that were dropped as
+    /// infected.
+    fn rescan(&mut self, path: &Path) -> FuseResult<usize> {
+        let files = {
+            let inode_list = self.inode_list();
+            let (mut node, _) = inode_list
+                .find_by_id(FUSE_ROOT_ID)
+                .ok_or(FuseError::NO_EXIST)?;
 
-        let (newparent_node, newparent_inode) = inode_list
-            .find_by_id(newparent)
-            .ok_or(FuseError::NO_EXIST)?;
-        let new_path = newparent_inode.proxy_path.join(newname);
+            for component in path.components() {
+                match component {
+                    Component::RootDir | Component::CurDir => {}
+                    Component::Normal(name) => {
+                        (node, _) = inode_list
+                            .find_child_by_name(node, name)
+                            .ok_or(FuseError::NO_EXIST)?;
+                    }
+                    _ => return Err(FuseError::INVALID_ARGUMENT),
+                }
+            }
 
-        let (node_index, inode) = inode_list
-            .find_child_by_name_mut(parent_node, name)
-            .ok_or(FuseError::NO_EXIST)?;
+            inode_list.regular_files(node)
+        };
 
-        let new = self.proxy_path_to_origin_path(new_path.as_path());
-        fs::rename(&inode.origin_path, &new).map_err(|_| FuseError::last())?;
+        let mut blocked = 0;
+        for (node_index, parent_node) in files {
+            let (origin_path, attr) = {
+                let inode_list = self.inode_list();
+                let Some(inode) = inode_list.list.node_weight(node_index) else {
+                    continue;
+                };
 
-        inode.proxy_path = new_path;
-        inode.origin_path = new;
+                (inode.origin_path.clone(), inode.attr)
+            };
 
-        let edge = inode_list
-            .list
-            .find_edge(parent_node, node_index)
-            .expect("We found a child above so we shouldn't fail here");
-        let _ = inode_list.list.remove_edge(edge);
+            let signature = match detect(
+                &*self.scanner,
+                self.detections.as_ref(),
+                &self.poll_waiters,
+                &origin_path,
+            ) {
+                Ok(Some(signature)) => signature,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Skipped rescan of {origin_path:?}: {err}");
+                    continue;
+                }
+            };
 
-        inode_list.list.add_edge(newparent_node, node_index, ());
+            let _ = self.inode_list_write().remove(node_index);
+            if self.config.leave_block_stub {
+                self.insert_block_stub(&origin_path, &attr, &signature, parent_node);
+            }
+            blocked += 1;
+        }
 
-        Ok(())
+        Ok(blocked)
     }
-}
 
-impl Drop for Rfs {
-    fn drop(&mut self) {
-        match self.mount.unmount(UnmountFlags::DETACH) {
-            Ok(()) => {
-                info!("Unmounted origin {:?} mount", self.origin_mount.path());
-            }
-            Err(err) => {
-                error!(
-                    "Failed to unmounted origin {:?} mount: {err}",
-                    self.origin_mount
-                );
+    /// `FOPEN_*` flags of a newly opened item.
+    pub fn open_flags(&self, ino: u64) -> u32 {
+        match self.inode_list().find_by_id(ino) {
+            // Size of generated content isn't known upfront, so reads shouldn't be cut by it
+            Some((_, inode))
+                if matches!(
+                    inode.virtual_file,
+                    Some(VirtualFile::ScanStats | VirtualFile::Control)
+                ) =>
+            {
+                consts::FOPEN_DIRECT_IO
             }
+            _ => 0,
         }
     }
-}
 
-fn fn_check_access_read(fh: u64) -> bool {
-    (fh & 1) != 0
-}
+    /// Attributes for getattr, with `--attr-passthrough` size and timestamps are taken from the
+    /// origin every time so changes made behind the proxy's back show up.
+    pub fn current_attr(&self, ino: u64) -> FuseResult<FileAttr> {
+        if !self.config.attr_passthrough {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            return Ok(inode.attr);
+        }
 
-fn fn_check_access_write(fh: u64) -> bool {
-    (fh & (1 << 1)) != 0
-}
+        let mut inode_list = self.inode_list_write();
+        let (_, inode) = inode_list.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST)?;
+        if inode.virtual_file.is_some() {
+            return Ok(inode.attr);
+        }
 
-fn std_file_type_to_fuse_file_type(tp: fs::FileType) -> FileType {
-    if tp.is_symlink() {
-        return FileType::Symlink;
+        let origin = self.stat(&inode.origin_path)?.build();
+        inode.attr.size = origin.size;
+        inode.attr.blocks = origin.blocks;
+        inode.attr.atime = origin.atime;
+        inode.attr.mtime = origin.mtime;
+        inode.attr.ctime = origin.ctime;
+
+        Ok(inode.attr)
     }
 
-    if tp.is_dir() {
-        return FileType::Directory;
+    fn stat<P: AsRef<Path>>(&self, item: P) -> FuseResult<FileAttrBuilder> {
+        debug!("Stat with {:?}", item.as_ref());
+
+        let meta = fs::symlink_metadata(item)?;
+        let mtime = epoch_time(meta.mtime(), meta.mtime_nsec());
+
+        Ok(FileAttrBuilder::new()
+            .with_size(meta.size())
+            .with_blocks(meta.blocks())
+            .with_atime(epoch_time(meta.atime(), meta.atime_nsec()))
+            .with_mtime(mtime)
+            .with_ctime(epoch_time(meta.ctime(), meta.ctime_nsec()))
+            // Not every file system keeps the creation time
+            .with_crtime(meta.created().unwrap_or(mtime))
+            .with_kind(std_file_type_to_fuse_file_type(meta.file_type()))
+            .with_perm((meta.mode() & PERMISSION_BITS) as u16)
+            .with_nlink(u32::try_from(meta.nlink()).unwrap_or(u32::MAX))
+            .with_uid(self.config.uid_map.to_proxy(meta.uid()))
+            .with_gid(self.config.gid_map.to_proxy(meta.gid()))
+            .with_rdev(encode_dev(meta.rdev()))
+            .with_blksize(u32::try_from(meta.blksize()).unwrap_or(u32::MAX))
+            .with_flags(0))
     }
 
-    FileType::RegularFile
+    pub fn create(
+        &mut self,
+        name: &OsStr,
+        parent_ino: u64,
+        mode: u32,
+        umask: u32,
+        kind: FileType,
+    ) -> FuseResult<FileAttr> {
+        self.ensure_writable()?;
+        let (parent_node, proxy_path, origin_path) = self.new_item_paths(parent_ino, name)?;
+        let mode = mode & !umask & PERMISSION_BITS;
+
+        match kind {
+            FileType::RegularFile => {
+                if let Err(err) = File::create(&origin_path) {
+                    error!("Failed to create {origin_path:?} file: {err}");
+                    return Err(err.into());
+                }
+            }
+            FileType::Directory => {
+                if let Err(err) = fs::create_dir(&origin_path) {
+                    error!("Failed to create {origin_path:?} directory: {err}");
+                    return Err(err.into());
+                }
+            }
+            _ => {
+                error!("{kind:?} creating is not implemented!");
+                return Err(FuseError::NOT_IMPLEMENTED);
+            }
+        };
+
+        // Origin items are created with the umask of the proxy process rather than the caller's
+        if let Err(err) = fs::set_permissions(&origin_path, fs::Permissions::from_mode(mode)) {
+            error!("Failed to set {origin_path:?} permissions to {mode:o}: {err}");
+            return Err(err.into());
+        }
+
+        // Real size, owner and timestamps, so getattr right after create agrees with the origin
+        let attr = self.stat(&origin_path)?.with_perm(mode as u16).build();
+
+        let inode = Inode::new(proxy_path, origin_path, attr);
+        let attr = self.insert_new_item(inode, parent_node)?;
+
+        if kind == FileType::Directory {
+            self.inode_list_write().link_subdir(parent_node, true);
+        }
+
+        Ok(attr)
+    }
+
+    pub fn symlink(
+        &mut self,
+        parent_ino: u64,
+        name: &OsStr,
+        target: &Path,
+    ) -> FuseResult<FileAttr> {
+        self.ensure_writable()?;
+        let (parent_node, proxy_path, origin_path) = self.new_item_paths(parent_ino, name)?;
+        self.ensure_link_contained(&origin_path, target)?;
+
+        if let Err(err) = symlink(target, &origin_path) {
+            error!("Failed to create {origin_path:?} symlink to {target:?}: {err}");
+            return Err(err.into());
+        }
+
+        let attr = self.stat(&origin_path)?.build();
+
+        let mut inode = Inode::new(proxy_path, origin_path, attr);
+        inode.link_target = Some(target.to_path_buf());
+        self.insert_new_item(inode, parent_node)
+    }
+
+    /// Creates `newname` hard link in `newparent` directory to the item with `ino` ino.
+    pub fn link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> FuseResult<FileAttr> {
+        self.ensure_writable()?;
+        let (parent_node, proxy_path, origin_path) = self.new_item_paths(newparent, newname)?;
+
+        let link = {
+            let mut inode_list = self.inode_list_write();
+            let (_, inode) = inode_list.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST)?;
+            ensure_origin(inode)?;
+
+            if inode.attr.kind == FileType::Directory {
+                error!(
+                    "Hard links to {:?} directory aren't allowed",
+                    inode.proxy_path
+                );
+                return Err(FuseError::OPERATION_NOT_PERMITTED);
+            }
+
+            self.copy_up(inode)?;
+
+            if let Err(err) = fs::hard_link(&inode.origin_path, &origin_path) {
+                error!(
+                    "Failed to link {:?} to {origin_path:?}: {err}",
+                    inode.origin_path
+                );
+                return Err(err.into());
+            }
+
+            let nlink = fs::symlink_metadata(&origin_path)
+                .map(|meta| u32::try_from(meta.nlink()).unwrap_or(u32::MAX))
+                .unwrap_or(inode.attr.nlink.saturating_add(1));
+
+            let mut link = Inode::new(proxy_path, origin_path, inode.attr);
+            link.layer = inode.layer;
+            link.link_target = inode.link_target.clone();
+
+            inode_list.set_nlink(ino, nlink);
+            link.attr.nlink = nlink;
+
+            link
+        };
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.remove_whiteout(relative_path(&self.proxy_mount, &link.proxy_path)?);
+        }
+
+        Ok(self.inode_list_write().insert_link(link, parent_node))
+    }
+
+    /// Statistics of the file system that backs the proxy, the overlay upper layer if there is one
+    /// as all writes go there.
+    pub fn origin_statfs(&self) -> FuseResult<libc::statvfs> {
+        let path = self
+            .overlay
+            .as_ref()
+            .map_or(self.origin_mount.path(), Overlay::root);
+        let c_path =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+            let err = FuseError::last();
+            error!("Failed to statvfs {path:?}: {err}");
+            return Err(err);
+        }
+
+        Ok(stats)
+    }
+
+    pub fn read_link(&self, ino: u64) -> FuseResult<PathBuf> {
+        let inode_list = self.inode_list();
+        let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+        if inode.attr.kind != FileType::Symlink {
+            return Err(FuseError::INVALID_ARGUMENT);
+        }
+
+        fs::read_link(&inode.origin_path).map_err(|err| {
+            error!("Failed to read {:?} link: {err}", inode.origin_path);
+            FuseError::from(err)
+        })
+    }
+
+    /// Resolves proxy and origin paths of a new `name` item in `parent_ino` directory.
+    fn new_item_paths(
+        &self,
+        parent_ino: u64,
+        name: &OsStr,
+    ) -> FuseResult<(NodeIndex, PathBuf, PathBuf)> {
+        ensure_name_fits(name)?;
+
+        let inode_list = self.inode_list();
+
+        let (parent_node, parent_inode) = inode_list
+            .find_by_id(parent_ino)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        if inode_list.find_child_by_name(parent_node, name).is_some() {
+            return Err(FuseError::FILE_EXISTS);
+        };
+
+        // With several devices the root is a temporary directory, items there would be lost
+        if self.mounts.len() > 1 && parent_ino == FUSE_ROOT_ID {
+            error!("Items can't be created next to the mounted devices");
+            return Err(FuseError::PERMISSION_DENIED);
+        }
+
+        let proxy_path = parent_inode.proxy_path.join(name);
+        let origin_path = self.proxy_path_to_origin_path(&proxy_path)?;
+
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.prepare_parent(&origin_path).map_err(|err| {
+                error!("Failed to prepare upper layer for {origin_path:?}: {err}");
+                FuseError::IO
+            })?;
+        }
+
+        Ok((parent_node, proxy_path, origin_path))
+    }
+
+    /// Adds an item that was just created through the proxy.
+    fn insert_new_item(
+        &mut self,
+        mut inode: Inode,
+        parent_node: NodeIndex,
+    ) -> FuseResult<FileAttr> {
+        if let Some(overlay) = self.overlay.as_mut() {
+            overlay.remove_whiteout(relative_path(&self.proxy_mount, &inode.proxy_path)?);
+            inode.layer = Layer::Upper;
+        }
+
+        Ok(self.inode_list_write().insert(inode, parent_node))
+    }
+
+    fn scan_item(&self, item: &Path) -> FuseResult<()> {
+        match detect(
+            &*self.scanner,
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            item,
+        )? {
+            Some(_) => Err(FuseError::OPERATION_NOT_PERMITTED),
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves a read-only proxy-only file next to the blocked item that explains why it's missing.
+    fn insert_block_stub(
+        &mut self,
+        item: &Path,
+        blocked: &FileAttr,
+        signature: &str,
+        parent_node: NodeIndex,
+    ) {
+        let proxy_path = self.origin_path_to_proxy_path(item);
+        let mut name = proxy_path.file_name().unwrap_or_default().to_os_string();
+        name.push(BLOCK_STUB_SUFFIX);
+
+        let mut inode_list = self.inode_list_write();
+        if inode_list.find_child_by_name(parent_node, &name).is_some() {
+            return;
+        }
+
+        let content = block_stub_content(item, signature).into_bytes();
+        let attr = FileAttrBuilder::new()
+            .with_kind(FileType::RegularFile)
+            .with_perm(0o444)
+            .with_size(content.len() as u64)
+            .with_nlink(1)
+            .with_uid(blocked.uid)
+            .with_gid(blocked.gid)
+            .with_blksize(blocked.blksize)
+            .build();
+
+        // No origin path, so nothing can reach the blocked file through the stub
+        let mut inode = Inode::new(proxy_path.with_file_name(&name), PathBuf::new(), attr);
+        inode.virtual_file = Some(VirtualFile::BlockStub(content));
+
+        info!("Left {:?} block stub", inode.proxy_path);
+        inode_list.insert(inode, parent_node);
+    }
+
+    fn listed_item(&self, item: PathBuf) -> FuseResult<ListedItem> {
+        let proxy_path = self.origin_path_to_proxy_path(&item);
+        let attr = self.stat(&item)?.build();
+
+        // Link targets are scanned on their own
+        let (link_target, mut scan) = if attr.kind == FileType::Symlink {
+            let target = fs::read_link(&item)?;
+            self.ensure_link_contained(&item, &target)?;
+
+            (Some(target), false)
+        } else if !matches!(attr.kind, FileType::RegularFile | FileType::Directory) {
+            // FIFOs, sockets and device nodes have no content of their own, reading a FIFO
+            // would block until something writes to it
+            (None, false)
+        } else if self
+            .exclude
+            .is_match(relative_path(&self.proxy_mount, &proxy_path)?)
+        {
+            debug!("Skipped scanning excluded {item:?}");
+            (None, false)
+        } else {
+            (None, true)
+        };
+
+        let mut scan_state = ScanState::Done;
+        match self.config.scan_mode {
+            ScanMode::Eager => {}
+            ScanMode::Lazy => {
+                if scan && attr.kind == FileType::RegularFile {
+                    scan_state = ScanState::Pending;
+                }
+                scan = false;
+            }
+            ScanMode::NewOnly => scan = false,
+        }
+
+        Ok(ListedItem {
+            origin_path: item,
+            proxy_path,
+            attr,
+            link_target,
+            scan,
+            scan_state,
+        })
+    }
+
+    /// Scans listed items on up to `scan_threads` threads, verdicts are in the order of `items`.
+    fn scan_listed_items(&self, items: &[ListedItem]) -> Vec<FuseResult<Option<String>>> {
+        let scanner = &*self.scanner;
+        let detections = self.detections.as_ref();
+        let poll_waiters = &*self.poll_waiters;
+        let scan = |item: &ListedItem| match item.scan {
+            true => panic::catch_unwind(AssertUnwindSafe(|| {
+                detect(scanner, detections, poll_waiters, &item.origin_path)
+            }))
+            .unwrap_or_else(|_| {
+                error!("Scanning {:?} panicked", item.origin_path);
+                Err(FuseError::IO)
+            }),
+            false => Ok(None),
+        };
+
+        let threads = self.config.scan_threads.min(items.len());
+        if threads <= 1 {
+            return items.iter().map(scan).collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let mut verdicts = thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut verdicts = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(item) = items.get(index) else {
+                                break verdicts;
+                            };
+                            verdicts.push((index, scan(item)));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("scan panics are caught"))
+                .collect::<Vec<_>>()
+        });
+
+        verdicts.sort_unstable_by_key(|(index, _)| *index);
+        verdicts.into_iter().map(|(_, verdict)| verdict).collect()
+    }
+
+    fn insert_item(
+        &mut self,
+        item: ListedItem,
+        signature: Option<String>,
+        parent_node: NodeIndex,
+    ) -> FuseResult<()> {
+        let ListedItem {
+            origin_path: item,
+            proxy_path,
+            attr,
+            link_target,
+            scan_state,
+            ..
+        } = item;
+
+        if let Some(signature) = signature {
+            if self.config.leave_block_stub {
+                self.insert_block_stub(&item, &attr, &signature, parent_node);
+            }
+
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        trace!("Added {:?} item", proxy_path);
+        let layer = match self.overlay.as_ref() {
+            Some(overlay) if item.starts_with(overlay.root()) => Layer::Upper,
+            _ => Layer::Lower,
+        };
+        let mut inode = Inode::new(proxy_path, item, attr);
+        inode.layer = layer;
+        inode.link_target = link_target;
+        inode.scan_state = scan_state;
+
+        let mut inode_list = self.inode_list.write().unwrap();
+        inode_list.insert(inode, parent_node);
+        Ok(())
+    }
+
+    /// Directories that make up the content of a proxy directory, the upper layer goes first.
+    fn layer_dirs(&self, directory: &Inode) -> FuseResult<Vec<PathBuf>> {
+        let Some(overlay) = self.overlay.as_ref() else {
+            return Ok(vec![directory.origin_path.clone()]);
+        };
+
+        let relative = relative_path(&self.proxy_mount, &directory.proxy_path)?;
+
+        Ok(vec![
+            overlay.upper_path(relative),
+            self.origin_mount.path().join(relative),
+        ])
+    }
+
+    /// Syncs children of a proxy directory with its origin: new items are added, items that were
+    /// removed from the origin behind the proxy's back are dropped.
+    pub fn add_folder(&mut self, ino: u64) -> FuseResult<()> {
+        let mut listed_dirs = Vec::new();
+        let (items, parent_node, parent_origin) = {
+            let inode_list = self.inode_list.read().unwrap();
+
+            let (parent_node, parent_inode) =
+                inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            trace!("Adding folder: {:?}...", parent_inode.proxy_path);
+
+            let mut seen = HashSet::new();
+            let mut items = Vec::new();
+            for folder in self.layer_dirs(parent_inode)? {
+                if !self.config.follow_mounts && self.is_nested_mount(&folder) {
+                    debug!("{folder:?} is on another file system, it's left empty");
+                    continue;
+                }
+
+                let entries = match read_dir(&folder) {
+                    Ok(entries) => entries,
+                    Err(err) if self.overlay.is_some() && err.kind() == ErrorKind::NotFound => {
+                        continue
+                    }
+                    Err(err) => {
+                        error!("Failed to read {folder:?} directory: {err}");
+                        return Err(err.into());
+                    }
+                };
+                listed_dirs.push(folder.clone());
+
+                for item in entries {
+                    let item = item.map_err(|err| {
+                        error!("readdir item error: {err}");
+                        FuseError::from(err)
+                    })?;
+
+                    let name = item.file_name();
+                    if name.as_bytes().starts_with(UNLINKED_PREFIX.as_bytes()) {
+                        continue; // removed through the proxy, waits for its last handle
+                    }
+                    if !seen.insert(name.clone()) {
+                        continue; // shadowed by the upper layer
+                    }
+
+                    if let Some(overlay) = self.overlay.as_ref() {
+                        let relative = relative_path(&self.proxy_mount, &parent_inode.proxy_path)?;
+                        if overlay.is_whiteout(relative.join(&name)) {
+                            continue;
+                        }
+                    }
+
+                    if inode_list
+                        .childs(parent_node)
+                        .any(|child| child.proxy_path.file_name() == Some(&name))
+                    {
+                        continue;
+                    }
+
+                    items.push(item);
+                }
+            }
+
+            (items, parent_node, parent_inode.origin_path.clone())
+        };
+
+        if let Some(watcher) = self.watcher.as_mut() {
+            for dir in &listed_dirs {
+                watcher.watch(dir);
+            }
+        }
+
+        self.drop_stale_items(parent_node);
+
+        // Subdirectories created or removed on the origin behind the proxy's back
+        if self.overlay.is_none() {
+            if let Ok(attr) = self.stat(&parent_origin) {
+                if let Some(parent) = self.inode_list_write().list.node_weight_mut(parent_node) {
+                    parent.attr.nlink = attr.build().nlink;
+                }
+            }
+        }
+
+        let mut listed = Vec::with_capacity(items.len());
+        for item in items {
+            let path = item.path();
+            match self.listed_item(path.clone()) {
+                Ok(item) => listed.push(item),
+                // Links that escape the origin or go in circles are left out of the listing
+                Err(err)
+                    if err == FuseError::PERMISSION_DENIED
+                        || err == FuseError::TOO_MANY_SYMLINKS =>
+                {
+                    warn!("Skipped {path:?}: {err}");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let items = listed;
+        let verdicts = self.scan_listed_items(&items);
+
+        for (item, verdict) in items.into_iter().zip(verdicts) {
+            let path = item.origin_path.clone();
+            match verdict.and_then(|signature| self.insert_item(item, signature, parent_node)) {
+                Ok(()) => {}
+                Err(err)
+                    if err == FuseError::OPERATION_NOT_PERMITTED
+                        || err == FuseError::PERMISSION_DENIED =>
+                {
+                    warn!("Skipped {path:?}: {err}");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.config.max_inodes > 0 {
+            let evicted = self
+                .inode_list_write()
+                .evict(self.config.max_inodes, parent_node);
+            if evicted > 0 {
+                debug!("Evicted {evicted} least recently used items");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a directory is on a file system mounted inside the origin rather than on the origin.
+    fn is_nested_mount(&self, dir: &Path) -> bool {
+        fs::metadata(dir).is_ok_and(|meta| !self.source_devs.contains(&meta.dev()))
+    }
+
+    pub fn open_dir(&mut self, ino: u64) -> FuseResult<u64> {
+        let kind = self
+            .inode_list()
+            .find_by_id(ino)
+            .ok_or(FuseError::NO_EXIST)?
+            .1
+            .attr
+            .kind;
+        if kind != FileType::Directory {
+            return Err(FuseError::NOT_DIRECTORY);
+        }
+
+        Ok(self.dir_handles.insert(ino))
+    }
+
+    pub fn release_dir(&mut self, fh: u64) -> FuseResult<()> {
+        self.dir_handles
+            .remove(fh)
+            .map(|_| ())
+            .ok_or(FuseError::BAD_FD)
+    }
+
+    /// Entries of an open directory that come after `offset`.
+    ///
+    /// Reading from the start syncs the directory with the origin and takes a new listing, later
+    /// batches are served from it, so they stay consistent while the directory changes.
+    pub fn dir_entries(&mut self, ino: u64, fh: u64, offset: i64) -> FuseResult<&[DirEntry]> {
+        let listed = self
+            .dir_handles
+            .get_mut(fh)
+            .filter(|handle| handle.ino == ino)
+            .ok_or(FuseError::BAD_FD)?
+            .entries
+            .is_some();
+
+        if offset == 0 || !listed {
+            self.add_folder(ino)?;
+            let entries = self.list_dir(ino)?;
+            self.dir_handles.get_mut(fh).unwrap().entries = Some(entries);
+        }
+
+        let entries = self.dir_handles.get_mut(fh).unwrap().entries.as_deref();
+        let entries = entries.unwrap_or_default();
+        let start = entries.partition_point(|entry| entry.offset <= offset);
+
+        Ok(&entries[start..])
+    }
+
+    /// Current entries of a directory with `.` and `..` first.
+    fn list_dir(&self, ino: u64) -> FuseResult<Vec<DirEntry>> {
+        let inode_list = self.inode_list();
+        let (node_index, _) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+        // `..` of the root leads out of the proxy, the kernel fills in its ino
+        let parent_ino = inode_list
+            .parent(node_index)
+            .map_or(ino, |parent_node| inode_list.list[parent_node].attr.ino);
+        let dot_entries = [(ino, "."), (parent_ino, "..")];
+        let dot_count = dot_entries.len() as u64;
+
+        let dots = dot_entries
+            .into_iter()
+            .zip(1..)
+            .map(|((ino, name), offset)| DirEntry {
+                ino,
+                offset,
+                kind: FileType::Directory,
+                name: name.into(),
+            });
+        // Items come after `.` and `..`, their offsets are shifted past them
+        let items = inode_list
+            .entries_after(node_index, 0)
+            .into_iter()
+            .map(|(offset, child)| {
+                trace!("Listing {:?}", child.proxy_path);
+                DirEntry {
+                    ino: child.attr.ino,
+                    offset: (offset + dot_count) as i64,
+                    kind: child.attr.kind,
+                    name: child.proxy_path.file_name().unwrap_or_default().into(),
+                }
+            });
+
+        Ok(dots.chain(items).collect())
+    }
+
+    /// Finds an item of a directory by name and counts the reference the kernel gets to it.
+    ///
+    /// With `--max-inodes` an item that isn't known could have been evicted, so the directory is
+    /// listed again before giving up.
+    pub fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> FuseResult<(FileAttr, u64)> {
+        let attr = match self.find_entry(parent, name) {
+            Err(err) if err == FuseError::NO_EXIST && self.config.max_inodes > 0 => {
+                self.add_folder(parent)?;
+                self.find_entry(parent, name)
+            }
+            found => found,
+        }?;
+
+        let generation = self.remember_entry(attr.ino);
+        Ok((attr, generation))
+    }
+
+    fn find_entry(&self, parent: u64, name: &OsStr) -> FuseResult<FileAttr> {
+        let read_view = self.inode_list();
+
+        let (parent_node, _) = read_view.find_by_id(parent).ok_or(FuseError::NO_EXIST)?;
+        let (_, inode) = read_view
+            .find_child_by_name(parent_node, name)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        // Names of a hard linked file share the ino, the one found by it holds actual attributes
+        Ok(read_view
+            .find_by_id(inode.attr.ino)
+            .map_or(inode.attr, |(_, inode)| inode.attr))
+    }
+
+    /// Counts a reference the kernel got to an item with an entry reply, returns the generation
+    /// the reply carries.
+    pub fn remember_entry(&self, ino: u64) -> u64 {
+        let mut inode_list = self.inode_list_write();
+        inode_list.remember(ino);
+        inode_list.generation(ino)
+    }
+
+    /// Syncs directories that changed on the origin since the last call, see `--watch`.
+    pub fn sync_watched(&mut self) {
+        let Some(watcher) = self.watcher.as_ref() else {
+            return;
+        };
+
+        for dir in watcher.take_changed() {
+            let relative = self
+                .overlay
+                .as_ref()
+                .and_then(|overlay| dir.strip_prefix(overlay.root()).ok())
+                .or_else(|| dir.strip_prefix(self.origin_mount.path()).ok());
+            let Some(proxy_path) = relative.map(|relative| self.proxy_mount.join(relative)) else {
+                continue;
+            };
+
+            let ino = self
+                .inode_list()
+                .list
+                .node_weights()
+                .find(|inode| {
+                    inode.attr.kind == FileType::Directory && inode.proxy_path == proxy_path
+                })
+                .map(|inode| inode.attr.ino);
+
+            // Directories that were removed or renamed since are synced through their parents
+            if let Some(ino) = ino {
+                if let Err(err) = self.add_folder(ino) {
+                    warn!("Failed to sync {proxy_path:?} with the origin: {err}");
+                }
+            }
+        }
+    }
+
+    fn drop_stale_items(&mut self, parent_node: NodeIndex) {
+        let mut inode_list = self.inode_list_write();
+
+        let stale: Vec<_> = inode_list
+            .list
+            .neighbors(parent_node)
+            .filter(|&node_index| {
+                let child = &inode_list.list[node_index];
+
+                // Proxy-only and still open items stay
+                child.virtual_file.is_none()
+                    && child.open_handles == 0
+                    && fs::symlink_metadata(&child.origin_path)
+                        .is_err_and(|err| err.kind() == ErrorKind::NotFound)
+            })
+            .collect();
+
+        for node_index in stale {
+            debug!(
+                "{:?} was removed from the origin",
+                inode_list.list[node_index].proxy_path
+            );
+            inode_list.remove_tree(node_index);
+        }
+    }
+
+    /// Scans a file about to be executed even if it was trusted without a scan, e.g. because of
+    /// `--scan-new-only` or an exclude pattern, see `--allow-exec-scan`.
+    pub fn scan_before_exec(&mut self, ino: u64) -> FuseResult<()> {
+        if !self.config.allow_exec_scan {
+            return Ok(());
+        }
+
+        let origin_path = {
+            let read_view = self.inode_list.read().unwrap();
+            let (_, inode) = read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            if inode.virtual_file.is_some() || inode.attr.kind != FileType::RegularFile {
+                return Ok(());
+            }
+
+            inode.origin_path.clone()
+        };
+
+        // Verdicts of unchanged files come from the scan cache, so repeated runs stay cheap.
+        // A file that can't be scanned isn't run either.
+        let verdict = detect(
+            &*self.scanner,
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            &origin_path,
+        )
+        .map_err(|_| FuseError::PERMISSION_DENIED)?;
+        let Some(signature) = verdict else {
+            return Ok(());
+        };
+
+        let mut write_view = self.inode_list.write().unwrap();
+        if let Some((_, inode)) = write_view.find_by_id_mut(ino) {
+            warn!("Execution of {:?} is denied", inode.proxy_path);
+            inode.scan_state = ScanState::Infected(signature);
+        }
+
+        Err(FuseError::PERMISSION_DENIED)
+    }
+
+    /// Scans an item that is still pending, `None` if it doesn't need a scan.
+    ///
+    /// The inode list isn't locked meanwhile, a scan may wait for its turn under `--scan-rate`.
+    fn scan_pending(&self, ino: u64) -> FuseResult<Option<Option<String>>> {
+        let origin_path = {
+            let read_view = self.inode_list.read().unwrap();
+            match read_view.find_by_id(ino) {
+                Some((_, inode))
+                    if inode.scan_state == ScanState::Pending
+                        && inode.virtual_file.is_none()
+                        && inode.attr.kind == FileType::RegularFile =>
+                {
+                    inode.origin_path.clone()
+                }
+                _ => return Ok(None),
+            }
+        };
+
+        detect(
+            &*self.scanner,
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            &origin_path,
+        )
+        .map(Some)
+    }
+
+    /// Opens an item with `open(2)` flags for a caller, files opened for execution are scanned
+    /// first. Without default_permissions the kernel leaves permission checks to the file system.
+    pub fn open_fh(&mut self, ino: u64, flags: i32, uid: u32, gid: u32) -> FuseResult<u64> {
+        let (mask, read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (libc::R_OK, true, false),
+            libc::O_WRONLY => (libc::W_OK, false, true),
+            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            _ => return Err(FuseError::INVALID_ARGUMENT),
+        };
+
+        if write {
+            self.ensure_writable()?;
+        }
+        self.check_access(ino, uid, gid, mask)?;
+        if flags & FMODE_EXEC != 0 {
+            self.scan_before_exec(ino)?;
+        }
+
+        let fh = self.allocate_fh(ino, read, write)?;
+
+        // Only once the file is open for writing, a read-only open never truncates anything
+        if write && flags & libc::O_TRUNC != 0 {
+            if let Err(err) = self.truncate(ino, 0) {
+                let _ = self.release_fh(ino, fh);
+                error!("Failed to truncate {ino} inode on open: {err}");
+                return Err(err);
+            }
+        }
+
+        Ok(fh)
+    }
+
+    /// Opens a file for a new handle.
+    ///
+    /// Only regular files can be opened. FIFOs, sockets and device nodes fail with `ENOTSUP`:
+    /// opening a FIFO blocks until the other end shows up and requests are served one at a time,
+    /// so a single open would hang the whole proxy.
+    pub fn allocate_fh(&mut self, ino: u64, read: bool, write: bool) -> FuseResult<u64> {
+        let verdict = self.scan_pending(ino)?;
+
+        let file = {
+            let mut write_view = self.inode_list.write().unwrap();
+            let (_, inode) = write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST)?;
+
+            if let Some(virtual_file) = inode.virtual_file.as_ref() {
+                if write && *virtual_file != VirtualFile::Control {
+                    return Err(FuseError::PERMISSION_DENIED);
+                }
+
+                // Served from memory, there is no origin file to open
+                return Ok(0);
+            }
+
+            if inode.attr.kind != FileType::RegularFile {
+                debug!(
+                    "{:?} is a {:?}, it can't be opened through the proxy",
+                    inode.proxy_path, inode.attr.kind
+                );
+                return Err(FuseError::NOT_SUPPORTED);
+            }
+
+            match &inode.scan_state {
+                ScanState::Done => {}
+                ScanState::Pending => match verdict {
+                    Some(Some(signature)) => {
+                        inode.scan_state = ScanState::Infected(signature);
+                        return Err(FuseError::OPERATION_NOT_PERMITTED);
+                    }
+                    Some(None) => inode.scan_state = ScanState::Done,
+                    // A pending item took the ino after the scan, it's scanned from scratch
+                    None => {
+                        drop(write_view);
+                        return self.allocate_fh(ino, read, write);
+                    }
+                },
+                ScanState::Infected(signature) => {
+                    warn!("{:?} is blocked, {signature} detected", inode.proxy_path);
+                    return Err(FuseError::OPERATION_NOT_PERMITTED);
+                }
+            }
+
+            // An open file can be changing through the proxy, it was verified on the first open
+            if self.config.verify_checksums && inode.open_handles == 0 {
+                verify_checksum(inode, write)?;
+            }
+
+            if write {
+                self.copy_up(inode)?;
+            }
+
+            let file = match open_origin(&inode.origin_path, read, write) {
+                Ok(file) => file,
+                Err(err) => {
+                    error!("Failed to open {:?}: {err}", inode.origin_path);
+                    return Err(err.into());
+                }
+            };
+            inode.open_handles += 1;
+
+            file
+        };
+
+        Ok(self.handles.insert(FileHandle::new(ino, file, read, write)))
+    }
+
+    /// Open handle of the item with `ino` ino.
+    pub fn file_handle(&self, ino: u64, fh: u64) -> FuseResult<&FileHandle> {
+        match self.handles.get(fh) {
+            Some(handle) if handle.ino == ino => Ok(handle),
+            _ => {
+                error!("{fh} fh isn't open for {ino} ino");
+                Err(FuseError::BAD_FD)
+            }
+        }
+    }
+
+    /// Reopens the origin file of a handle that was closed to stay under `--max-open-files`,
+    /// handles in `pinned` are kept open.
+    fn activate_fh(&mut self, ino: u64, fh: u64, pinned: &[u64]) -> FuseResult<()> {
+        self.file_handle(ino, fh)?;
+
+        let inode_list = self.inode_list.read().unwrap();
+        let result = self.handles.activate(fh, pinned, |handle| {
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(ErrorKind::NotFound)?;
+            trace!("Reopening {:?} for {fh} fh", inode.origin_path);
+
+            open_origin(&inode.origin_path, handle.read, handle.write)
+        });
+
+        result.map_err(|err| {
+            error!("Failed to reopen {fh} fh of {ino} ino: {err}");
+            FuseError::from(err)
+        })
+    }
+
+    /// Reopens the origin file of a handle by its path once the open file reports `ESTALE`, e.g.
+    /// it was replaced on a network origin. The new file is scanned before it's used.
+    fn refresh_stale_fh(&mut self, ino: u64, fh: u64) -> FuseResult<()> {
+        match self.file_handle(ino, fh)?.file()?.metadata() {
+            Err(err) if err.raw_os_error() == Some(libc::ESTALE) => {}
+            _ => return Ok(()),
+        }
+
+        let origin_path = {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            inode.origin_path.clone()
+        };
+        warn!("{fh} fh of {origin_path:?} is stale, reopening it");
+
+        if let Some(signature) = detect(
+            &*self.scanner,
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            &origin_path,
+        )? {
+            warn!("Replacement of {origin_path:?} is blocked, {signature} detected");
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        let handle = self.handles.get_mut(fh).ok_or(FuseError::BAD_FD)?;
+        let file = open_origin(&origin_path, handle.read, handle.write).map_err(|err| {
+            error!("Failed to reopen stale {origin_path:?}: {err}");
+            FuseError::STALE_HANDLE
+        })?;
+        handle.replace_file(file)?;
+
+        Ok(())
+    }
+
+    pub fn open_file(&mut self, ino: u64, fh: u64, read: bool, write: bool) -> FuseResult<&File> {
+        self.activate_fh(ino, fh, &[])?;
+        self.refresh_stale_fh(ino, fh)?;
+        let handle = self.file_handle(ino, fh)?;
+
+        if read && !handle.read {
+            error!("Read is not allowed!");
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        if write && !handle.write {
+            error!("Write is not allowed!");
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        Ok(handle.file()?)
+    }
+
+    /// Reads up to `size` bytes at `offset`, fewer only at the end of the file.
+    pub fn read_file(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        size: usize,
+    ) -> FuseResult<Vec<u8>> {
+        // Reads have to see data that is still buffered, by this handle or another one
+        self.flush_buffers(ino)?;
+
+        {
+            let read_view = self.inode_list.read().unwrap();
+            let (_, inode) = read_view.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+            if let Some(virtual_file) = inode.virtual_file.as_ref() {
+                let content = self.virtual_content(virtual_file);
+                let start = usize::try_from(offset)
+                    .map_or(content.len(), |offset| offset.min(content.len()));
+                let end = usize::min(start.saturating_add(size), content.len());
+
+                return Ok(content[start..end].to_vec());
+            }
+        }
+
+        let chunk_size = self.max_read_chunk();
+        let file = self.open_file(ino, fh, true, false)?;
+
+        // Cached size can lag behind the origin, so read until EOF instead of relying on it. The
+        // buffer grows a chunk at a time, so a short file never gets the whole size allocated
+        let mut buf = Vec::new();
+        let mut read = 0;
+        while read < size {
+            if read == buf.len() {
+                buf.resize(size.min(read + chunk_size), 0);
+            }
+
+            match file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(amount) => read += amount,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Failed to read {size} bytes from {fh} fh of {ino} ino: {err}");
+                    return Err(err.into());
+                }
+            }
+        }
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    /// Returns the amount of written bytes, a short write means the origin device is full.
+    pub fn write_fh(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> FuseResult<usize> {
+        self.open_file(ino, fh, false, true)?;
+
+        let capacity = self.config.write_buffer;
+        let handle = self.handles.get_mut(fh).ok_or(FuseError::BAD_FD)?;
+        let written = handle.write_at(data, offset, capacity)?;
+        handle.written = true;
+
+        if written < data.len() {
+            warn!(
+                "Short write to {fh} fh of {ino} ino: {written} of {} bytes",
+                data.len()
+            );
+        }
+
+        Ok(written)
+    }
+
+    /// Writes through the handle and updates the cached size and times, returns the amount of
+    /// written bytes.
+    pub fn write_file(&mut self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> FuseResult<usize> {
+        self.ensure_writable()?;
+        let written = self.write_fh(ino, fh, offset, data)?;
+
+        let mut write_view = self.inode_list.write().unwrap();
+        let (_, inode) = write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST)?;
+
+        // Only what went through counts, the device may have filled up midway. A write past EOF
+        // leaves a hole the origin reads back as zeros, one inside the file never shrinks it
+        let attr = &mut inode.attr;
+        attr.size = attr.size.max(offset + written as u64);
+
+        let time_now = SystemTime::now();
+        attr.ctime = time_now;
+        attr.mtime = time_now;
+
+        Ok(written)
+    }
+
+    /// Copies `len` bytes between two open files without passing them through the proxy, returns
+    /// the amount of copied bytes which is less than `len` only at the end of the source.
+    pub fn copy_range(
+        &mut self,
+        (ino_in, fh_in, offset_in): (u64, u64, u64),
+        (ino_out, fh_out, offset_out): (u64, u64, u64),
+        len: u64,
+    ) -> FuseResult<u64> {
+        self.flush_fh(ino_in, fh_in)?;
+        self.flush_fh(ino_out, fh_out)?;
+
+        // Opening one of the files must not close the other one
+        self.activate_fh(ino_in, fh_in, &[fh_out])?;
+        self.activate_fh(ino_out, fh_out, &[fh_in])?;
+        let fd_in = self.open_file(ino_in, fh_in, true, false)?.as_raw_fd();
+        let fd_out = self.open_file(ino_out, fh_out, false, true)?.as_raw_fd();
+
+        let mut offset_in = i64::try_from(offset_in).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+        let mut offset_out = i64::try_from(offset_out).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+        let mut copied = 0;
+
+        // The kernel may copy less than asked for in a single call
+        while copied < len {
+            let chunk = usize::try_from(len - copied).unwrap_or(usize::MAX);
+            let result = unsafe {
+                libc::copy_file_range(fd_in, &mut offset_in, fd_out, &mut offset_out, chunk, 0)
+            };
+
+            match result {
+                0 => break,
+                -1 => {
+                    let err = FuseError::last();
+                    error!("Failed to copy range from {ino_in} to {ino_out} inode: {err}");
+                    return Err(err);
+                }
+                amount => copied += amount as u64,
+            }
+        }
+
+        if let Some(handle) = self.handles.get_mut(fh_out) {
+            handle.written = true;
+        }
+
+        if let Some((_, inode)) = self.inode_list_write().find_by_id_mut(ino_out) {
+            let time_now = SystemTime::now();
+            inode.attr.size = inode.attr.size.max(offset_out as u64);
+            inode.attr.ctime = time_now;
+            inode.attr.mtime = time_now;
+        }
+
+        Ok(copied)
+    }
+
+    /// Repositions the origin file of a handle, `SEEK_DATA` and `SEEK_HOLE` find data and holes
+    /// of sparse files.
+    pub fn seek(&mut self, ino: u64, fh: u64, offset: i64, whence: i32) -> FuseResult<i64> {
+        if !matches!(
+            whence,
+            libc::SEEK_SET | libc::SEEK_CUR | libc::SEEK_END | libc::SEEK_DATA | libc::SEEK_HOLE
+        ) {
+            return Err(FuseError::INVALID_ARGUMENT);
+        }
+
+        let virtual_len = {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            inode
+                .virtual_file
+                .as_ref()
+                .map(|virtual_file| self.virtual_content(virtual_file).len() as i64)
+        };
+
+        // Content of virtual files has no holes
+        if let Some(len) = virtual_len {
+            return match whence {
+                libc::SEEK_DATA if offset < len => Ok(offset),
+                libc::SEEK_HOLE if offset < len => Ok(len),
+                libc::SEEK_DATA | libc::SEEK_HOLE => Err(FuseError::NO_SUCH_DEVICE_OR_ADDRESS),
+                libc::SEEK_END => Ok(len + offset),
+                _ => Ok(offset),
+            };
+        }
+
+        // Holes may be filled by buffered data
+        self.flush_fh(ino, fh)?;
+
+        let fd = self.open_file(ino, fh, false, false)?.as_raw_fd();
+        let position = unsafe { libc::lseek(fd, offset, whence) };
+        if position == -1 {
+            return Err(FuseError::last());
+        }
+
+        Ok(position)
+    }
+
+    /// Device block of a file block for bmap. Origin blocks aren't exposed, the proxy isn't backed
+    /// by a block device of its own.
+    pub fn block_map(&self, ino: u64, blocksize: u32, idx: u64) -> FuseResult<u64> {
+        debug!("bmap of {idx} block with {blocksize} size of {ino} inode isn't supported");
+        Err(FuseError::NOT_SUPPORTED)
+    }
+
+    /// Manipulates allocated space of an open file, `mode` takes `FALLOC_FL_*` flags.
+    pub fn allocate(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> FuseResult<()> {
+        self.flush_fh(ino, fh)?;
+
+        let fd = self.open_file(ino, fh, false, true)?.as_raw_fd();
+        if unsafe { libc::fallocate(fd, mode, offset, length) } != 0 {
+            let err = FuseError::last();
+            error!("Failed to fallocate {length} bytes at {offset} of {ino} inode: {err}");
+            return Err(err);
+        }
+
+        // Plain preallocation keeps the content, everything else changes it
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            if let Some(handle) = self.handles.get_mut(fh) {
+                handle.written = true;
+            }
+        }
+
+        if let Some((_, inode)) = self.inode_list_write().find_by_id_mut(ino) {
+            if mode & libc::FALLOC_FL_KEEP_SIZE == 0 {
+                let end = u64::try_from(offset.saturating_add(length)).unwrap_or_default();
+                inode.attr.size = inode.attr.size.max(end);
+            }
+
+            let time_now = SystemTime::now();
+            inode.attr.ctime = time_now;
+            inode.attr.mtime = time_now;
+        }
+
+        Ok(())
+    }
+
+    /// Writes data buffered in the handle to the origin.
+    pub fn flush_fh(&mut self, ino: u64, fh: u64) -> FuseResult<()> {
+        match self.handles.get_mut(fh) {
+            Some(handle) if handle.ino == ino => Ok(handle.flush_buffer()?),
+            _ => Ok(()),
+        }
+    }
+
+    /// Syncs the origin directory of a proxy directory, so items created in it are durable.
+    pub fn sync_dir(&self, ino: u64, datasync: bool) -> FuseResult<()> {
+        let origin_path = {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+            inode.origin_path.clone()
+        };
+
+        let dir = File::open(&origin_path).map_err(|err| {
+            error!("Failed to open {origin_path:?} directory: {err}");
+            FuseError::from(err)
+        })?;
+        let result = match datasync {
+            true => dir.sync_data(),
+            false => dir.sync_all(),
+        };
+        if let Err(err) = result {
+            error!("Failed to sync {origin_path:?} directory: {err}");
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes data buffered by every handle of the item, so it's seen through all of them.
+    pub fn flush_buffers(&mut self, ino: u64) -> FuseResult<()> {
+        if let Err(err) = self.handles.flush_ino(ino) {
+            error!("Failed to write buffered data of {ino} ino: {err}");
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes buffered data of the handle and scans content written through it, infected content
+    /// is discarded so it never stays on the origin.
+    ///
+    /// A failed flush doesn't skip the scan, part of the buffer may have reached the origin.
+    pub fn scan_written(&mut self, ino: u64, fh: u64) -> FuseResult<()> {
+        if self.scan_buffered(ino, fh)? {
+            return Ok(());
+        }
+
+        let flushed = self.flush_fh(ino, fh);
+
+        match self.handles.get_mut(fh) {
+            Some(handle) if handle.ino == ino && handle.written => handle.written = false,
+            _ => return flushed,
+        }
+
+        let origin_path = {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+            inode.origin_path.clone()
+        };
+
+        let Some(signature) = detect(
+            &*self.scanner,
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            &origin_path,
+        )?
+        else {
+            return flushed;
+        };
+        warn!("Discarding {signature} infected content written to {origin_path:?}");
+
+        let file = self.open_file(ino, fh, false, true)?;
+        if let Err(err) = file.set_len(0) {
+            error!("Failed to discard content of {origin_path:?}: {err}");
+            return Err(err.into());
+        }
+
+        if let Some((_, inode)) = self.inode_list_write().find_by_id_mut(ino) {
+            inode.attr.size = 0;
+            inode.attr.mtime = SystemTime::now();
+        }
+
+        Err(FuseError::OPERATION_NOT_PERMITTED)
+    }
+
+    /// Scans written data from the buffer of the handle before it's written, if the buffer holds
+    /// the whole content of the file. Infected content never reaches the origin that way.
+    ///
+    /// Returns whether the buffer was scanned, otherwise the file has to be scanned once it's
+    /// flushed.
+    fn scan_buffered(&mut self, ino: u64, fh: u64) -> FuseResult<bool> {
+        match self.file_handle(ino, fh) {
+            Ok(handle) if handle.written && handle.has_buffered() => {}
+            _ => return Ok(false),
+        }
+        self.activate_fh(ino, fh, &[])?;
+
+        let origin_path = {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+            inode.origin_path.clone()
+        };
+
+        let handle = self.handles.get_mut(fh).ok_or(FuseError::BAD_FD)?;
+        let Some(content) = handle.buffered_content()? else {
+            return Ok(false);
+        };
+        let verdict = report_verdict(
+            self.detections.as_ref(),
+            &self.poll_waiters,
+            &origin_path,
+            self.scanner.scan_bytes(content),
+        )?;
+        handle.written = false;
+
+        let Some(signature) = verdict else {
+            handle.flush_buffer()?;
+            return Ok(true);
+        };
+        warn!("Discarding {signature} infected content before it's written to {origin_path:?}");
+        handle.discard_buffer();
+
+        // The origin may hold older content the buffer was going to overwrite
+        if let Err(err) = handle.file()?.set_len(0) {
+            error!("Failed to discard content of {origin_path:?}: {err}");
+            return Err(err.into());
+        }
+
+        if let Some((_, inode)) = self.inode_list_write().find_by_id_mut(ino) {
+            inode.attr.size = 0;
+            inode.attr.mtime = SystemTime::now();
+        }
+
+        Err(FuseError::OPERATION_NOT_PERMITTED)
+    }
+
+    /// Writes back and scans what was written through the handle on `close()`, so the writer
+    /// learns about write errors and infected content. The file itself is closed in
+    /// [`Rfs::release_fh`], and it isn't synced, that's what `fsync()` is for.
+    pub fn flush_file(&mut self, ino: u64, fh: u64) -> FuseResult<()> {
+        self.scan_written(ino, fh)?;
+
+        {
+            let inode_list = self.inode_list();
+            let (_, inode) = inode_list.find_by_id(ino).ok_or(FuseError::NO_EXIST)?;
+
+            // Files that live only in the proxy have nothing to write back
+            if inode.virtual_file.is_some() {
+                return Ok(());
+            }
+        }
+
+        if !self.file_handle(ino, fh)?.write {
+            return Ok(());
+        }
+
+        let mut file = self.open_file(ino, fh, false, true)?;
+        if let Err(err) = file.flush() {
+            error!("Failed to flush {fh} fh of {ino} ino: {err}");
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Closes the handle, files that live only in the proxy have none.
+    ///
+    /// Buffered data is expected to be written and scanned by [`Rfs::scan_written`] before,
+    /// whatever is still left failed to be written and is dropped rather than written unscanned.
+    pub fn release_fh(&mut self, ino: u64, fh: u64) -> FuseResult<()> {
+        let mut write_view = self.inode_list.write().unwrap();
+        let inode = write_view.find_by_id_mut(ino);
+
+        if inode
+            .as_ref()
+            .is_some_and(|(_, inode)| inode.virtual_file.is_some())
+        {
+            return Ok(());
+        }
+
+        self.file_handle(ino, fh)?;
+        let handle = self.handles.remove(fh).ok_or(FuseError::BAD_FD)?;
+        // The item may be gone already, e.g. dropped by a rescan
+        let mut last_unlinked = None;
+        if let Some((node_index, inode)) = inode {
+            inode.open_handles = inode.open_handles.saturating_sub(1);
+            if inode.unlinked && inode.open_handles == 0 {
+                last_unlinked = Some(node_index);
+            }
+        }
+
+        if let Some(inode) = last_unlinked.and_then(|node_index| write_view.remove(node_index)) {
+            self.delete_unlinked(&inode);
+        }
+
+        if handle.has_buffered() {
+            warn!("Dropping data of {ino} inode that failed to be written before release");
+        }
+
+        Ok(())
+    }
+
+    /// Changes permission bits of an item, only its owner and root are allowed to do that.
+    pub fn chmod(&self, inode: &mut Inode, caller_uid: u32, mode: u32) -> FuseResult<()> {
+        self.ensure_writable()?;
+        ensure_origin(inode)?;
+
+        if caller_uid != 0 && caller_uid != inode.attr.uid {
+            error!(
+                "{caller_uid} uid isn't allowed to change mode of {:?}",
+                inode.proxy_path
+            );
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        self.copy_up(inode)?;
+
+        let mode = mode & PERMISSION_BITS;
+
+        let mut permissions = fs::metadata(&inode.origin_path)?.permissions();
+        permissions.set_mode((permissions.mode() & !PERMISSION_BITS) | mode);
+
+        if let Err(err) = fs::set_permissions(&inode.origin_path, permissions) {
+            error!("Failed to chmod {:?}: {err}", inode.origin_path);
+            return Err(err.into());
+        }
+
+        inode.attr.perm = (inode.attr.perm & !(PERMISSION_BITS as u16)) | mode as u16;
+        inode.attr.ctime = SystemTime::now();
+
+        Ok(())
+    }
+
+    /// Changes ownership of an item, only root is allowed to do that.
+    pub fn chown(
+        &self,
+        inode: &mut Inode,
+        caller_uid: u32,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> FuseResult<()> {
+        self.ensure_writable()?;
+        ensure_origin(inode)?;
+
+        let uid = uid.filter(|&uid| uid != inode.attr.uid);
+        let gid = gid.filter(|&gid| gid != inode.attr.gid);
+
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+
+        if caller_uid != 0 {
+            error!(
+                "{caller_uid} uid isn't allowed to change ownership of {:?}",
+                inode.proxy_path
+            );
+            return Err(FuseError::OPERATION_NOT_PERMITTED);
+        }
+
+        self.copy_up(inode)?;
+
+        let origin_uid = uid.map(|uid| self.config.uid_map.to_origin(uid));
+        let origin_gid = gid.map(|gid| self.config.gid_map.to_origin(gid));
+        if let Err(err) = lchown(&inode.origin_path, origin_uid, origin_gid) {
+            error!("Failed to chown {:?}: {err}", inode.origin_path);
+            return Err(err.into());
+        }
+
+        if let Some(uid) = uid {
+            inode.attr.uid = uid;
+        }
+
+        if let Some(gid) = gid {
+            inode.attr.gid = gid;
+        }
+        inode.attr.ctime = SystemTime::now();
+
+        Ok(())
+    }
+
+    /// Changes the size of a file. Data buffered by its handles is written first, otherwise a
+    /// later flush would bring back what was truncated.
+    pub fn truncate(&mut self, ino: u64, size: u64) -> FuseResult<()> {
+        self.ensure_writable()?;
+        self.flush_buffers(ino)?;
+
+        let mut write_view = self.inode_list.write().unwrap();
+        let (_, inode) = write_view.find_by_id_mut(ino).ok_or(FuseError::NO_EXIST)?;
+
+        // Opening the control file with O_TRUNC is fine, there is nothing to truncate
+        if inode.virtual_file == Some(VirtualFile::Control) {
+            return Ok(());
+        }
+
+        ensure_origin(inode)?;
+        self.copy_up(inode)?;
+        inode.checksum = None;
+
+        let file = File::options().write(true).open(&inode.origin_path)?;
+        if let Err(err) = file.set_len(size) {
+            error!("Failed to truncate {:?}: {err}", inode.origin_path);
+            return Err(err.into());
+        }
+
+        let time_now = SystemTime::now();
+        inode.attr.size = size;
+        inode.attr.ctime = time_now;
+        inode.attr.mtime = time_now;
+
+        Ok(())
+    }
+
+    /// Moves a lower layer item into the overlay upper layer, so it can be modified without
+    /// touching the origin. Does nothing without an overlay or for items that are already there.
+    fn copy_up(&self, inode: &mut Inode) -> FuseResult<()> {
+        let Some(overlay) = self.overlay.as_ref() else {
+            return Ok(());
+        };
+
+        if inode.layer == Layer::Upper {
+            return Ok(());
+        }
+
+        let upper_path = overlay.upper_path(relative_path(&self.proxy_mount, &inode.proxy_path)?);
+        overlay.prepare_parent(&upper_path).map_err(|err| {
+            error!("Failed to prepare upper layer for {upper_path:?}: {err}");
+            FuseError::IO
+        })?;
+
+        match inode.attr.kind {
+            FileType::RegularFile => {
+                if let Err(err) = fs::copy(&inode.origin_path, &upper_path) {
+                    error!("Failed to copy up {:?}: {err}", inode.origin_path);
+                    return Err(FuseError::IO);
+                }
+
+                if let Err(err) = self.scan_item(&upper_path) {
+                    let _ = fs::remove_file(&upper_path);
+                    return Err(err);
+                }
+            }
+            FileType::Directory => {
+                let permissions = fs::metadata(&inode.origin_path)?.permissions();
+                fs::create_dir_all(&upper_path)?;
+                fs::set_permissions(&upper_path, permissions)?;
+            }
+            FileType::Symlink => {
+                let target = inode
+                    .link_target
+                    .as_ref()
+                    .ok_or(FuseError::INVALID_ARGUMENT)?;
+                symlink(target, &upper_path)?;
+            }
+            other => {
+                error!("Copy up is not implemented for {other:?}");
+                return Err(FuseError::NOT_IMPLEMENTED);
+            }
+        }
+
+        debug!("Copied up {:?} to {upper_path:?}", inode.origin_path);
+        inode.origin_path = upper_path;
+        inode.layer = Layer::Upper;
+
+        Ok(())
+    }
+
+    /// Location where an item with the given proxy path is created, the upper layer when there is
+    /// an overlay.
+    fn proxy_path_to_origin_path<P: AsRef<Path>>(&self, item: P) -> FuseResult<PathBuf> {
+        let relative = relative_path(&self.proxy_mount, item.as_ref())?;
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            error!("{relative:?} escapes the origin");
+            return Err(FuseError::PERMISSION_DENIED);
+        }
+
+        Ok(match self.overlay.as_ref() {
+            Some(overlay) => overlay.upper_path(relative),
+            None => self.origin_mount.path().join(relative),
+        })
+    }
+
+    fn origin_path_to_proxy_path<P: AsRef<Path>>(&self, item: P) -> PathBuf {
+        let item = item.as_ref();
+        let relative = match self.overlay.as_ref() {
+            Some(overlay) if item.starts_with(overlay.root()) => {
+                item.strip_prefix(overlay.root()).unwrap()
+            }
+            _ => item.strip_prefix(&self.origin_mount).unwrap(),
+        };
+
+        self.proxy_mount.as_path().join(relative)
+    }
+
+    /// Refuses symlinks the kernel would resolve outside of the origin layer they live in.
+    /// Absolute targets are resolved against the host root, so they may only point into the proxy.
+    ///
+    /// Links to links are followed up to [`MAX_SYMLINK_FOLLOWS`] times, the end of the chain has
+    /// to stay inside as well and a cycle ends up as `ELOOP`.
+    fn ensure_link_contained(&self, link: &Path, target: &Path) -> FuseResult<()> {
+        let (mut link, mut target) = (link.to_path_buf(), target.to_path_buf());
+
+        for _ in 0..MAX_SYMLINK_FOLLOWS {
+            let resolved = self.contained_link_target(&link, &target).ok_or_else(|| {
+                warn!("{link:?} link to {target:?} escapes the origin");
+                FuseError::PERMISSION_DENIED
+            })?;
+
+            // Absolute targets are proxy paths, they're followed on the origin, never through
+            // the proxy itself
+            let resolved = if target.is_absolute() {
+                let relative = relative_path(&self.proxy_mount, &resolved)?;
+                match self.overlay.as_ref() {
+                    Some(overlay) if fs::symlink_metadata(overlay.upper_path(relative)).is_ok() => {
+                        overlay.upper_path(relative)
+                    }
+                    _ => self.origin_mount.path().join(relative),
+                }
+            } else {
+                resolved
+            };
+
+            match fs::read_link(&resolved) {
+                Ok(next) => (link, target) = (resolved, next),
+                Err(_) => return Ok(()),
+            }
+        }
+
+        warn!("{link:?} link is one of too many chained links, there may be a cycle");
+        Err(FuseError::TOO_MANY_SYMLINKS)
+    }
+
+    /// Where a link target leads to if it stays inside, lexically, so symlinks in the middle of
+    /// the target aren't followed.
+    fn contained_link_target(&self, link: &Path, target: &Path) -> Option<PathBuf> {
+        if target.is_absolute() {
+            normalize_path(target).filter(|resolved| resolved.starts_with(&self.proxy_mount))
+        } else {
+            let root = match self.overlay.as_ref() {
+                Some(overlay) if link.starts_with(overlay.root()) => overlay.root(),
+                _ => self.origin_mount.path(),
+            };
+
+            link.parent()
+                .and_then(|parent| normalize_path(&parent.join(target)))
+                .filter(|resolved| resolved.starts_with(root))
+        }
+    }
+
+    /// Atomically swaps two existing items, for `RENAME_EXCHANGE` rename.
+    fn exchange(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> FuseResult<()> {
+        let mut inode_list = self.inode_list.write().unwrap();
+
+        let (parent_node, _) = inode_list.find_by_id(parent).ok_or(FuseError::NO_EXIST)?;
+        let (newparent_node, _) = inode_list
+            .find_by_id(newparent)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        let (first_node, _) = inode_list
+            .find_child_by_name(parent_node, name)
+            .ok_or(FuseError::NO_EXIST)?;
+        let (second_node, _) = inode_list
+            .find_child_by_name(newparent_node, newname)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        if first_node == second_node {
+            return Ok(());
+        }
+
+        // Both items have to live in the same layer to be swapped in place
+        for node_index in [first_node, second_node] {
+            let inode = &mut inode_list.list[node_index];
+            ensure_origin(inode)?;
+
+            if self.overlay.is_some() && inode.layer == Layer::Lower {
+                if inode.attr.kind == FileType::Directory {
+                    return Err(FuseError::CROSS_DEVICE_LINK);
+                }
+
+                self.copy_up(inode)?;
+            }
+        }
+
+        let first = inode_list.list[first_node].origin_path.clone();
+        let second = inode_list.list[second_node].origin_path.clone();
+        let c_first =
+            CString::new(first.as_os_str().as_bytes()).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+        let c_second =
+            CString::new(second.as_os_str().as_bytes()).map_err(|_| FuseError::INVALID_ARGUMENT)?;
+
+        if unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                c_first.as_ptr(),
+                libc::AT_FDCWD,
+                c_second.as_ptr(),
+                libc::RENAME_EXCHANGE,
+            )
+        } != 0
+        {
+            let err = FuseError::last();
+            error!("Failed to exchange {first:?} and {second:?}: {err}");
+            return Err(err);
+        }
+
+        let first_proxy = inode_list.list[first_node].proxy_path.clone();
+        let second_proxy = inode_list.list[second_node].proxy_path.clone();
+
+        let inode = &mut inode_list.list[first_node];
+        inode.proxy_path = second_proxy.clone();
+        inode.origin_path = second.clone();
+
+        let inode = &mut inode_list.list[second_node];
+        inode.proxy_path = first_proxy.clone();
+        inode.origin_path = first.clone();
+
+        if parent_node != newparent_node {
+            inode_list.reparent(first_node, parent_node, newparent_node);
+            inode_list.reparent(second_node, newparent_node, parent_node);
+        }
+
+        inode_list.rebase_descendants(first_node, &first_proxy, &first);
+        inode_list.rebase_descendants(second_node, &second_proxy, &second);
+
+        Ok(())
+    }
+
+    /// Whether a proxy directory has no items in any layer, blocked items included.
+    pub fn is_empty_dir(&self, inode_list: &InodeList, dir_node: NodeIndex) -> FuseResult<bool> {
+        if inode_list.childs(dir_node).next().is_some() {
+            return Ok(false);
+        }
+
+        let dir = &inode_list.list[dir_node];
+        let relative = relative_path(&self.proxy_mount, &dir.proxy_path)?;
+
+        for folder in self.layer_dirs(dir)? {
+            let entries = match read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    error!("Failed to read {folder:?} directory: {err}");
+                    return Err(err.into());
+                }
+            };
+
+            for entry in entries.flatten() {
+                let hidden = self
+                    .overlay
+                    .as_ref()
+                    .is_some_and(|overlay| overlay.is_whiteout(relative.join(entry.file_name())));
+
+                if !hidden {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Hides the lower layer item behind `proxy_path` after it was removed from the proxy.
+    fn whiteout(&mut self, proxy_path: &Path) -> FuseResult<()> {
+        let relative = relative_path(&self.proxy_mount, proxy_path)?.to_path_buf();
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            if fs::symlink_metadata(self.origin_mount.path().join(&relative)).is_ok() {
+                overlay.add_whiteout(relative);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether removing an item through the proxy removes it from the origin as well. Lower layer
+    /// items of an overlay are only hidden, the origin stays untouched.
+    fn owns_origin(&self, inode: &Inode) -> bool {
+        inode.virtual_file.is_none() && (inode.layer == Layer::Upper || self.overlay.is_none())
+    }
+
+    /// Removes a file that is still open the POSIX way: it's gone from its directory right away,
+    /// but open handles keep working until the last one is released, see [`Rfs::release_fh`].
+    ///
+    /// The origin file is moved aside under a hidden name meanwhile, so handles closed to stay
+    /// under `--max-open-files` can still be reopened by path.
+    fn unlink_open(&mut self, node_index: NodeIndex) -> FuseResult<()> {
+        let mut inode_view = self.inode_list.write().unwrap();
+        let inode = &mut inode_view.list[node_index];
+
+        if self.owns_origin(inode) {
+            let hidden = inode
+                .origin_path
+                .with_file_name(format!("{UNLINKED_PREFIX}{}", inode.attr.ino));
+            if let Err(err) = fs::rename(&inode.origin_path, &hidden) {
+                error!(
+                    "Failed to move removed {:?} aside: {err}",
+                    inode.origin_path
+                );
+                return Err(err.into());
+            }
+            inode.origin_path = hidden;
+        }
+
+        debug!(
+            "{:?} is removed while open, it's dropped on the last release",
+            inode.proxy_path
+        );
+        inode.unlinked = true;
+        inode.attr.nlink = 0;
+        let proxy_path = inode.proxy_path.clone();
+        inode_view.detach(node_index);
+        drop(inode_view);
+
+        self.whiteout(&proxy_path)
+    }
+
+    /// Deletes the origin of a file removed while it was open, once its last handle is gone.
+    fn delete_unlinked(&self, inode: &Inode) {
+        if !self.owns_origin(inode) {
+            return;
+        }
+
+        match fs::remove_file(&inode.origin_path) {
+            Ok(()) => debug!("Deleted {:?} after its last release", inode.proxy_path),
+            Err(err) => error!("Failed to delete removed {:?}: {err}", inode.origin_path),
+        }
+    }
+
+    pub fn remove(&mut self, parent: u64, name: &OsStr) -> FuseResult<()> {
+        self.ensure_writable()?;
+
+        let mut inode_view = self.inode_list.write().unwrap();
+        let (parent_node, _) = inode_view.find_by_id(parent).ok_or(FuseError::NO_EXIST)?;
+        let (node_index, inode) = inode_view
+            .find_child_by_name(parent_node, name)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        if inode.open_handles > 0
+            && inode.attr.kind == FileType::RegularFile
+            && !inode_view.has_other_names(node_index)
+        {
+            drop(inode_view);
+            return self.unlink_open(node_index);
+        }
+
+        if self.owns_origin(inode) {
+            match inode.attr.kind {
+                FileType::Directory => {
+                    fs::remove_dir_all(&inode.origin_path)?;
+                }
+                // Symlinks, fifos, sockets and device nodes are unlinked the same way as files
+                _ => {
+                    fs::remove_file(&inode.origin_path)?;
+                }
+            }
+        }
+
+        let proxy_path = inode.proxy_path.clone();
+        let ino = inode.attr.ino;
+        let nlink = inode.attr.nlink;
+        let kind = inode.attr.kind;
+        let _ = inode_view.remove(node_index);
+
+        if kind == FileType::Directory {
+            inode_view.link_subdir(parent_node, false);
+        }
+
+        // Other names of a hard linked file keep the data alive on the origin
+        if inode_view.find_by_id(ino).is_some() {
+            inode_view.set_nlink(ino, nlink.saturating_sub(1));
+        }
+        drop(inode_view);
+
+        self.whiteout(&proxy_path)
+    }
+
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> FuseResult<()> {
+        self.ensure_writable()?;
+
+        if flags == libc::RENAME_EXCHANGE {
+            return self.exchange(parent, name, newparent, newname);
+        }
+
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            error!("Unsupported rename flags: {flags:#x}");
+            return Err(FuseError::INVALID_ARGUMENT);
+        }
+
+        ensure_name_fits(newname)?;
+
+        let mut inode_list = self.inode_list.write().unwrap();
+
+        let (parent_node, _) = inode_list.find_by_id(parent).ok_or(FuseError::NO_EXIST)?;
+
+        let (newparent_node, newparent_inode) = inode_list
+            .find_by_id(newparent)
+            .ok_or(FuseError::NO_EXIST)?;
+        let new_path = newparent_inode.proxy_path.join(newname);
+
+        let (_, inode) = inode_list
+            .find_child_by_name(parent_node, name)
+            .ok_or(FuseError::NO_EXIST)?;
+        ensure_origin(inode)?;
+
+        // The existing destination is replaced, same as rename(2) does
+        let replaced = match inode_list.find_child_by_name(newparent_node, newname) {
+            Some((target_node, target)) => {
+                if flags & libc::RENAME_NOREPLACE != 0 {
+                    return Err(FuseError::FILE_EXISTS);
+                }
+
+                if target.attr.ino == inode.attr.ino {
+                    return Ok(()); // both names refer to the same item
+                }
+                ensure_origin(target)?;
+
+                match (inode.attr.kind, target.attr.kind) {
+                    (FileType::Directory, FileType::Directory)
+                        if !self.is_empty_dir(&inode_list, target_node)? =>
+                    {
+                        return Err(FuseError::DIRECTORY_NOT_EMPTY);
+                    }
+                    (FileType::Directory, _) => return Err(FuseError::NOT_DIRECTORY),
+                    (_, FileType::Directory) => return Err(FuseError::IS_DIRECTORY),
+                    _ => {}
+                }
+
+                Some(target_node)
+            }
+            None => None,
+        };
+
+        let (node_index, inode) = inode_list
+            .find_child_by_name_mut(parent_node, name)
+            .ok_or(FuseError::NO_EXIST)?;
+
+        if self.overlay.is_some() && inode.layer == Layer::Lower {
+            if inode.attr.kind == FileType::Directory {
+                // Same as overlayfs: let the caller fall back to copying the directory
+                return Err(FuseError::CROSS_DEVICE_LINK);
+            }
+
+            self.copy_up(inode)?;
+        }
+
+        let new = self.proxy_path_to_origin_path(new_path.as_path())?;
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.prepare_parent(&new).map_err(|err| {
+                error!("Failed to prepare upper layer for {new:?}: {err}");
+                FuseError::IO
+            })?;
+        }
+        fs::rename(&inode.origin_path, &new)?;
+
+        let old_path = std::mem::replace(&mut inode.proxy_path, new_path);
+        let old_origin_path = std::mem::replace(&mut inode.origin_path, new);
+
+        inode_list.reparent(node_index, parent_node, newparent_node);
+        inode_list.rebase_descendants(node_index, &old_path, &old_origin_path);
+
+        if let Some(target) = replaced.and_then(|target_node| inode_list.remove(target_node)) {
+            debug!("{:?} was replaced by the renamed item", target.proxy_path);
+
+            if target.attr.kind == FileType::Directory {
+                inode_list.link_subdir(newparent_node, false);
+            }
+
+            // Other names of a hard linked file keep the data alive on the origin
+            if inode_list.find_by_id(target.attr.ino).is_some() {
+                inode_list.set_nlink(target.attr.ino, target.attr.nlink.saturating_sub(1));
+            }
+        }
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            let new_path = &inode_list.list[node_index].proxy_path;
+            overlay.remove_whiteout(relative_path(&self.proxy_mount, new_path)?);
+        }
+        drop(inode_list);
+
+        self.whiteout(&old_path)
+    }
+}
+
+impl Drop for Rfs {
+    fn drop(&mut self) {
+        info!("Scan cache stats: {}", self.scanner.cache_stats());
+
+        let mut inode_list = self.inode_list_write();
+        let busy = inode_list.open_paths();
+        if !busy.is_empty() {
+            warn!(
+                "{}: {busy:?} are still open, closing them before unmounting origin",
+                FuseError::BUSY
+            );
+            inode_list.close_all();
+        }
+        for inode in inode_list
+            .list
+            .node_weights()
+            .filter(|inode| inode.unlinked)
+        {
+            self.delete_unlinked(inode);
+        }
+        drop(inode_list);
+        self.handles.clear();
+
+        if let Some(state_file) = self.config.state_file.as_ref() {
+            if let Err(err) = self.save_state(state_file) {
+                error!("Failed to save state: {err:#}");
+            }
+        }
+
+        for mount in self.mounts.iter().rev() {
+            match mount.unmount(UnmountFlags::DETACH) {
+                Ok(()) => {
+                    info!("Unmounted origin {:?} mount", mount.target_path());
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to unmounted origin {:?} mount: {err}",
+                        mount.target_path()
+                    );
+                }
+            }
+        }
+
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.unmount();
+        }
+    }
+}
+
+/// Writes a newline to the readiness fd and closes it, the way s6 and similar service managers
+/// expect it.
+fn notify_ready(ready_fd: RawFd) {
+    // The fd was checked to be open when the arguments were parsed and nothing else owns it
+    let mut ready = unsafe { File::from_raw_fd(ready_fd) };
+    if let Err(err) = ready.write_all(b"\n") {
+        warn!("Failed to write readiness notification to {ready_fd} fd: {err}");
+    }
+}
+
+/// Opens an origin file, once more if the first attempt hits a stale handle of a network origin.
+fn open_origin(path: &Path, read: bool, write: bool) -> std::io::Result<File> {
+    let open = || File::options().read(read).write(write).open(path);
+
+    match open() {
+        Err(err) if err.raw_os_error() == Some(libc::ESTALE) => {
+            warn!("Stale handle while opening {path:?}, retrying");
+            open()
+        }
+        result => result,
+    }
+}
+
+/// Compares the content with the checksum of the first open, the first open takes it. A file
+/// opened for writing gets a new checksum on its next open, see `--verify-checksums`.
+fn verify_checksum(inode: &mut Inode, write: bool) -> FuseResult<()> {
+    let checksum = match allow::file_hash(&inode.origin_path) {
+        Ok(checksum) => checksum,
+        Err(err) => {
+            error!("Failed to checksum {:?}: {err}", inode.origin_path);
+            return Err(err.into());
+        }
+    };
+
+    if inode.checksum.is_some_and(|expected| expected != checksum) {
+        error!(
+            "{:?} changed on the origin since it was first opened",
+            inode.origin_path
+        );
+        return Err(FuseError::IO);
+    }
+
+    inode.checksum = (!write).then_some(checksum);
+    Ok(())
+}
+
+/// Scans an item, returns the signature name if it's infected.
+fn detect(
+    scanner: &dyn Scanner,
+    detections: Option<&DetectionLog>,
+    poll_waiters: &PollWaiters,
+    item: &Path,
+) -> FuseResult<Option<String>> {
+    report_verdict(detections, poll_waiters, item, scanner.scan(item))
+}
+
+/// Logs and records the scan result of `item`, returns the signature if it's infected.
+fn report_verdict(
+    detections: Option<&DetectionLog>,
+    poll_waiters: &PollWaiters,
+    item: &Path,
+    result: anyhow::Result<ScanResult>,
+) -> FuseResult<Option<String>> {
+    match result {
+        Ok(scan_result) => match scan_result {
+            ScanResult::Clean => Ok(None),
+            ScanResult::Whitelisted => {
+                warn!("{item:?} is whitelisted");
+                Ok(None)
+            }
+            ScanResult::Virus(signature) => {
+                error!("{item:?} is infected with {signature}");
+                if let Some(detections) = detections {
+                    detections.record(item, &signature);
+                }
+                // Detection counters in the stats file changed
+                poll_waiters.notify();
+                Ok(Some(signature))
+            }
+        },
+        Err(err) => {
+            error!("Failed to scan {:?} file: {err}", item);
+            Err(FuseError::IO)
+        }
+    }
+}
+
+/// Same rules as the kernel applies without `default_permissions`: root may read and write
+/// anything and execute items with any execute bit, others get the owner, group or other bits.
+fn access_allowed(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    if mask == 0 {
+        return true; // F_OK, existence is checked by the caller
+    }
+
+    if uid == 0 {
+        return mask & libc::X_OK as u16 == 0
+            || attr.kind == FileType::Directory
+            || attr.perm & 0o111 != 0;
+    }
+
+    let granted = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    } & 0o7;
+
+    granted & mask == mask
+}
+
+/// Waits up to `timeout` for all open handles to be released, so the mount can be unmounted.
+///
+/// Returns proxy paths of files that are still open once the timeout is reached.
+pub fn wait_for_release(
+    inode_list: &RwLock<InodeList>,
+    timeout: Duration,
+) -> Result<(), Vec<PathBuf>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let busy = inode_list.read().unwrap().open_paths();
+        if busy.is_empty() {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(busy);
+        }
+
+        debug!("Waiting for {} open files to be released...", busy.len());
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Fails for files that exist only in the proxy, they have no origin to modify.
+fn ensure_origin(inode: &Inode) -> FuseResult<()> {
+    if inode.virtual_file.is_some() {
+        error!("{:?} exists only in the proxy", inode.proxy_path);
+        return Err(FuseError::OPERATION_NOT_PERMITTED);
+    }
+
+    Ok(())
+}
+
+/// Refuses names the origin file system would, the same way on every one of them.
+fn ensure_name_fits(name: &OsStr) -> FuseResult<()> {
+    if name.len() > NAME_MAX {
+        error!(
+            "{name:?} name is {} bytes long, only {NAME_MAX} are allowed",
+            name.len()
+        );
+        return Err(FuseError::NAME_TOO_LONG);
+    }
+
+    Ok(())
+}
+
+fn block_stub_content(item: &Path, signature: &str) -> String {
+    let blocked_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+
+    format!(
+        "rvfs blocked access to this file because it is infected.\n\n\
+         Detection: {signature}\n\
+         Blocked at: {blocked_at} (seconds since Unix epoch)\n\
+         Origin location: {}\n",
+        item.display()
+    )
+}
+
+/// Path of a proxy item relative to the proxy root.
+fn relative_path<'a>(proxy_mount: &Path, proxy_path: &'a Path) -> FuseResult<&'a Path> {
+    proxy_path.strip_prefix(proxy_mount).map_err(|_| {
+        error!("{proxy_path:?} is outside of {proxy_mount:?} proxy mount");
+        FuseError::INVALID_ARGUMENT
+    })
+}
+
+/// Timestamp of `seconds` and `nanos` since the epoch, times before the epoch are clamped to it.
+fn epoch_time(seconds: i64, nanos: i64) -> SystemTime {
+    match u64::try_from(seconds) {
+        Ok(seconds) => {
+            SystemTime::UNIX_EPOCH.add(Duration::new(seconds, u32::try_from(nanos).unwrap_or(0)))
+        }
+        Err(_) => SystemTime::UNIX_EPOCH,
+    }
+}
+
+/// Packs a device number into the 32 bits FUSE has for it, the same way the kernel does for
+/// `new_encode_dev`.
+fn encode_dev(rdev: u64) -> u32 {
+    let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// Resolves `.` and `..` components without touching the file system, `None` if `..` goes above
+/// the root.
+fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    Some(normalized)
+}
+
+fn std_file_type_to_fuse_file_type(tp: fs::FileType) -> FileType {
+    if tp.is_symlink() {
+        FileType::Symlink
+    } else if tp.is_dir() {
+        FileType::Directory
+    } else if tp.is_fifo() {
+        FileType::NamedPipe
+    } else if tp.is_socket() {
+        FileType::Socket
+    } else if tp.is_char_device() {
+        FileType::CharDevice
+    } else if tp.is_block_device() {
+        FileType::BlockDevice
+    } else {
+        FileType::RegularFile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, io::Read, sync::mpsc, thread};
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        cache::CacheStats,
+        handle::tests::Tmpfs,
+        origin::SourceType,
+        scanner::{ScanStats, EICAR},
+    };
+
+    /// Flags every file and buffer that holds the EICAR test string.
+    struct EicarScanner;
+
+    impl EicarScanner {
+        fn verdict(data: &[u8]) -> ScanResult {
+            match data
+                .windows(EICAR.len())
+                .any(|window| window == EICAR.as_bytes())
+            {
+                true => ScanResult::Virus("Eicar-Signature".to_owned()),
+                false => ScanResult::Clean,
+            }
+        }
+    }
+
+    impl Scanner for EicarScanner {
+        fn scan(&self, path: &Path) -> anyhow::Result<ScanResult> {
+            if path.is_dir() {
+                return Ok(ScanResult::Clean);
+            }
+
+            Ok(Self::verdict(&fs::read(path)?))
+        }
+
+        fn scan_bytes(&self, data: &[u8]) -> anyhow::Result<ScanResult> {
+            Ok(Self::verdict(data))
+        }
+
+        fn signature_count(&self) -> u32 {
+            1
+        }
+
+        fn cache_stats(&self) -> CacheStats {
+            CacheStats::default()
+        }
+
+        fn report(&self) -> String {
+            String::new()
+        }
+
+        fn stats(&self) -> Option<&ScanStats> {
+            None
+        }
+
+        fn reload(&self) -> anyhow::Result<u32> {
+            Ok(1)
+        }
+
+        fn in_flight(&self) -> usize {
+            0
+        }
+    }
+
+    /// Proxy over `origin` used in place with its root listed, nothing is mounted or scanned.
+    fn proxy(origin: &Path) -> Rfs {
+        proxy_with(origin, Config::default(), Arc::new(NullScanner))
+    }
+
+    /// Proxy over `origin` that detects EICAR.
+    fn scanned_proxy(origin: &Path, config: Config) -> Rfs {
+        proxy_with(origin, config, Arc::new(EicarScanner))
+    }
+
+    fn proxy_with(origin: &Path, config: Config, scanner: Arc<dyn Scanner>) -> Rfs {
+        let config = Config {
+            source_type: SourceType::Dir,
+            // The scanner is replaced, ClamAV is never set up
+            no_scan: true,
+            ..config
+        };
+        let sources = vec![(origin.to_path_buf(), PathBuf::new())];
+        let mut rfs = Rfs::new(sources, PathBuf::from("/proxy"), config).unwrap();
+        rfs.scanner = scanner;
+        rfs.init();
+        rfs.add_folder(FUSE_ROOT_ID).unwrap();
+
+        rfs
+    }
+
+    fn lookup(rfs: &mut Rfs, name: &str) -> FileAttr {
+        rfs.lookup_entry(FUSE_ROOT_ID, OsStr::new(name)).unwrap().0
+    }
+
+    /// Names a readdir of the directory returns, `.` and `..` aside.
+    fn listing(rfs: &mut Rfs, ino: u64) -> Vec<OsString> {
+        let fh = rfs.open_dir(ino).unwrap();
+        let names = rfs
+            .dir_entries(ino, fh, 0)
+            .unwrap()
+            .iter()
+            .skip(2)
+            .map(|entry| entry.name.clone())
+            .collect();
+        rfs.release_dir(fh).unwrap();
+
+        names
+    }
+
+    #[test]
+    fn fifo_is_refused_without_blocking() {
+        let origin = TempDir::new("rvfs-fifo").unwrap();
+        let fifo = CString::new(origin.path().join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let mut rfs = proxy(origin.path());
+        let attr = lookup(&mut rfs, "fifo");
+        assert_eq!(attr.kind, FileType::NamedPipe);
+
+        // There is no writer, so opening the FIFO itself would never return
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(rfs.allocate_fh(attr.ino, true, false));
+        });
+        let opened = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("open of a FIFO blocked");
+        assert_eq!(opened, Err(FuseError::NOT_SUPPORTED));
+    }
+
+    #[test]
+    fn hard_link_keeps_data_after_unlink() {
+        let origin = TempDir::new("rvfs-link").unwrap();
+        fs::write(origin.path().join("data"), "linked").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let linked = rfs
+            .link(data.ino, FUSE_ROOT_ID, OsStr::new("alias"))
+            .unwrap();
+        assert_eq!(linked.ino, data.ino);
+        assert_eq!(linked.nlink, 2);
+
+        rfs.remove(FUSE_ROOT_ID, OsStr::new("data")).unwrap();
+        let alias = lookup(&mut rfs, "alias");
+        assert_eq!(alias.ino, data.ino);
+        assert_eq!(alias.nlink, 1);
+
+        let fh = rfs.allocate_fh(alias.ino, true, false).unwrap();
+        let mut content = String::new();
+        let mut file = rfs.open_file(alias.ino, fh, true, false).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "linked");
+    }
+
+    #[test]
+    fn removed_origin_fails_with_eio() {
+        let origin = TempDir::new("rvfs-lost").unwrap();
+        fs::write(origin.path().join("data"), "lost").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        assert_eq!(rfs.ensure_origin_available(), Ok(()));
+        // A single missing item doesn't mean the origin is gone
+        assert_eq!(rfs.reply_error(FuseError::NO_EXIST), FuseError::NO_EXIST);
+
+        fs::remove_dir_all(origin.path()).unwrap();
+        let err = rfs.allocate_fh(data.ino, true, false).unwrap_err();
+        assert_eq!(rfs.reply_error(err), FuseError::IO);
+
+        // Every request fails the same way from now on, served from memory or not
+        for _ in 0..3 {
+            assert_eq!(rfs.ensure_origin_available(), Err(FuseError::IO));
+            assert_eq!(rfs.reply_error(FuseError::NO_EXIST), FuseError::IO);
+        }
+    }
+
+    #[test]
+    fn file_changed_after_first_open_is_rejected() {
+        let origin = TempDir::new("rvfs-checksum").unwrap();
+        fs::write(origin.path().join("data"), "original").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        rfs.config.verify_checksums = true;
+        let data = lookup(&mut rfs, "data");
+
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+        rfs.release_fh(data.ino, fh).unwrap();
+        // Unchanged content opens again
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+        rfs.release_fh(data.ino, fh).unwrap();
+
+        fs::write(origin.path().join("data"), "tampered").unwrap();
+        assert_eq!(rfs.allocate_fh(data.ino, true, false), Err(FuseError::IO));
+    }
+
+    #[test]
+    fn only_owner_and_root_change_mode() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("Skipped, the file can be given away only by root");
+            return;
+        }
+
+        let origin = TempDir::new("rvfs-chmod").unwrap();
+        let path = origin.path().join("data");
+        fs::write(&path, "owned").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::chown(&path, Some(1000), Some(1000)).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let inode_list = Arc::clone(&rfs.inode_list);
+        let mut write_view = inode_list.write().unwrap();
+        let (_, inode) = write_view.find_by_id_mut(data.ino).unwrap();
+        let ctime = inode.attr.ctime;
+
+        assert_eq!(
+            rfs.chmod(inode, 1001, 0o777),
+            Err(FuseError::OPERATION_NOT_PERMITTED)
+        );
+        assert_eq!(inode.attr.perm & 0o777, 0o644);
+
+        rfs.chmod(inode, 1000, 0o600).unwrap();
+        assert_eq!(inode.attr.perm & 0o777, 0o600);
+        assert!(inode.attr.ctime > ctime);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        rfs.chmod(inode, 0, 0o640).unwrap();
+        assert_eq!(inode.attr.perm & 0o777, 0o640);
+    }
+
+    #[test]
+    fn only_root_changes_owner() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("Skipped, the origin file can be chowned only by root");
+            return;
+        }
+
+        let origin = TempDir::new("rvfs-chown").unwrap();
+        let path = origin.path().join("data");
+        fs::write(&path, "owned").unwrap();
+        std::os::unix::fs::chown(&path, Some(1000), Some(1000)).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let inode_list = Arc::clone(&rfs.inode_list);
+        let mut write_view = inode_list.write().unwrap();
+        let (_, inode) = write_view.find_by_id_mut(data.ino).unwrap();
+
+        // Not even the owner may give the file away
+        assert_eq!(
+            rfs.chown(inode, 1000, Some(1001), None),
+            Err(FuseError::OPERATION_NOT_PERMITTED)
+        );
+        assert_eq!((inode.attr.uid, inode.attr.gid), (1000, 1000));
+        // Nothing to change, nothing to check
+        assert_eq!(rfs.chown(inode, 1000, Some(1000), Some(1000)), Ok(()));
+
+        rfs.chown(inode, 0, Some(1001), Some(1002)).unwrap();
+        assert_eq!((inode.attr.uid, inode.attr.gid), (1001, 1002));
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1001, 1002));
+    }
+
+    #[test]
+    fn flush_writes_back_buffered_data() {
+        let origin = TempDir::new("rvfs-flush").unwrap();
+        fs::write(origin.path().join("data"), "").unwrap();
+        fs::write(origin.path().join("other"), "").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        rfs.config.write_buffer = 4096;
+        let data = lookup(&mut rfs, "data");
+        let other = lookup(&mut rfs, "other");
+
+        let fh = rfs.allocate_fh(data.ino, false, true).unwrap();
+        rfs.write_fh(data.ino, fh, 0, b"flushed").unwrap();
+        assert_eq!(rfs.flush_file(data.ino, fh), Ok(()));
+        assert_eq!(fs::read(origin.path().join("data")).unwrap(), b"flushed");
+
+        // The handle stays open until release
+        rfs.write_fh(data.ino, fh, 7, b" twice").unwrap();
+        assert_eq!(rfs.flush_file(data.ino, fh), Ok(()));
+        assert_eq!(
+            fs::read(origin.path().join("data")).unwrap(),
+            b"flushed twice"
+        );
+
+        // close() of a handle that doesn't belong to the file reports the error
+        assert_eq!(rfs.flush_file(other.ino, fh), Err(FuseError::BAD_FD));
+        rfs.release_fh(data.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn write_past_eof_leaves_a_hole() {
+        let origin = TempDir::new("rvfs-hole").unwrap();
+        fs::write(origin.path().join("data"), "head").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let fh = rfs.allocate_fh(data.ino, false, true).unwrap();
+
+        assert_eq!(rfs.write_file(data.ino, fh, 8, b"tail"), Ok(4));
+        rfs.release_fh(data.ino, fh).unwrap();
+
+        assert_eq!(lookup(&mut rfs, "data").size, 12);
+        let content = fs::read(origin.path().join("data")).unwrap();
+        assert_eq!(content, b"head\0\0\0\0tail");
+    }
+
+    #[test]
+    fn overlapping_write_keeps_the_size() {
+        let origin = TempDir::new("rvfs-overlap").unwrap();
+        fs::write(origin.path().join("data"), "0123456789").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let fh = rfs.allocate_fh(data.ino, false, true).unwrap();
+
+        assert_eq!(rfs.write_file(data.ino, fh, 2, b"ab"), Ok(2));
+        // Partly inside the file, partly past its end
+        assert_eq!(rfs.write_file(data.ino, fh, 8, b"xyz"), Ok(3));
+        rfs.release_fh(data.ino, fh).unwrap();
+
+        assert_eq!(lookup(&mut rfs, "data").size, 11);
+        let content = fs::read(origin.path().join("data")).unwrap();
+        assert_eq!(content, b"01ab4567xyz");
+    }
+
+    #[test]
+    fn mkdir_applies_the_umask() {
+        let origin = TempDir::new("rvfs-mkdir").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let attr = rfs
+            .create(
+                OsStr::new("dir"),
+                FUSE_ROOT_ID,
+                0o777,
+                0o022,
+                FileType::Directory,
+            )
+            .unwrap();
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.perm, 0o755);
+
+        let mode = fs::metadata(origin.path().join("dir")).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+
+    /// Write syscalls made by the calling thread so far.
+    fn thread_writes() -> u64 {
+        let io = fs::read_to_string("/proc/thread-self/io").unwrap();
+        io.lines()
+            .find_map(|line| line.strip_prefix("syscw: "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// Appends `count` small chunks through a fresh handle, returns the write syscalls it took.
+    fn append_chunks(rfs: &mut Rfs, ino: u64, count: u64) -> u64 {
+        let fh = rfs.allocate_fh(ino, false, true).unwrap();
+        let before = thread_writes();
+        for index in 0..count {
+            rfs.write_file(ino, fh, index * 16, &[b'x'; 16]).unwrap();
+        }
+        rfs.flush_file(ino, fh).unwrap();
+        let writes = thread_writes() - before;
+        rfs.release_fh(ino, fh).unwrap();
+
+        writes
+    }
+
+    #[test]
+    fn write_buffer_coalesces_small_writes() {
+        let origin = TempDir::new("rvfs-coalesce").unwrap();
+        fs::write(origin.path().join("direct"), "").unwrap();
+        fs::write(origin.path().join("buffered"), "").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let direct = lookup(&mut rfs, "direct");
+        let buffered = lookup(&mut rfs, "buffered");
+
+        let direct_writes = append_chunks(&mut rfs, direct.ino, 1000);
+        rfs.config.write_buffer = 64 << 10;
+        let buffered_writes = append_chunks(&mut rfs, buffered.ino, 1000);
+
+        assert!(direct_writes >= 1000, "{direct_writes} direct writes");
+        assert!(buffered_writes <= 10, "{buffered_writes} buffered writes");
+        assert_eq!(
+            fs::read(origin.path().join("buffered")).unwrap(),
+            fs::read(origin.path().join("direct")).unwrap()
+        );
+    }
+
+    #[test]
+    fn buffered_writes_are_seen_by_other_handles() {
+        let origin = TempDir::new("rvfs-buffered").unwrap();
+        fs::write(origin.path().join("data"), "").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        rfs.config.write_buffer = 4096;
+        let data = lookup(&mut rfs, "data");
+        let writer = rfs.allocate_fh(data.ino, false, true).unwrap();
+        let reader = rfs.allocate_fh(data.ino, true, false).unwrap();
+
+        rfs.write_file(data.ino, writer, 0, b"buffered").unwrap();
+        assert_eq!(fs::metadata(origin.path().join("data")).unwrap().len(), 0);
+        assert_eq!(rfs.read_file(data.ino, reader, 0, 64).unwrap(), b"buffered");
+
+        // A truncation isn't undone by data that was buffered before it
+        rfs.write_file(data.ino, writer, 8, b" and gone").unwrap();
+        rfs.truncate(data.ino, 4).unwrap();
+        rfs.flush_file(data.ino, writer).unwrap();
+        assert_eq!(rfs.read_file(data.ino, reader, 0, 64).unwrap(), b"buff");
+        assert_eq!(fs::read(origin.path().join("data")).unwrap(), b"buff");
+        assert_eq!(lookup(&mut rfs, "data").size, 4);
+    }
+
+    #[test]
+    fn full_device_keeps_size_consistent() {
+        let origin = TempDir::new("rvfs-full").unwrap();
+        let _tmpfs = match Tmpfs::mount(origin.path(), "64k") {
+            Ok(tmpfs) => tmpfs,
+            Err(err) => {
+                eprintln!("Skipped, tmpfs can't be mounted: {err}");
+                return;
+            }
+        };
+        fs::write(origin.path().join("direct"), "").unwrap();
+        fs::write(origin.path().join("buffered"), "").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        for (name, write_buffer) in [("direct", 0), ("buffered", 16 << 10)] {
+            rfs.config.write_buffer = write_buffer;
+            let attr = lookup(&mut rfs, name);
+            let fh = rfs.allocate_fh(attr.ino, false, true).unwrap();
+
+            let chunk = [1; 10000];
+            let mut accepted = 0;
+            let err = loop {
+                match rfs.write_file(attr.ino, fh, accepted, &chunk) {
+                    Ok(written) => accepted += written as u64,
+                    Err(err) => break err,
+                }
+            };
+            assert_eq!(err, FuseError::NO_SPACE);
+            rfs.flush_file(attr.ino, fh).unwrap();
+            rfs.release_fh(attr.ino, fh).unwrap();
+
+            // Only what made it to the device is accounted for
+            let size = fs::metadata(origin.path().join(name)).unwrap().len();
+            assert_eq!(size, accepted, "{name}");
+            assert_eq!(lookup(&mut rfs, name).size, size, "{name}");
+
+            // Space for the next file
+            rfs.truncate(attr.ino, 0).unwrap();
+        }
+    }
+
+    #[test]
+    fn infected_writes_fail_close_and_leave_nothing() {
+        let origin = TempDir::new("rvfs-infected-write").unwrap();
+        fs::write(origin.path().join("direct"), "").unwrap();
+        fs::write(origin.path().join("buffered"), "").unwrap();
+
+        let mut rfs = scanned_proxy(origin.path(), Config::default());
+        // Written straight to the origin and scanned there, or scanned in the buffer before
+        for (name, write_buffer) in [("direct", 0), ("buffered", 4096)] {
+            rfs.config.write_buffer = write_buffer;
+            let attr = lookup(&mut rfs, name);
+            let fh = rfs.allocate_fh(attr.ino, false, true).unwrap();
+
+            rfs.write_file(attr.ino, fh, 0, EICAR.as_bytes()).unwrap();
+            assert_eq!(
+                rfs.flush_file(attr.ino, fh),
+                Err(FuseError::OPERATION_NOT_PERMITTED),
+                "{name}"
+            );
+            rfs.release_fh(attr.ino, fh).unwrap();
+
+            assert_eq!(fs::metadata(origin.path().join(name)).unwrap().len(), 0);
+            assert_eq!(lookup(&mut rfs, name).size, 0);
+        }
+    }
+
+    #[test]
+    fn links_out_of_the_origin_are_refused() {
+        let origin = TempDir::new("rvfs-escape").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("data"), "inside").unwrap();
+        symlink("/etc/passwd", origin.path().join("planted")).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        // Found on the origin, it's left out of the listing
+        assert!(rfs
+            .lookup_entry(FUSE_ROOT_ID, OsStr::new("planted"))
+            .is_err());
+
+        for target in ["/etc/passwd", "../..", "dir/../../outside"] {
+            assert_eq!(
+                rfs.symlink(FUSE_ROOT_ID, OsStr::new("escape"), Path::new(target)),
+                Err(FuseError::PERMISSION_DENIED),
+                "{target}"
+            );
+            assert!(fs::symlink_metadata(origin.path().join("escape")).is_err());
+        }
+
+        // Targets inside the origin are fine, relative or through the proxy mount
+        rfs.symlink(
+            FUSE_ROOT_ID,
+            OsStr::new("relative"),
+            Path::new("dir/../data"),
+        )
+        .unwrap();
+        rfs.symlink(
+            FUSE_ROOT_ID,
+            OsStr::new("absolute"),
+            Path::new("/proxy/data"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn link_cycle_fails_with_eloop() {
+        let origin = TempDir::new("rvfs-cycle").unwrap();
+        symlink("b", origin.path().join("a")).unwrap();
+        symlink("a", origin.path().join("b")).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut rfs = proxy(origin.path());
+            let listed = rfs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).map(|_| ());
+            let created = rfs
+                .symlink(FUSE_ROOT_ID, OsStr::new("c"), Path::new("a"))
+                .map(|_| ());
+            let _ = sender.send((listed, created));
+        });
+        let (listed, created) = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("following a link cycle never ended");
+
+        // Left out of the listing
+        assert_eq!(listed, Err(FuseError::NO_EXIST));
+        assert_eq!(created, Err(FuseError::TOO_MANY_SYMLINKS));
+    }
+
+    #[test]
+    fn read_only_mount_rejects_changes() {
+        let origin = TempDir::new("rvfs-read-only").unwrap();
+        fs::write(origin.path().join("data"), "kept").unwrap();
+
+        let config = Config {
+            read_only: true,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let data = lookup(&mut rfs, "data");
+        let name = OsStr::new("new");
+
+        for kind in [FileType::RegularFile, FileType::Directory] {
+            assert_eq!(
+                rfs.create(name, FUSE_ROOT_ID, 0o644, 0, kind),
+                Err(FuseError::READ_ONLY_FILE_SYSTEM)
+            );
+        }
+        assert_eq!(
+            rfs.symlink(FUSE_ROOT_ID, name, Path::new("data")),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+        assert_eq!(
+            rfs.link(data.ino, FUSE_ROOT_ID, name),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+        assert_eq!(
+            rfs.rename(FUSE_ROOT_ID, OsStr::new("data"), FUSE_ROOT_ID, name, 0),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+        assert_eq!(
+            rfs.truncate(data.ino, 0),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+        assert_eq!(
+            rfs.remove(FUSE_ROOT_ID, OsStr::new("data")),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+
+        // Opening for reading is fine, nothing goes through it
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+        assert_eq!(
+            rfs.write_file(data.ino, fh, 0, b"changed"),
+            Err(FuseError::READ_ONLY_FILE_SYSTEM)
+        );
+
+        {
+            let inode_list = Arc::clone(&rfs.inode_list);
+            let mut write_view = inode_list.write().unwrap();
+            let (_, inode) = write_view.find_by_id_mut(data.ino).unwrap();
+            assert_eq!(
+                rfs.chmod(inode, 0, 0o600),
+                Err(FuseError::READ_ONLY_FILE_SYSTEM)
+            );
+            assert_eq!(
+                rfs.chown(inode, 0, Some(1), None),
+                Err(FuseError::READ_ONLY_FILE_SYSTEM)
+            );
+        }
+
+        assert_eq!(fs::read(origin.path().join("data")).unwrap(), b"kept");
+        let names = fs::read_dir(origin.path()).unwrap().count();
+        assert_eq!(names, 1);
+    }
+
+    #[test]
+    fn unlinked_file_lives_until_last_close() {
+        let origin = TempDir::new("rvfs-unlinked").unwrap();
+        fs::write(origin.path().join("data"), "open").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let fh = rfs.allocate_fh(data.ino, true, true).unwrap();
+
+        rfs.remove(FUSE_ROOT_ID, OsStr::new("data")).unwrap();
+        assert!(rfs.lookup_entry(FUSE_ROOT_ID, OsStr::new("data")).is_err());
+
+        // The open handle keeps working
+        rfs.write_file(data.ino, fh, 4, b" still").unwrap();
+        assert_eq!(rfs.read_file(data.ino, fh, 0, 64).unwrap(), b"open still");
+        rfs.flush_file(data.ino, fh).unwrap();
+
+        rfs.release_fh(data.ino, fh).unwrap();
+        assert_eq!(fs::read_dir(origin.path()).unwrap().count(), 0);
+        assert!(rfs.inode_list().find_by_id(data.ino).is_none());
+    }
+
+    #[test]
+    fn trusted_binary_is_scanned_before_exec() {
+        let origin = TempDir::new("rvfs-exec").unwrap();
+        let infected = [b"\x7fELF".as_slice(), EICAR.as_bytes()].concat();
+        fs::write(origin.path().join("infected"), infected).unwrap();
+        fs::write(origin.path().join("clean"), b"\x7fELF").unwrap();
+
+        // Files found on the origin are trusted, nothing is scanned on listing
+        let config = Config {
+            scan_mode: ScanMode::NewOnly,
+            allow_exec_scan: true,
+            ..Config::default()
+        };
+        let mut rfs = scanned_proxy(origin.path(), config);
+        let infected = lookup(&mut rfs, "infected");
+        let clean = lookup(&mut rfs, "clean");
+
+        assert_eq!(rfs.scan_before_exec(clean.ino), Ok(()));
+        assert_eq!(
+            rfs.scan_before_exec(infected.ino),
+            Err(FuseError::PERMISSION_DENIED)
+        );
+        // Blocked for good once it's detected
+        assert_eq!(
+            rfs.allocate_fh(infected.ino, true, false),
+            Err(FuseError::OPERATION_NOT_PERMITTED)
+        );
+    }
+
+    #[test]
+    fn only_new_content_is_scanned() {
+        let origin = TempDir::new("rvfs-new-only").unwrap();
+        fs::write(origin.path().join("existing"), EICAR).unwrap();
+
+        let config = Config {
+            scan_mode: ScanMode::NewOnly,
+            ..Config::default()
+        };
+        let mut rfs = scanned_proxy(origin.path(), config);
+
+        let existing = lookup(&mut rfs, "existing");
+        let fh = rfs.allocate_fh(existing.ino, true, false).unwrap();
+        let content = rfs.read_file(existing.ino, fh, 0, 128).unwrap();
+        assert_eq!(content, EICAR.as_bytes());
+        rfs.release_fh(existing.ino, fh).unwrap();
+
+        let new = rfs
+            .create(
+                OsStr::new("new"),
+                FUSE_ROOT_ID,
+                0o644,
+                0,
+                FileType::RegularFile,
+            )
+            .unwrap();
+        let fh = rfs.allocate_fh(new.ino, false, true).unwrap();
+        rfs.write_file(new.ino, fh, 0, EICAR.as_bytes()).unwrap();
+        assert_eq!(
+            rfs.flush_file(new.ino, fh),
+            Err(FuseError::OPERATION_NOT_PERMITTED)
+        );
+        rfs.release_fh(new.ino, fh).unwrap();
+        assert_eq!(fs::metadata(origin.path().join("new")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn long_names_are_refused_before_the_origin() {
+        let origin = TempDir::new("rvfs-long-name").unwrap();
+        fs::write(origin.path().join("data"), "named").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let long_name = "x".repeat(300);
+        let long_name = OsStr::new(&long_name);
+
+        for kind in [FileType::RegularFile, FileType::Directory] {
+            assert_eq!(
+                rfs.create(long_name, FUSE_ROOT_ID, 0o644, 0, kind),
+                Err(FuseError::NAME_TOO_LONG)
+            );
+        }
+        assert_eq!(
+            rfs.rename(FUSE_ROOT_ID, OsStr::new("data"), FUSE_ROOT_ID, long_name, 0),
+            Err(FuseError::NAME_TOO_LONG)
+        );
+
+        let names = fs::read_dir(origin.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["data"]);
+    }
+
+    #[test]
+    fn audit_records_the_caller() {
+        let origin = TempDir::new("rvfs-audit").unwrap();
+        let logs = TempDir::new("rvfs-audit-log").unwrap();
+        let audit_log = logs.path().join("audit.log");
+
+        let config = Config {
+            audit_log: Some(audit_log.clone()),
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+
+        let name = OsStr::new("new");
+        let created = rfs.create(name, FUSE_ROOT_ID, 0o644, 0, FileType::RegularFile);
+        let caller = Caller {
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+        };
+        let result = created.as_ref().map(|_| ());
+        rfs.audit("create", caller, (FUSE_ROOT_ID, Some(name)), None, result);
+
+        let log = fs::read_to_string(&audit_log).unwrap();
+        let fields = log.trim_end().split('\t').collect::<Vec<_>>();
+        assert_eq!(
+            fields[1..],
+            ["create", "1000", "100", "42", "/proxy/new", "-", "ok"]
+        );
+    }
+
+    #[test]
+    fn readiness_is_reported_once_initialized() {
+        let mut fds = [0; 2];
+        // Close on exec, a process spawned by another test must not hold the write end open
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let [read_fd, write_fd] = fds;
+        let mut ready = unsafe { File::from_raw_fd(read_fd) };
+
+        let origin = TempDir::new("rvfs-ready").unwrap();
+        let config = Config {
+            ready_fd: Some(write_fd),
+            ..Config::default()
+        };
+        let _rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+
+        // The write end is closed after the newline, so reading ends right after it
+        let mut content = Vec::new();
+        ready.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"\n");
+    }
+
+    #[test]
+    fn overlay_edit_leaves_the_lower_file() {
+        let origin = TempDir::new("rvfs-lower").unwrap();
+        fs::write(origin.path().join("data"), "lower").unwrap();
+
+        let config = Config {
+            overlay: true,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let data = lookup(&mut rfs, "data");
+
+        let fh = rfs.allocate_fh(data.ino, true, true).unwrap();
+        rfs.write_file(data.ino, fh, 0, b"upper").unwrap();
+        rfs.flush_file(data.ino, fh).unwrap();
+        assert_eq!(rfs.read_file(data.ino, fh, 0, 64).unwrap(), b"upper");
+        rfs.release_fh(data.ino, fh).unwrap();
+
+        // Opened again, it's served from the upper layer
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+        assert_eq!(rfs.read_file(data.ino, fh, 0, 64).unwrap(), b"upper");
+        assert_eq!(fs::read(origin.path().join("data")).unwrap(), b"lower");
+    }
+
+    #[test]
+    fn open_file_keeps_the_mount_busy() {
+        let origin = TempDir::new("rvfs-busy").unwrap();
+        fs::write(origin.path().join("data"), "busy").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let data = lookup(&mut rfs, "data");
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            wait_for_release(&rfs.inode_list, timeout),
+            Err(vec![PathBuf::from("/proxy/data")])
+        );
+
+        rfs.release_fh(data.ino, fh).unwrap();
+        assert_eq!(wait_for_release(&rfs.inode_list, timeout), Ok(()));
+    }
+
+    #[test]
+    fn blocked_file_leaves_a_stub() {
+        let origin = TempDir::new("rvfs-stub").unwrap();
+        fs::write(origin.path().join("eicar.com"), EICAR).unwrap();
+
+        let config = Config {
+            leave_block_stub: true,
+            ..Config::default()
+        };
+        let mut rfs = scanned_proxy(origin.path(), config);
+        assert!(rfs
+            .lookup_entry(FUSE_ROOT_ID, OsStr::new("eicar.com"))
+            .is_err());
+
+        // Neither writable nor executable
+        let stub = lookup(&mut rfs, "eicar.com.rvfs-blocked");
+        assert_eq!(stub.perm, 0o444);
+        assert_eq!(
+            rfs.allocate_fh(stub.ino, true, true),
+            Err(FuseError::PERMISSION_DENIED)
+        );
+        let fh = rfs.allocate_fh(stub.ino, true, false).unwrap();
+        let content = rfs.read_file(stub.ino, fh, 0, 4096).unwrap();
+        let content = String::from_utf8(content).unwrap();
+        assert_eq!(content.len() as u64, stub.size);
+        assert!(
+            content.contains("Detection: Eicar-Signature\n"),
+            "{content}"
+        );
+        let location = format!(
+            "Origin location: {}\n",
+            origin.path().join("eicar.com").display()
+        );
+        assert!(content.contains(&location), "{content}");
+        assert!(content.contains("Blocked at: "), "{content}");
+    }
+
+    #[test]
+    fn symlink_target_reads_back() {
+        let origin = TempDir::new("rvfs-readlink").unwrap();
+        fs::write(origin.path().join("data"), "target").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let link = rfs
+            .symlink(FUSE_ROOT_ID, OsStr::new("link"), Path::new("data"))
+            .unwrap();
+        assert_eq!(link.kind, FileType::Symlink);
+        assert_eq!(rfs.read_link(link.ino), Ok(PathBuf::from("data")));
+
+        let data = lookup(&mut rfs, "data");
+        assert_eq!(rfs.read_link(data.ino), Err(FuseError::INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn statfs_reports_the_origin_file_system() {
+        let origin = TempDir::new("rvfs-statfs").unwrap();
+        let rfs = proxy(origin.path());
+
+        let path = CString::new(origin.path().as_os_str().as_bytes()).unwrap();
+        let mut expected = unsafe { std::mem::zeroed::<libc::statvfs>() };
+        assert_eq!(unsafe { libc::statvfs(path.as_ptr(), &mut expected) }, 0);
+
+        let stats = rfs.origin_statfs().unwrap();
+        assert_eq!(stats.f_blocks, expected.f_blocks);
+        assert_eq!(stats.f_bsize, expected.f_bsize);
+        assert_eq!(stats.f_namemax, expected.f_namemax);
+    }
+
+    #[test]
+    fn rename_replaces_an_existing_destination() {
+        let origin = TempDir::new("rvfs-rename-over").unwrap();
+        fs::write(origin.path().join("source"), "new").unwrap();
+        fs::write(origin.path().join("target"), "old").unwrap();
+        fs::create_dir_all(origin.path().join("full/nested")).unwrap();
+        fs::create_dir(origin.path().join("empty")).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let source = lookup(&mut rfs, "source");
+        let name = |name| OsStr::new(name);
+
+        rfs.rename(
+            FUSE_ROOT_ID,
+            name("source"),
+            FUSE_ROOT_ID,
+            name("target"),
+            0,
+        )
+        .unwrap();
+        assert_eq!(lookup(&mut rfs, "target").ino, source.ino);
+        assert!(rfs.lookup_entry(FUSE_ROOT_ID, name("source")).is_err());
+        assert_eq!(fs::read(origin.path().join("target")).unwrap(), b"new");
+        assert!(!origin.path().join("source").exists());
+        // A single child has the name
+        let inode_list = rfs.inode_list();
+        let (root, _) = inode_list.find_by_id(FUSE_ROOT_ID).unwrap();
+        let targets = inode_list
+            .childs(root)
+            .filter(|child| child.proxy_path.file_name() == Some(name("target")))
+            .count();
+        assert_eq!(targets, 1);
+        drop(inode_list);
+
+        assert_eq!(
+            rfs.rename(FUSE_ROOT_ID, name("target"), FUSE_ROOT_ID, name("full"), 0),
+            Err(FuseError::IS_DIRECTORY)
+        );
+        assert_eq!(
+            rfs.rename(FUSE_ROOT_ID, name("empty"), FUSE_ROOT_ID, name("full"), 0),
+            Err(FuseError::DIRECTORY_NOT_EMPTY)
+        );
+        assert!(origin.path().join("full/nested").is_dir());
+        assert_eq!(fs::read(origin.path().join("target")).unwrap(), b"new");
+    }
+
+    #[test]
+    fn rename_flags() {
+        let origin = TempDir::new("rvfs-rename-flags").unwrap();
+        fs::write(origin.path().join("a"), "a").unwrap();
+        fs::write(origin.path().join("b"), "b").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let (a, b) = (lookup(&mut rfs, "a"), lookup(&mut rfs, "b"));
+        let name = |name| OsStr::new(name);
+
+        assert_eq!(
+            rfs.rename(
+                FUSE_ROOT_ID,
+                name("a"),
+                FUSE_ROOT_ID,
+                name("b"),
+                libc::RENAME_NOREPLACE
+            ),
+            Err(FuseError::FILE_EXISTS)
+        );
+        assert_eq!(fs::read(origin.path().join("b")).unwrap(), b"b");
+
+        rfs.rename(
+            FUSE_ROOT_ID,
+            name("a"),
+            FUSE_ROOT_ID,
+            name("b"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        assert_eq!(fs::read(origin.path().join("a")).unwrap(), b"b");
+        assert_eq!(fs::read(origin.path().join("b")).unwrap(), b"a");
+        assert_eq!(lookup(&mut rfs, "a").ino, b.ino);
+        assert_eq!(lookup(&mut rfs, "b").ino, a.ino);
+
+        assert_eq!(
+            rfs.rename(
+                FUSE_ROOT_ID,
+                name("a"),
+                FUSE_ROOT_ID,
+                name("c"),
+                libc::RENAME_WHITEOUT
+            ),
+            Err(FuseError::INVALID_ARGUMENT)
+        );
+        assert!(rfs.lookup_entry(FUSE_ROOT_ID, name("a")).is_ok());
+    }
+
+    #[test]
+    fn removed_origin_file_leaves_the_listing() {
+        let origin = TempDir::new("rvfs-vanish").unwrap();
+        fs::write(origin.path().join("kept"), "kept").unwrap();
+        fs::write(origin.path().join("removed"), "removed").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let names = listing(&mut rfs, FUSE_ROOT_ID);
+        assert!(names.contains(&"removed".into()));
+
+        fs::remove_file(origin.path().join("removed")).unwrap();
+        let names = listing(&mut rfs, FUSE_ROOT_ID);
+        assert!(!names.contains(&"removed".into()));
+        assert!(names.contains(&"kept".into()));
+    }
+
+    #[test]
+    fn read_goes_past_a_stale_size() {
+        let origin = TempDir::new("rvfs-grown").unwrap();
+        fs::write(origin.path().join("data"), "short").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        rfs.config.max_read_chunk = 4;
+        let data = lookup(&mut rfs, "data");
+        assert_eq!(data.size, 5);
+
+        fs::write(origin.path().join("data"), "grown behind the proxy").unwrap();
+        let fh = rfs.allocate_fh(data.ino, true, false).unwrap();
+        assert_eq!(
+            rfs.read_file(data.ino, fh, 6, 64).unwrap(),
+            b"behind the proxy"
+        );
+        // Past EOF there is nothing
+        assert_eq!(rfs.read_file(data.ino, fh, 64, 64).unwrap(), b"");
+    }
+
+    #[test]
+    fn excluded_files_are_not_scanned() {
+        let origin = TempDir::new("rvfs-exclude").unwrap();
+        fs::create_dir(origin.path().join("cache")).unwrap();
+        fs::write(origin.path().join("cache/eicar.com"), EICAR).unwrap();
+        fs::write(origin.path().join("eicar.com"), EICAR).unwrap();
+
+        let config = Config {
+            exclude: vec!["cache/**".to_owned()],
+            ..Config::default()
+        };
+        let mut rfs = scanned_proxy(origin.path(), config);
+        // The scanner would flag it
+        assert!(rfs
+            .lookup_entry(FUSE_ROOT_ID, OsStr::new("eicar.com"))
+            .is_err());
+
+        let cache = lookup(&mut rfs, "cache");
+        assert_eq!(listing(&mut rfs, cache.ino), ["eicar.com"]);
+        let (excluded, _) = rfs
+            .lookup_entry(cache.ino, OsStr::new("eicar.com"))
+            .unwrap();
+        let fh = rfs.allocate_fh(excluded.ino, true, false).unwrap();
+        assert_eq!(
+            rfs.read_file(excluded.ino, fh, 0, 128).unwrap(),
+            EICAR.as_bytes()
+        );
+    }
+
+    #[test]
+    fn stats_file_counts_the_scans() {
+        let origin = TempDir::new("rvfs-stats").unwrap();
+        fs::write(origin.path().join("first"), "first").unwrap();
+        fs::write(origin.path().join("second"), "second").unwrap();
+
+        let scanner = match ClamAV::new(&Config::default()) {
+            Ok(scanner) => scanner,
+            Err(err) => {
+                eprintln!("Skipped, ClamAV can't be set up: {err:#}");
+                return;
+            }
+        };
+        let mut rfs = proxy_with(origin.path(), Config::default(), Arc::new(scanner));
+
+        let stats = lookup(&mut rfs, STATS_FILE_NAME);
+        let fh = rfs.allocate_fh(stats.ino, true, false).unwrap();
+        let report = rfs.read_file(stats.ino, fh, 0, 4096).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        for line in ["scanned: 2\n", "clean: 2\n", "infected: 0\n", "failed: 0\n"] {
+            assert!(report.contains(line), "{report}");
+        }
+    }
+
+    #[test]
+    fn copy_range_copies_every_byte() {
+        let origin = TempDir::new("rvfs-copy-range").unwrap();
+        let content = (0..1 << 20)
+            .map(|i: u32| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(origin.path().join("source"), &content).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let source = lookup(&mut rfs, "source");
+        let target = rfs
+            .create(
+                OsStr::new("target"),
+                FUSE_ROOT_ID,
+                0o644,
+                0,
+                FileType::RegularFile,
+            )
+            .unwrap();
+        let fh_in = rfs.allocate_fh(source.ino, true, false).unwrap();
+        let fh_out = rfs.allocate_fh(target.ino, false, true).unwrap();
+
+        let len = content.len() as u64;
+        let copied = rfs
+            .copy_range((source.ino, fh_in, 0), (target.ino, fh_out, 0), len)
+            .unwrap();
+        assert_eq!(copied, len);
+        // Past the end of the source
+        let copied = rfs
+            .copy_range((source.ino, fh_in, len), (target.ino, fh_out, len), 10)
+            .unwrap();
+        assert_eq!(copied, 0);
+        rfs.release_fh(source.ino, fh_in).unwrap();
+        rfs.release_fh(target.ino, fh_out).unwrap();
+
+        assert_eq!(lookup(&mut rfs, "target").size, len);
+        assert!(fs::read(origin.path().join("target")).unwrap() == content);
+    }
+
+    #[test]
+    fn allocate_preallocates_and_punches_holes() {
+        let origin = TempDir::new("rvfs-allocate").unwrap();
+        fs::write(origin.path().join("file"), [1; 8192]).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let attr = lookup(&mut rfs, "file");
+        let fh = rfs.allocate_fh(attr.ino, true, true).unwrap();
+
+        rfs.allocate(attr.ino, fh, 0, 1 << 20, 0).unwrap();
+        assert_eq!(lookup(&mut rfs, "file").size, 1 << 20);
+        // Space past the end without changing the size
+        rfs.allocate(attr.ino, fh, 1 << 20, 1 << 20, libc::FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        assert_eq!(lookup(&mut rfs, "file").size, 1 << 20);
+        let metadata = fs::metadata(origin.path().join("file")).unwrap();
+        assert_eq!(metadata.len(), 1 << 20);
+        assert!(metadata.blocks() * 512 >= 2 << 20, "{}", metadata.blocks());
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        rfs.allocate(attr.ino, fh, 0, 4096, mode).unwrap();
+        let content = rfs.read_file(attr.ino, fh, 0, 8192).unwrap();
+        assert_eq!(content[..4096], [0; 4096]);
+        assert_eq!(content[4096..], [1; 4096]);
+        assert_eq!(lookup(&mut rfs, "file").size, 1 << 20);
+        rfs.release_fh(attr.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn dot_dot_is_a_directory() {
+        let origin = TempDir::new("rvfs-dot-dot").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let dir = lookup(&mut rfs, "dir");
+        // The kernel replaces the ino of the root's `..` with the one outside of the proxy
+        for (ino, parent_ino) in [(FUSE_ROOT_ID, FUSE_ROOT_ID), (dir.ino, FUSE_ROOT_ID)] {
+            let fh = rfs.open_dir(ino).unwrap();
+            let dot_dot = &rfs.dir_entries(ino, fh, 0).unwrap()[1];
+            assert_eq!(dot_dot.name, "..");
+            assert_eq!(dot_dot.ino, parent_ino);
+            assert_eq!(dot_dot.kind, FileType::Directory);
+            assert_eq!(dot_dot.offset, 2);
+            rfs.release_dir(fh).unwrap();
+        }
+
+        let root = rfs.current_attr(FUSE_ROOT_ID).unwrap();
+        assert_eq!(root.kind, FileType::Directory);
+        assert!(root.nlink >= 3, "{}", root.nlink);
+    }
+
+    #[test]
+    fn rescan_drops_planted_eicar() {
+        let origin = TempDir::new("rvfs-rescan").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("dir/planted"), "clean").unwrap();
+        fs::write(origin.path().join("dir/clean"), "clean").unwrap();
+
+        let mut rfs = scanned_proxy(origin.path(), Config::default());
+        let dir = lookup(&mut rfs, "dir");
+        assert_eq!(listing(&mut rfs, dir.ino).len(), 2);
+
+        // Planted behind the proxy's back, the cached listing doesn't notice
+        fs::write(origin.path().join("dir/planted"), EICAR).unwrap();
+        rfs.control(b"rescan /\n").unwrap();
+
+        assert_eq!(listing(&mut rfs, dir.ino), ["clean"]);
+        assert_eq!(rfs.control(b"rescan /missing"), Err(FuseError::NO_EXIST));
+        assert_eq!(rfs.control(b"scan /"), Err(FuseError::INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn create_applies_the_umask() {
+        let origin = TempDir::new("rvfs-create-mode").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        for (name, mode, perm) in [("plain", 0o644, 0o644), ("shared", 0o666, 0o644)] {
+            let attr = rfs
+                .create(
+                    OsStr::new(name),
+                    FUSE_ROOT_ID,
+                    mode,
+                    0o022,
+                    FileType::RegularFile,
+                )
+                .unwrap();
+            assert_eq!(attr.kind, FileType::RegularFile);
+            assert_eq!(attr.perm, perm, "{name}");
+
+            let mode = fs::metadata(origin.path().join(name)).unwrap().mode();
+            assert_eq!(mode & 0o7777, u32::from(perm), "{name}");
+        }
+    }
+
+    #[test]
+    fn sync_dir_after_create() {
+        let origin = TempDir::new("rvfs-fsyncdir").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let dir = lookup(&mut rfs, "dir");
+        rfs.create(OsStr::new("file"), dir.ino, 0o644, 0, FileType::RegularFile)
+            .unwrap();
+
+        rfs.sync_dir(dir.ino, false).unwrap();
+        rfs.sync_dir(dir.ino, true).unwrap();
+        assert!(origin.path().join("dir/file").exists());
+        assert_eq!(rfs.sync_dir(u64::MAX, false), Err(FuseError::NO_EXIST));
+    }
+
+    #[test]
+    fn attr_passthrough_shows_origin_changes() {
+        let origin = TempDir::new("rvfs-passthrough").unwrap();
+        let path = origin.path().join("file");
+        fs::write(&path, "content").unwrap();
+        let touched = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+        for attr_passthrough in [false, true] {
+            let config = Config {
+                attr_passthrough,
+                ..Config::default()
+            };
+            let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+            let attr = lookup(&mut rfs, "file");
+            assert_ne!(attr.mtime, touched);
+
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(touched)
+                .unwrap();
+            let mtime = rfs.current_attr(attr.ino).unwrap().mtime;
+            assert_eq!(mtime == touched, attr_passthrough);
+
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn handles_over_the_open_file_limit() {
+        let origin = TempDir::new("rvfs-open-files").unwrap();
+        for i in 0..8 {
+            fs::write(origin.path().join(i.to_string()), i.to_string()).unwrap();
+        }
+
+        let config = Config {
+            max_open_files: 2,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let handles = (0..8)
+            .map(|i| {
+                let attr = lookup(&mut rfs, &i.to_string());
+                (attr.ino, rfs.allocate_fh(attr.ino, true, false).unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        // Closed origin files are opened again on use, in any order
+        for (i, &(ino, fh)) in handles.iter().enumerate().rev() {
+            let content = rfs.read_file(ino, fh, 0, 16).unwrap();
+            assert_eq!(content, i.to_string().as_bytes());
+        }
+        for (ino, fh) in handles {
+            rfs.release_fh(ino, fh).unwrap();
+        }
+    }
+
+    #[test]
+    fn renamed_directory_keeps_nested_files() {
+        let origin = TempDir::new("rvfs-rename-dir").unwrap();
+        fs::create_dir_all(origin.path().join("dir/sub")).unwrap();
+        fs::write(origin.path().join("dir/sub/file"), "nested").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let dir = lookup(&mut rfs, "dir");
+        listing(&mut rfs, dir.ino);
+        let (sub, _) = rfs.lookup_entry(dir.ino, OsStr::new("sub")).unwrap();
+        listing(&mut rfs, sub.ino);
+        let (file, _) = rfs.lookup_entry(sub.ino, OsStr::new("file")).unwrap();
+
+        rfs.rename(
+            FUSE_ROOT_ID,
+            OsStr::new("dir"),
+            FUSE_ROOT_ID,
+            OsStr::new("moved"),
+            0,
+        )
+        .unwrap();
+        assert!(origin.path().join("moved/sub/file").exists());
+
+        // Known descendants follow, the inos stay the same
+        let moved = lookup(&mut rfs, "moved");
+        assert_eq!(moved.ino, dir.ino);
+        let (sub, _) = rfs.lookup_entry(moved.ino, OsStr::new("sub")).unwrap();
+        let (moved_file, _) = rfs.lookup_entry(sub.ino, OsStr::new("file")).unwrap();
+        assert_eq!(moved_file.ino, file.ino);
+
+        let fh = rfs.allocate_fh(file.ino, true, false).unwrap();
+        assert_eq!(rfs.read_file(file.ino, fh, 0, 16).unwrap(), b"nested");
+        rfs.release_fh(file.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn state_file_restores_the_listing() {
+        let origin = TempDir::new("rvfs-state").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("dir/file"), "file").unwrap();
+        fs::write(origin.path().join("top"), "top").unwrap();
+        let state = TempDir::new("rvfs-state-file").unwrap();
+
+        let config = || Config {
+            state_file: Some(state.path().join("state")),
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config(), Arc::new(NullScanner));
+        let dir = lookup(&mut rfs, "dir");
+        let names = (listing(&mut rfs, FUSE_ROOT_ID), listing(&mut rfs, dir.ino));
+        drop(rfs);
+
+        // The directory isn't listed again, its items come from the state
+        let mut rfs = proxy_with(origin.path(), config(), Arc::new(NullScanner));
+        let dir = lookup(&mut rfs, "dir");
+        assert!(rfs.lookup_entry(dir.ino, OsStr::new("file")).is_ok());
+        assert_eq!(
+            (listing(&mut rfs, FUSE_ROOT_ID), listing(&mut rfs, dir.ino)),
+            names
+        );
+    }
+
+    #[test]
+    fn sources_share_one_tree() {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("Skipped, bind mounts need root");
+            return;
+        }
+
+        let first = TempDir::new("rvfs-first-source").unwrap();
+        fs::write(first.path().join("first"), "first").unwrap();
+        let second = TempDir::new("rvfs-second-source").unwrap();
+        fs::write(second.path().join("second"), "second").unwrap();
+        let work_dir = TempDir::new("rvfs-sources-work").unwrap();
+
+        let config = Config {
+            source_type: SourceType::Bind,
+            no_scan: true,
+            work_dir: Some(work_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let sources = vec![
+            (first.path().to_path_buf(), PathBuf::from("a")),
+            (second.path().to_path_buf(), PathBuf::from("b")),
+        ];
+        let mut rfs = Rfs::new(sources, PathBuf::from("/proxy"), config).unwrap();
+        rfs.init();
+        rfs.add_folder(FUSE_ROOT_ID).unwrap();
+
+        for (subdir, name) in [("a", "first"), ("b", "second")] {
+            let dir = lookup(&mut rfs, subdir);
+            assert_eq!(listing(&mut rfs, dir.ino), [name]);
+            let (file, _) = rfs.lookup_entry(dir.ino, OsStr::new(name)).unwrap();
+            let fh = rfs.allocate_fh(file.ino, true, false).unwrap();
+            assert_eq!(rfs.read_file(file.ino, fh, 0, 16).unwrap(), name.as_bytes());
+            rfs.release_fh(file.ino, fh).unwrap();
+        }
+
+        // Every source is unmounted with the proxy
+        drop(rfs);
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0);
+        assert!(first.path().join("first").exists());
+        assert!(second.path().join("second").exists());
+    }
+
+    #[test]
+    fn created_file_attributes_match_the_origin() {
+        let origin = TempDir::new("rvfs-create-attr").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        for (name, kind) in [
+            ("file", FileType::RegularFile),
+            ("dir", FileType::Directory),
+        ] {
+            let attr = rfs
+                .create(OsStr::new(name), FUSE_ROOT_ID, 0o755, 0, kind)
+                .unwrap();
+
+            let metadata = fs::symlink_metadata(origin.path().join(name)).unwrap();
+            assert_eq!(attr.uid, metadata.uid(), "{name}");
+            assert_eq!(attr.gid, metadata.gid(), "{name}");
+            assert_eq!(attr.size, metadata.size(), "{name}");
+            assert_eq!(attr.mtime, metadata.modified().unwrap(), "{name}");
+            assert_eq!(attr.atime, metadata.accessed().unwrap(), "{name}");
+            assert_eq!(lookup(&mut rfs, name), attr);
+        }
+    }
+
+    #[test]
+    fn seek_finds_data_and_holes() {
+        let origin = TempDir::new("rvfs-seek").unwrap();
+        let file = File::create(origin.path().join("sparse")).unwrap();
+        file.set_len(1 << 20).unwrap();
+        file.write_all_at(&[1; 4096], 1 << 19).unwrap();
+        drop(file);
+
+        let mut rfs = proxy(origin.path());
+        let attr = lookup(&mut rfs, "sparse");
+        let fh = rfs.allocate_fh(attr.ino, true, false).unwrap();
+
+        assert_eq!(rfs.seek(attr.ino, fh, 0, libc::SEEK_DATA), Ok(1 << 19));
+        let hole = rfs.seek(attr.ino, fh, 1 << 19, libc::SEEK_HOLE).unwrap();
+        assert!(((1 << 19) + 4096..=1 << 20).contains(&hole), "{hole}");
+        assert_eq!(rfs.seek(attr.ino, fh, 0, libc::SEEK_HOLE), Ok(0));
+        assert_eq!(
+            rfs.seek(attr.ino, fh, 1 << 20, libc::SEEK_DATA),
+            Err(FuseError::NO_SUCH_DEVICE_OR_ADDRESS)
+        );
+        assert_eq!(
+            rfs.seek(attr.ino, fh, 0, 42),
+            Err(FuseError::INVALID_ARGUMENT)
+        );
+        rfs.release_fh(attr.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn lookup_under_an_unknown_parent() {
+        let origin = TempDir::new("rvfs-bogus-parent").unwrap();
+        fs::write(origin.path().join("file"), "file").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let file = lookup(&mut rfs, "file");
+        assert_eq!(
+            rfs.lookup_entry(u64::MAX, OsStr::new("file")),
+            Err(FuseError::NO_EXIST)
+        );
+        // A file has no items of its own
+        assert_eq!(
+            rfs.lookup_entry(file.ino, OsStr::new("file")),
+            Err(FuseError::NO_EXIST)
+        );
+    }
+
+    #[test]
+    fn pre_epoch_times_are_clamped() {
+        let origin = TempDir::new("rvfs-pre-epoch").unwrap();
+        let path = origin.path().join("old");
+        File::create(&path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH - Duration::from_secs(86400))
+            .unwrap();
+
+        let config = Config {
+            attr_passthrough: true,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let attr = lookup(&mut rfs, "old");
+        assert_eq!(attr.mtime, SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            rfs.current_attr(attr.ino).unwrap().mtime,
+            SystemTime::UNIX_EPOCH
+        );
+    }
+
+    #[test]
+    fn watched_origin_changes_show_up() {
+        let origin = TempDir::new("rvfs-watch").unwrap();
+        fs::write(origin.path().join("early"), "early").unwrap();
+
+        let config = Config {
+            watch: true,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        assert!(rfs.watcher.is_some());
+        lookup(&mut rfs, "early");
+
+        fs::write(origin.path().join("late"), "late").unwrap();
+        fs::remove_file(origin.path().join("early")).unwrap();
+        // Events arrive on the watcher thread, without a readdir in between
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while rfs.find_entry(FUSE_ROOT_ID, OsStr::new("late")).is_err() {
+            assert!(Instant::now() < deadline, "late never showed up");
+            thread::sleep(Duration::from_millis(10));
+            rfs.sync_watched();
+        }
+
+        assert_eq!(
+            rfs.find_entry(FUSE_ROOT_ID, OsStr::new("early")),
+            Err(FuseError::NO_EXIST)
+        );
+        assert!(listing(&mut rfs, FUSE_ROOT_ID).contains(&"late".into()));
+    }
+
+    #[test]
+    fn bmap_is_not_supported() {
+        let origin = TempDir::new("rvfs-bmap").unwrap();
+        fs::write(origin.path().join("file"), [1; 8192]).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let attr = lookup(&mut rfs, "file");
+        assert_eq!(
+            rfs.block_map(attr.ino, 4096, 1),
+            Err(FuseError::NOT_SUPPORTED)
+        );
+        assert_eq!(i32::from(FuseError::NOT_SUPPORTED), libc::EOPNOTSUPP);
+    }
+
+    #[test]
+    fn detections_are_logged() {
+        let origin = TempDir::new("rvfs-detections").unwrap();
+        fs::write(origin.path().join("eicar.com"), EICAR).unwrap();
+        fs::write(origin.path().join("clean"), "clean").unwrap();
+        let log_dir = TempDir::new("rvfs-detections-log").unwrap();
+        let log = log_dir.path().join("detections");
+
+        let config = Config {
+            detections_log: Some(log.clone()),
+            ..Config::default()
+        };
+        let _rfs = scanned_proxy(origin.path(), config);
+
+        let lines = fs::read_to_string(&log).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        let [line] = lines.as_slice() else {
+            panic!("{lines:?}");
+        };
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let [timestamp, path, signature] = fields.as_slice() else {
+            panic!("{line}");
+        };
+        assert!(timestamp.parse::<u64>().unwrap() > 0);
+        assert_eq!(
+            Path::new(path),
+            origin.path().canonicalize().unwrap().join("eicar.com")
+        );
+        assert_eq!(*signature, "Eicar-Signature");
+    }
+
+    #[test]
+    fn subdirectories_count_as_parent_links() {
+        let origin = TempDir::new("rvfs-nlink").unwrap();
+        fs::create_dir_all(origin.path().join("parent/listed")).unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let parent = lookup(&mut rfs, "parent");
+        assert_eq!(parent.nlink, 3);
+
+        rfs.create(
+            OsStr::new("created"),
+            parent.ino,
+            0o755,
+            0,
+            FileType::Directory,
+        )
+        .unwrap();
+        rfs.create(
+            OsStr::new("file"),
+            parent.ino,
+            0o644,
+            0,
+            FileType::RegularFile,
+        )
+        .unwrap();
+        assert_eq!(lookup(&mut rfs, "parent").nlink, 4);
+
+        rfs.remove(parent.ino, OsStr::new("created")).unwrap();
+        assert_eq!(lookup(&mut rfs, "parent").nlink, 3);
+    }
+
+    #[test]
+    fn replaced_origin_file_is_opened_again() {
+        let origin = TempDir::new("rvfs-replaced").unwrap();
+        let path = origin.path().join("file");
+        fs::write(&path, "old").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let attr = lookup(&mut rfs, "file");
+        let old_fh = rfs.allocate_fh(attr.ino, true, false).unwrap();
+        assert_eq!(rfs.read_file(attr.ino, old_fh, 0, 16).unwrap(), b"old");
+
+        // A new inode takes the name, like an editor saving through a temporary file
+        let replacement = origin.path().join("file.tmp");
+        fs::write(&replacement, "replaced").unwrap();
+        fs::rename(&replacement, &path).unwrap();
+
+        let fh = rfs.allocate_fh(attr.ino, true, false).unwrap();
+        assert_eq!(rfs.read_file(attr.ino, fh, 0, 16).unwrap(), b"replaced");
+        rfs.release_fh(attr.ino, fh).unwrap();
+        // A local origin keeps the old inode alive for handles that were open before
+        assert_eq!(rfs.read_file(attr.ino, old_fh, 0, 16).unwrap(), b"old");
+        rfs.release_fh(attr.ino, old_fh).unwrap();
+    }
+
+    #[test]
+    fn ttls_are_echoed() {
+        let origin = TempDir::new("rvfs-ttl").unwrap();
+
+        let config = Config {
+            entry_ttl: Duration::from_secs(3600),
+            attr_ttl: Duration::from_millis(1500),
+            ..Config::default()
+        };
+        let rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        assert_eq!(rfs.entry_ttl(), Duration::from_secs(3600));
+        assert_eq!(rfs.attr_ttl(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn truncating_open_empties_the_file() {
+        let origin = TempDir::new("rvfs-open-trunc").unwrap();
+        fs::write(origin.path().join("file"), "content").unwrap();
+        fs::write(origin.path().join("kept"), "content").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let file = lookup(&mut rfs, "file");
+        let fh = rfs
+            .open_fh(file.ino, libc::O_WRONLY | libc::O_TRUNC, uid, gid)
+            .unwrap();
+        assert_eq!(lookup(&mut rfs, "file").size, 0);
+        assert_eq!(fs::read(origin.path().join("file")).unwrap(), b"");
+        rfs.release_fh(file.ino, fh).unwrap();
+
+        // A read-only open never truncates anything
+        let kept = lookup(&mut rfs, "kept");
+        let fh = rfs
+            .open_fh(kept.ino, libc::O_RDONLY | libc::O_TRUNC, uid, gid)
+            .unwrap();
+        assert_eq!(rfs.read_file(kept.ino, fh, 0, 16).unwrap(), b"content");
+        rfs.release_fh(kept.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn open_checks_the_caller_access() {
+        let origin = TempDir::new("rvfs-open-access").unwrap();
+        for (name, mode) in [("write-only", 0o602), ("read-only", 0o604)] {
+            let path = origin.path().join(name);
+            fs::write(&path, name).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let mut rfs = proxy(origin.path());
+        // Neither the owner nor in the group of the files
+        let (uid, gid) = (lookup(&mut rfs, "read-only").uid + 1, u32::MAX - 1);
+        for (name, denied, allowed) in [
+            ("write-only", libc::O_RDONLY, libc::O_WRONLY),
+            ("read-only", libc::O_WRONLY, libc::O_RDONLY),
+        ] {
+            let attr = lookup(&mut rfs, name);
+            for flags in [denied, libc::O_RDWR] {
+                assert_eq!(
+                    rfs.open_fh(attr.ino, flags, uid, gid),
+                    Err(FuseError::PERMISSION_DENIED),
+                    "{name}: {flags:#o}"
+                );
+            }
+
+            let fh = rfs.open_fh(attr.ino, allowed, uid, gid).unwrap();
+            rfs.release_fh(attr.ino, fh).unwrap();
+        }
+    }
+
+    #[test]
+    fn large_directory_is_listed_in_batches() {
+        let origin = TempDir::new("rvfs-large-dir").unwrap();
+        for i in 0..1000 {
+            fs::write(origin.path().join(format!("file-{i}")), "").unwrap();
+        }
+
+        let mut rfs = proxy(origin.path());
+        let fh = rfs.open_dir(FUSE_ROOT_ID).unwrap();
+        let mut names = Vec::new();
+        let mut offset = 0;
+        // The kernel asks for the next batch from the offset of the last entry it got
+        loop {
+            let entries = rfs.dir_entries(FUSE_ROOT_ID, fh, offset).unwrap();
+            let batch = &entries[..entries.len().min(64)];
+            let Some(last) = batch.last() else {
+                break;
+            };
+            offset = last.offset;
+            names.extend(batch.iter().map(|entry| entry.name.clone()));
+
+            // Changes in between don't shift the listing
+            if names.len() == 64 {
+                fs::write(origin.path().join("a-late-file"), "").unwrap();
+                fs::remove_file(origin.path().join("file-500")).unwrap();
+            }
+        }
+        rfs.release_dir(fh).unwrap();
+
+        let unique = names.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), names.len(), "entries were repeated");
+        // `.`, `..` and the virtual files
+        assert_eq!(names.len(), 1000 + 4);
+        for i in 0..1000 {
+            assert!(unique.contains(&OsString::from(format!("file-{i}"))));
+        }
+    }
+
+    #[test]
+    fn listing_starts_with_dot_entries() {
+        let origin = TempDir::new("rvfs-dots").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("dir/file"), "").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let dir = lookup(&mut rfs, "dir");
+        let fh = rfs.open_dir(dir.ino).unwrap();
+        let entries = rfs.dir_entries(dir.ino, fh, 0).unwrap();
+        let dots = entries
+            .iter()
+            .take(2)
+            .map(|entry| (entry.name.clone(), entry.ino, entry.kind, entry.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dots,
+            [
+                (".".into(), dir.ino, FileType::Directory, 1),
+                ("..".into(), FUSE_ROOT_ID, FileType::Directory, 2),
+            ]
+        );
+        assert_eq!(entries.len(), 3);
+
+        // Continuing after `.` starts with `..`
+        let entries = rfs.dir_entries(dir.ino, fh, 1).unwrap();
+        assert_eq!(entries[0].name, "..");
+        rfs.release_dir(fh).unwrap();
+    }
+
+    #[test]
+    fn directory_source_is_used_in_place() {
+        let origin = TempDir::new("rvfs-dir-source").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("dir/nested"), "nested").unwrap();
+        fs::write(origin.path().join("top"), "top").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let mut names = listing(&mut rfs, FUSE_ROOT_ID);
+        names.sort();
+        assert_eq!(names, [CONTROL_FILE_NAME, STATS_FILE_NAME, "dir", "top"]);
+        let dir = lookup(&mut rfs, "dir");
+        assert_eq!(listing(&mut rfs, dir.ino), ["nested"]);
+
+        // Nothing is mounted, changes land in the source directory itself
+        rfs.create(
+            OsStr::new("created"),
+            dir.ino,
+            0o644,
+            0,
+            FileType::RegularFile,
+        )
+        .unwrap();
+        assert!(origin.path().join("dir/created").exists());
+
+        let config = Config {
+            source_type: SourceType::Dir,
+            no_scan: true,
+            ..Config::default()
+        };
+        let top = origin.path().join("top");
+        let sources = vec![(top, PathBuf::new())];
+        assert!(Rfs::new(sources, PathBuf::from("/proxy"), config.clone()).is_err());
+        let sources = vec![
+            (origin.path().join("dir"), PathBuf::from("a")),
+            (origin.path().to_path_buf(), PathBuf::from("b")),
+        ];
+        assert!(Rfs::new(sources, PathBuf::from("/proxy"), config).is_err());
+    }
+
+    #[test]
+    fn buffered_write_is_persisted_and_scanned_on_close() {
+        let origin = TempDir::new("rvfs-buffered-close").unwrap();
+        fs::write(origin.path().join("file"), "").unwrap();
+
+        let config = Config {
+            write_buffer: 4096,
+            ..Config::default()
+        };
+        let scanner = match ClamAV::new(&config) {
+            Ok(scanner) => scanner,
+            Err(err) => {
+                eprintln!("Skipped, ClamAV can't be set up: {err:#}");
+                return;
+            }
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(scanner));
+        let scanned = |rfs: &Rfs| {
+            let report = rfs.scanner.report();
+            report
+                .lines()
+                .find_map(|line| line.strip_prefix("scanned: "))
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        };
+
+        let attr = lookup(&mut rfs, "file");
+        let fh = rfs.allocate_fh(attr.ino, false, true).unwrap();
+        rfs.write_file(attr.ino, fh, 0, b"first ").unwrap();
+        rfs.write_file(attr.ino, fh, 6, b"second").unwrap();
+        assert_eq!(fs::read(origin.path().join("file")).unwrap(), b"");
+        let before = scanned(&rfs);
+
+        // The kernel flushes a handle before it's released
+        rfs.flush_file(attr.ino, fh).unwrap();
+        rfs.release_fh(attr.ino, fh).unwrap();
+        assert_eq!(
+            fs::read(origin.path().join("file")).unwrap(),
+            b"first second"
+        );
+        assert_eq!(scanned(&rfs), before + 1);
+    }
+
+    #[test]
+    fn overlay_changes_leave_the_origin_pristine() {
+        let origin = TempDir::new("rvfs-overlay").unwrap();
+        fs::write(origin.path().join("edited"), "lower").unwrap();
+        fs::write(origin.path().join("removed"), "lower").unwrap();
+
+        let config = Config {
+            overlay: true,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let edited = lookup(&mut rfs, "edited");
+        let fh = rfs.allocate_fh(edited.ino, false, true).unwrap();
+        rfs.write_file(edited.ino, fh, 5, b" and upper").unwrap();
+        rfs.flush_file(edited.ino, fh).unwrap();
+        rfs.release_fh(edited.ino, fh).unwrap();
+
+        let created = rfs
+            .create(
+                OsStr::new("created"),
+                FUSE_ROOT_ID,
+                0o644,
+                0,
+                FileType::RegularFile,
+            )
+            .unwrap();
+        let fh = rfs.allocate_fh(created.ino, false, true).unwrap();
+        rfs.write_file(created.ino, fh, 0, b"upper").unwrap();
+        rfs.flush_file(created.ino, fh).unwrap();
+        rfs.release_fh(created.ino, fh).unwrap();
+        rfs.remove(FUSE_ROOT_ID, OsStr::new("removed")).unwrap();
+
+        let mut names = listing(&mut rfs, FUSE_ROOT_ID);
+        names.retain(|name| !name.as_bytes().starts_with(b".rvfs"));
+        names.sort();
+        assert_eq!(names, ["created", "edited"]);
+        let fh = rfs.allocate_fh(edited.ino, true, false).unwrap();
+        assert_eq!(
+            rfs.read_file(edited.ino, fh, 0, 64).unwrap(),
+            b"lower and upper"
+        );
+        rfs.release_fh(edited.ino, fh).unwrap();
+
+        let mut origin_names = fs::read_dir(origin.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        origin_names.sort();
+        assert_eq!(origin_names, ["edited", "removed"]);
+        assert_eq!(fs::read(origin.path().join("edited")).unwrap(), b"lower");
+    }
+
+    #[test]
+    fn symlink_and_fifo_are_removed() {
+        let origin = TempDir::new("rvfs-remove-special").unwrap();
+        fs::write(origin.path().join("target"), "target").unwrap();
+        std::os::unix::fs::symlink("target", origin.path().join("link")).unwrap();
+        let fifo = CString::new(origin.path().join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let mut rfs = proxy(origin.path());
+        for (name, kind) in [("link", FileType::Symlink), ("fifo", FileType::NamedPipe)] {
+            assert_eq!(lookup(&mut rfs, name).kind, kind);
+            rfs.remove(FUSE_ROOT_ID, OsStr::new(name)).unwrap();
+
+            assert!(fs::symlink_metadata(origin.path().join(name)).is_err());
+            assert_eq!(
+                rfs.find_entry(FUSE_ROOT_ID, OsStr::new(name)),
+                Err(FuseError::NO_EXIST)
+            );
+        }
+        // The link goes, not what it points to
+        assert_eq!(fs::read(origin.path().join("target")).unwrap(), b"target");
+    }
+
+    #[test]
+    fn large_read_returns_the_whole_file() {
+        let origin = TempDir::new("rvfs-large-read").unwrap();
+        let content = (0..8 << 20)
+            .map(|i: u32| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        fs::write(origin.path().join("large"), &content).unwrap();
+
+        let config = Config {
+            max_read_chunk: 64 << 10,
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+        let attr = lookup(&mut rfs, "large");
+        let fh = rfs.allocate_fh(attr.ino, true, false).unwrap();
+
+        // Asking for more than there is stops at the end of the file
+        let read = rfs.read_file(attr.ino, fh, 0, 16 << 20).unwrap();
+        assert!(read == content, "{} bytes read", read.len());
+        let read = rfs.read_file(attr.ino, fh, 3 << 20, 1 << 20).unwrap();
+        assert!(read == content[3 << 20..4 << 20]);
+        rfs.release_fh(attr.ino, fh).unwrap();
+    }
+
+    #[test]
+    fn stats_poll_reports_new_detections() {
+        let origin = TempDir::new("rvfs-poll").unwrap();
+        fs::write(origin.path().join("planted"), "clean").unwrap();
+
+        let mut rfs = scanned_proxy(origin.path(), Config::default());
+        let stats = lookup(&mut rfs, STATS_FILE_NAME);
+        let (kh, schedule) = (7, consts::FUSE_POLL_SCHEDULE_NOTIFY);
+        let ready = (libc::POLLIN | libc::POLLRDNORM) as u32;
+        let changed = ready | libc::POLLPRI as u32;
+        assert_eq!(rfs.poll_events(stats.ino, kh, schedule), Ok(ready));
+
+        fs::write(origin.path().join("planted"), EICAR).unwrap();
+        rfs.control(b"rescan /").unwrap();
+        assert_eq!(rfs.poll_events(stats.ino, kh, schedule), Ok(changed));
+        // Reported once, until the next detection
+        assert_eq!(rfs.poll_events(stats.ino, kh, schedule), Ok(ready));
+
+        // Origin items are always ready, the stats have nothing to do with them
+        let origin_events = rfs.poll_events(FUSE_ROOT_ID, kh, schedule).unwrap();
+        assert_eq!(origin_events & libc::POLLPRI as u32, 0);
+    }
+
+    #[test]
+    fn nested_mounts_are_not_followed() {
+        let origin = TempDir::new("rvfs-nested-mount").unwrap();
+        let nested = origin.path().join("nested");
+        fs::create_dir(&nested).unwrap();
+        let _tmpfs = match Tmpfs::mount(&nested, "64k") {
+            Ok(tmpfs) => tmpfs,
+            Err(err) => {
+                eprintln!("Skipped, tmpfs can't be mounted: {err}");
+                return;
+            }
+        };
+        fs::write(nested.join("file"), "nested").unwrap();
+
+        for follow_mounts in [false, true] {
+            let config = Config {
+                follow_mounts,
+                ..Config::default()
+            };
+            let mut rfs = proxy_with(origin.path(), config, Arc::new(NullScanner));
+            // The mountpoint itself stays, its content is on another file system
+            let dir = lookup(&mut rfs, "nested");
+            let names = listing(&mut rfs, dir.ino);
+            assert_eq!(names.is_empty(), !follow_mounts, "{names:?}");
+        }
+    }
+
+    #[test]
+    fn dir_handles_keep_their_listing() {
+        let origin = TempDir::new("rvfs-dir-handles").unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(origin.path().join(name), name).unwrap();
+        }
+
+        let mut rfs = proxy(origin.path());
+        let names = |entries: &[DirEntry]| {
+            entries
+                .iter()
+                .map(|entry| entry.name.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        let first = rfs.open_dir(FUSE_ROOT_ID).unwrap();
+        let entries = &rfs.dir_entries(FUSE_ROOT_ID, first, 0).unwrap()[..4];
+        let (head, offset) = (names(entries), entries[3].offset);
+
+        // The origin is read once per listing, the rest of it comes from the handle
+        fs::write(origin.path().join("late"), "late").unwrap();
+        let second = rfs.open_dir(FUSE_ROOT_ID).unwrap();
+        assert!(names(rfs.dir_entries(FUSE_ROOT_ID, second, 0).unwrap()).contains(&"late".into()));
+        let tail = names(rfs.dir_entries(FUSE_ROOT_ID, first, offset).unwrap());
+        assert!(!tail.contains(&"late".into()), "{tail:?}");
+        assert_eq!(head.len() + tail.len(), 2 + 2 + 3);
+
+        rfs.release_dir(first).unwrap();
+        assert_eq!(
+            rfs.dir_entries(FUSE_ROOT_ID, first, offset).err(),
+            Some(FuseError::BAD_FD)
+        );
+        assert_eq!(rfs.release_dir(first), Err(FuseError::BAD_FD));
+        rfs.release_dir(second).unwrap();
+    }
+
+    #[test]
+    fn origin_xattr_has_the_origin_path() {
+        let origin = TempDir::new("rvfs-xattr").unwrap();
+        fs::create_dir(origin.path().join("dir")).unwrap();
+        fs::write(origin.path().join("dir/file"), "file").unwrap();
+
+        let mut rfs = proxy(origin.path());
+        let dir = lookup(&mut rfs, "dir");
+        listing(&mut rfs, dir.ino);
+        let (file, _) = rfs.lookup_entry(dir.ino, OsStr::new("file")).unwrap();
+
+        let name = OsStr::new(ORIGIN_XATTR);
+        let origin_path = rfs.xattr(file.ino, name).unwrap();
+        assert_eq!(
+            Path::new(OsStr::from_bytes(&origin_path)),
+            origin.path().canonicalize().unwrap().join("dir/file")
+        );
+        assert_eq!(rfs.xattr_names(file.ino).unwrap(), b"user.rvfs.origin\0");
+        assert_eq!(
+            rfs.ensure_xattr_writable(name),
+            Err(FuseError::OPERATION_NOT_PERMITTED)
+        );
+
+        // Virtual files have no origin, other attributes aren't exposed
+        let stats = lookup(&mut rfs, STATS_FILE_NAME);
+        assert_eq!(rfs.xattr(stats.ino, name), Err(FuseError::NO_DATA));
+        assert_eq!(rfs.xattr_names(stats.ino).unwrap(), b"");
+        assert_eq!(
+            rfs.xattr(file.ino, OsStr::new("user.other")),
+            Err(FuseError::NO_DATA)
+        );
+    }
+
+    #[test]
+    fn work_dir_holds_the_overlay() {
+        let origin = TempDir::new("rvfs-work-origin").unwrap();
+        let work_dir = TempDir::new("rvfs-work-dir").unwrap();
+
+        let config = Config {
+            overlay: true,
+            work_dir: Some(work_dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut rfs = proxy_with(origin.path(), config.clone(), Arc::new(NullScanner));
+        rfs.create(
+            OsStr::new("created"),
+            FUSE_ROOT_ID,
+            0o644,
+            0,
+            FileType::RegularFile,
+        )
+        .unwrap();
+        let upper = rfs.overlay.as_ref().unwrap().root().to_path_buf();
+        assert!(upper.starts_with(work_dir.path()), "{upper:?}");
+        assert!(upper.join("created").exists());
+        drop(rfs);
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0);
+
+        let missing = work_dir.path().join("missing");
+        let config = Config {
+            work_dir: Some(missing.clone()),
+            source_type: SourceType::Dir,
+            no_scan: true,
+            ..config
+        };
+        let sources = vec![(origin.path().to_path_buf(), PathBuf::new())];
+        let err = Rfs::new(sources, PathBuf::from("/proxy"), config)
+            .err()
+            .unwrap();
+        assert!(
+            format!("{err:#}").contains(&*missing.to_string_lossy()),
+            "{err:#}"
+        );
+    }
 }
@@ -1,25 +1,603 @@
-use std::path::Path;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
 use clamav_rs::{
     db,
     engine::{Engine, ScanResult},
+    fmap::Fmap,
     scan_settings::{ScanSettings, ScanSettingsBuilder},
 };
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use tempdir::TempDir;
+
+use crate::{
+    allow::AllowList,
+    archive,
+    cache::{BoundedCache, CacheStats},
+    config::Config,
+};
+
+/// Standard antivirus test file, every engine with signatures loaded detects it.
+pub(crate) const EICAR: &str =
+    r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+/// Detection reported for files blocked by [`OversizedPolicy::Block`].
+const OVERSIZED_SIGNATURE: &str = "rvfs.Oversized";
+
+/// Detection reported for files blocked by [`TimeoutPolicy::Block`].
+const TIMEOUT_SIGNATURE: &str = "rvfs.ScanTimeout";
+
+/// Detection reported for archives over [`ArchiveLimits::max_ratio`].
+const ARCHIVE_RATIO_SIGNATURE: &str = "rvfs.ArchiveRatio";
+
+/// What happens to files whose scan takes longer than the scan timeout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPolicy {
+    /// Treats them as clean.
+    Allow,
+    /// Blocks them the same way as infected files, a malformed file is the usual cause.
+    #[default]
+    Block,
+}
+
+impl TimeoutPolicy {
+    fn result(self) -> ScanResult {
+        match self {
+            TimeoutPolicy::Allow => ScanResult::Clean,
+            TimeoutPolicy::Block => ScanResult::Virus(TIMEOUT_SIGNATURE.to_owned()),
+        }
+    }
+}
+
+impl FromStr for TimeoutPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(TimeoutPolicy::Allow),
+            "block" => Ok(TimeoutPolicy::Block),
+            other => bail!("{other:?} is not a valid timeout policy(expected allow or block)"),
+        }
+    }
+}
+
+/// Bounds of archive unpacking, archives that go over them are blocked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArchiveLimits {
+    /// How deep archives nested in archives are unpacked.
+    pub max_depth: u32,
+    /// How many files are unpacked from a single archive.
+    pub max_files: u32,
+    /// Unpacked to packed size ratio, checked against sizes zip and gzip declare, `0` means no
+    /// limit.
+    pub max_ratio: f64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 16,
+            max_files: 10_000,
+            max_ratio: 100.0,
+        }
+    }
+}
+
+/// What happens to files larger than the maximum scan size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedPolicy {
+    /// Treats them as clean.
+    #[default]
+    Allow,
+    /// Blocks them the same way as infected files.
+    Block,
+}
+
+impl OversizedPolicy {
+    fn result(self) -> ScanResult {
+        match self {
+            OversizedPolicy::Allow => ScanResult::Clean,
+            OversizedPolicy::Block => ScanResult::Virus(OVERSIZED_SIGNATURE.to_owned()),
+        }
+    }
+}
+
+impl FromStr for OversizedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(OversizedPolicy::Allow),
+            "block" => Ok(OversizedPolicy::Block),
+            other => bail!("{other:?} is not a valid oversized policy(expected allow or block)"),
+        }
+    }
+}
+
+/// When listed files are scanned.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// Scans all items of a directory once it's listed.
+    #[default]
+    Eager,
+    /// Scans a file the first time it's opened.
+    Lazy,
+    /// Trusts files found on the origin, only content written through the proxy is scanned.
+    NewOnly,
+}
+
+impl FromStr for ScanMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eager" => Ok(ScanMode::Eager),
+            "lazy" => Ok(ScanMode::Lazy),
+            other => bail!("{other:?} is not a valid scan mode(expected eager or lazy)"),
+        }
+    }
+}
+
+/// Kinds of files that go through the engine, all other files are treated as clean.
+///
+/// Files are told apart by their leading bytes, names and extensions are ignored:
+/// - `exec`: ELF, PE(`MZ`), Mach-O and scripts with a `#!` interpreter line;
+/// - `archive`: zip(including jar, apk and office open xml), gzip, bzip2, xz, zstd, 7z, rar,
+///   tar, cab and OLE2 compound documents(legacy office files, msi);
+/// - `all`: every file, the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanTypes {
+    all: bool,
+    exec: bool,
+    archive: bool,
+}
+
+impl ScanTypes {
+    /// Bytes needed to tell all known types apart, tar magic is the farthest one.
+    const HEADER_SIZE: usize = 262;
+
+    const EXEC_MAGICS: &'static [&'static [u8]] = &[
+        b"\x7fELF",
+        b"MZ",
+        b"#!",
+        b"\xfe\xed\xfa\xce",
+        b"\xfe\xed\xfa\xcf",
+        b"\xce\xfa\xed\xfe",
+        b"\xcf\xfa\xed\xfe",
+        b"\xca\xfe\xba\xbe",
+    ];
+
+    const ARCHIVE_MAGICS: &'static [&'static [u8]] = &[
+        b"PK\x03\x04",
+        b"PK\x05\x06",
+        b"PK\x07\x08",
+        b"\x1f\x8b",
+        b"BZh",
+        b"\xfd7zXZ\x00",
+        b"\x28\xb5\x2f\xfd",
+        b"7z\xbc\xaf\x27\x1c",
+        b"Rar!\x1a\x07",
+        b"MSCF",
+        b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1",
+    ];
+
+    /// Whether the file has to be scanned, files that can't be read are scanned to let the
+    /// engine report the error.
+    fn matches(&self, path: &Path) -> bool {
+        if self.all {
+            return true;
+        }
+
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE);
+        let read = File::open(path)
+            .and_then(|file| file.take(Self::HEADER_SIZE as u64).read_to_end(&mut header));
+        if read.is_err() {
+            return true;
+        }
+
+        let has_magic = |magics: &[&[u8]]| magics.iter().any(|magic| header.starts_with(magic));
+        let is_tar = header.get(257..262) == Some(b"ustar");
+
+        (self.exec && has_magic(Self::EXEC_MAGICS))
+            || (self.archive && (has_magic(Self::ARCHIVE_MAGICS) || is_tar))
+    }
+}
+
+impl Default for ScanTypes {
+    fn default() -> Self {
+        Self {
+            all: true,
+            exec: false,
+            archive: false,
+        }
+    }
+}
+
+/// Comma separated list of types, e.g. `exec,archive`.
+impl FromStr for ScanTypes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut types = Self {
+            all: false,
+            exec: false,
+            archive: false,
+        };
+
+        for scan_type in s.split(',') {
+            match scan_type.trim() {
+                "all" => types.all = true,
+                "exec" => types.exec = true,
+                "archive" => types.archive = true,
+                other => bail!("{other:?} is not a valid scan type(expected exec, archive or all)"),
+            }
+        }
+
+        Ok(types)
+    }
+}
+
+/// Upper bounds of the scan latency histogram buckets, the last bucket has no bound.
+const LATENCY_BUCKETS: [Duration; 9] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Scan counters since mount, verdicts served from the cache included.
+#[derive(Debug, Default, Serialize)]
+pub struct ScanStats {
+    /// Files that went through the engine.
+    scanned: AtomicU64,
+    clean: AtomicU64,
+    whitelisted: AtomicU64,
+    infected: AtomicU64,
+    /// Files skipped because of the maximum scan size.
+    oversized: AtomicU64,
+    /// Files skipped because their type isn't among the scanned ones.
+    skipped_type: AtomicU64,
+    /// Scans abandoned because of the scan timeout.
+    timed_out: AtomicU64,
+    failed: AtomicU64,
+    /// Time spent in the engine.
+    scan_time_us: AtomicU64,
+    /// Engine scans by the first [`LATENCY_BUCKETS`] bound they fit in.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl ScanStats {
+    fn record(&self, result: &anyhow::Result<ScanResult>) {
+        let counter = match result {
+            Ok(ScanResult::Clean) => &self.clean,
+            Ok(ScanResult::Whitelisted) => &self.whitelisted,
+            Ok(ScanResult::Virus(_)) => &self.infected,
+            Err(_) => &self.failed,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_scan(&self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| elapsed <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+
+        self.scanned.fetch_add(1, Ordering::Relaxed);
+        self.scan_time_us.fetch_add(
+            u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ScanStats {
+    /// Counters in the Prometheus text format.
+    pub fn write_metrics(&self, out: &mut String) {
+        use std::fmt::Write;
+
+        let counters = [
+            (
+                "rvfs_scans_total",
+                "Files scanned by the engine.",
+                &self.scanned,
+            ),
+            (
+                "rvfs_detections_total",
+                "Infected verdicts.",
+                &self.infected,
+            ),
+            ("rvfs_clean_total", "Clean verdicts.", &self.clean),
+            (
+                "rvfs_whitelisted_total",
+                "Whitelisted verdicts.",
+                &self.whitelisted,
+            ),
+            (
+                "rvfs_scan_timeouts_total",
+                "Scans abandoned because of the scan timeout.",
+                &self.timed_out,
+            ),
+            (
+                "rvfs_scan_failures_total",
+                "Scans that failed.",
+                &self.failed,
+            ),
+            (
+                "rvfs_oversized_total",
+                "Files above the maximum scan size.",
+                &self.oversized,
+            ),
+            (
+                "rvfs_skipped_type_total",
+                "Files of types that aren't scanned.",
+                &self.skipped_type,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let name = "rvfs_scan_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Time files spent in the engine.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (index, bucket) in self.latency_buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS
+                .get(index)
+                .map_or("+Inf".to_owned(), |bound| bound.as_secs_f64().to_string());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let scan_time = Duration::from_micros(self.scan_time_us.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_sum {}", scan_time.as_secs_f64());
+        let _ = writeln!(out, "{name}_count {cumulative}");
+    }
+}
+
+impl Display for ScanStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scan_time = Duration::from_micros(self.scan_time_us.load(Ordering::Relaxed));
+
+        writeln!(f, "scanned: {}", self.scanned.load(Ordering::Relaxed))?;
+        writeln!(f, "clean: {}", self.clean.load(Ordering::Relaxed))?;
+        writeln!(
+            f,
+            "whitelisted: {}",
+            self.whitelisted.load(Ordering::Relaxed)
+        )?;
+        writeln!(f, "infected: {}", self.infected.load(Ordering::Relaxed))?;
+        writeln!(f, "oversized: {}", self.oversized.load(Ordering::Relaxed))?;
+        writeln!(
+            f,
+            "skipped by type: {}",
+            self.skipped_type.load(Ordering::Relaxed)
+        )?;
+        writeln!(f, "timed out: {}", self.timed_out.load(Ordering::Relaxed))?;
+        writeln!(f, "failed: {}", self.failed.load(Ordering::Relaxed))?;
+        writeln!(f, "scan time: {:.3}s", scan_time.as_secs_f64())
+    }
+}
+
+/// Identifies a file version, so a verdict is reused only while the content stays the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ScanKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl ScanKey {
+    fn new(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+
+        Some(Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        })
+    }
+}
+
+/// Checks content of origin files, shared between the FUSE session and signal handlers.
+pub trait Scanner: Send + Sync {
+    fn scan(&self, path: &Path) -> anyhow::Result<ScanResult>;
+
+    /// Scans content that isn't on disk yet, e.g. writes that are still buffered. There is no
+    /// file behind it, so the scan cache and `--scan-types` don't apply.
+    fn scan_bytes(&self, data: &[u8]) -> anyhow::Result<ScanResult>;
+
+    fn signature_count(&self) -> u32;
+
+    fn cache_stats(&self) -> CacheStats;
+
+    /// Human readable scan statistics since mount.
+    fn report(&self) -> String;
+
+    /// Scan statistics since mount as a JSON object, see `--json`.
+    fn report_json(&self) -> String {
+        let report = json!({
+            "signatures": self.signature_count(),
+            "cache": self.cache_stats(),
+            "stats": self.stats(),
+        });
+
+        format!("{report:#}\n")
+    }
+
+    /// Counters behind the report, `None` if nothing is scanned.
+    fn stats(&self) -> Option<&ScanStats>;
+
+    /// Loads and compiles the signature databases again, returns the new signature count.
+    fn reload(&self) -> anyhow::Result<u32>;
+
+    /// Engine scans that haven't finished yet, ones abandoned because of the scan timeout
+    /// included.
+    fn in_flight(&self) -> usize;
+}
+
+/// Token bucket of `--scan-rate`: it holds up to `rate` scans and refills at `rate` scans per
+/// second. Scans over the rate wait for their turn rather than being skipped.
+#[derive(Debug)]
+struct ScanRate {
+    /// Time between two scans at the steady rate.
+    interval: Duration,
+    /// How far ahead of the steady rate scans may start, it's the bucket size.
+    burst: Duration,
+    /// When the next scan would start at the steady rate.
+    next: Mutex<Instant>,
+}
+
+impl ScanRate {
+    fn new(rate: u32) -> Self {
+        let interval = Duration::from_secs(1) / rate;
+
+        Self {
+            interval,
+            burst: interval * (rate - 1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until a scan may start, concurrent scans get consecutive turns.
+    fn wait(&self) {
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let steady = (*next).max(now);
+            *next = steady + self.interval;
+
+            steady
+                .checked_sub(self.burst)
+                .map_or(now, |start| start.max(now))
+        };
+
+        let delay = start.saturating_duration_since(now);
+        if !delay.is_zero() {
+            debug!("Delaying a scan by {delay:?} to stay within the scan rate");
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Counts a running engine scan until it's dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(counter))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Scanner of `--no-scan` mode, every file is clean and ClamAV is never initialized.
+#[derive(Debug, Default)]
+pub struct NullScanner;
+
+impl Scanner for NullScanner {
+    fn scan(&self, _path: &Path) -> anyhow::Result<ScanResult> {
+        Ok(ScanResult::Clean)
+    }
+
+    fn scan_bytes(&self, _data: &[u8]) -> anyhow::Result<ScanResult> {
+        Ok(ScanResult::Clean)
+    }
+
+    fn signature_count(&self) -> u32 {
+        0
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+
+    fn report(&self) -> String {
+        "scanning is disabled(--no-scan)\n".to_owned()
+    }
+
+    fn stats(&self) -> Option<&ScanStats> {
+        None
+    }
+
+    fn reload(&self) -> anyhow::Result<u32> {
+        Ok(0)
+    }
+
+    fn in_flight(&self) -> usize {
+        0
+    }
+}
 
 pub struct ClamAV {
-    engine: Engine,
-    settings: ScanSettings,
+    /// Every scan takes its own reference, so a reload swaps the engine without waiting for scans
+    /// and an abandoned scan keeps using the engine it started with.
+    engine: RwLock<Arc<Engine>>,
+    signature_count: AtomicU32,
+    /// Every scan builds its own settings, so concurrent scans don't wait for each other.
+    settings: ScanSettingsBuilder,
+    cache: Mutex<BoundedCache<ScanKey, ScanResult>>,
+    max_scan_size: Option<u64>,
+    oversized_policy: OversizedPolicy,
+    scan_types: ScanTypes,
+    archive_limits: ArchiveLimits,
+    allow_hash_file: Option<PathBuf>,
+    /// Replaced on reload together with the engine.
+    allow_list: RwLock<AllowList>,
+    /// Held for the whole reload, so reloads of SIGHUP and `--auto-update` don't overlap.
+    reloading: Mutex<()>,
+    scan_timeout: Option<Duration>,
+    timeout_policy: TimeoutPolicy,
+    /// Shared with scan threads, an abandoned scan stays counted until its thread ends.
+    in_flight: Arc<AtomicUsize>,
+    scan_rate: Option<ScanRate>,
+    stats: ScanStats,
 }
 
 impl ClamAV {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
         clamav_rs::initialize().map_err(Into::<anyhow::Error>::into)?;
 
-        let engine = Engine::new();
-        engine.load_databases(&db::default_directory())?;
-        engine.compile()?;
+        let allow_list = load_allow_list(config.allow_hash_file.as_deref())?;
+        let (engine, signature_count) = load_engine(&config.archive_limits)?;
+        info!("Loaded {signature_count} signatures");
 
-        let settings = ScanSettingsBuilder::new()
+        let mut settings = ScanSettingsBuilder::new();
+        settings
             .enable_archive()
             .enable_mail()
             .enable_ole2()
@@ -33,14 +611,637 @@ impl ClamAV {
             .block_macros()
             .enable_xmldocs()
             .enable_hwp3()
-            .build();
+            .block_max_limits();
+
+        let cache = BoundedCache::new(config.scan_cache_size, config.scan_cache_policy);
+
+        Ok(Self {
+            engine: RwLock::new(Arc::new(engine)),
+            signature_count: AtomicU32::new(signature_count),
+            settings,
+            cache: Mutex::new(cache),
+            max_scan_size: config.max_scan_size,
+            oversized_policy: config.oversized_policy,
+            scan_types: config.scan_types,
+            archive_limits: config.archive_limits,
+            allow_hash_file: config.allow_hash_file.clone(),
+            allow_list: RwLock::new(allow_list),
+            reloading: Mutex::new(()),
+            scan_timeout: config.scan_timeout,
+            scan_rate: config.scan_rate.map(ScanRate::new),
+            timeout_policy: config.timeout_policy,
+            in_flight: Arc::default(),
+            stats: ScanStats::default(),
+        })
+    }
+
+    fn scan_uncounted(&self, path: &Path) -> anyhow::Result<ScanResult> {
+        let key = ScanKey::new(path);
+
+        if let Some(max_scan_size) = self.max_scan_size {
+            if let Some(size) = key
+                .as_ref()
+                .map(|key| key.size)
+                .filter(|&size| size > max_scan_size)
+            {
+                debug!("Skipped scanning {path:?}: {size} bytes is above {max_scan_size} limit");
+                self.stats.oversized.fetch_add(1, Ordering::Relaxed);
+                return Ok(self.oversized_policy.result());
+            }
+        }
+
+        if !self.scan_types.matches(path) {
+            debug!("Skipped scanning {path:?}: its type isn't scanned");
+            self.stats.skipped_type.fetch_add(1, Ordering::Relaxed);
+            return Ok(ScanResult::Clean);
+        }
+
+        if let Some(key) = key.as_ref() {
+            if let Some(result) = self.cache.lock().unwrap().get(key) {
+                return Ok(result.clone());
+            }
+        }
+
+        if self.exceeds_ratio(path, key.as_ref().map(|key| key.size)) {
+            return Ok(ScanResult::Virus(ARCHIVE_RATIO_SIGNATURE.to_owned()));
+        }
+
+        if let Some(scan_rate) = self.scan_rate.as_ref() {
+            scan_rate.wait();
+        }
+
+        let started = Instant::now();
+        // ClamAV takes UTF-8 paths only, such files can't be scanned and aren't served
+        let Some(file) = path.to_str().map(str::to_owned) else {
+            bail!("{path:?} isn't valid UTF-8");
+        };
+        let result = self.scan_engine(&path, move |engine, settings| {
+            Ok(engine.scan_file(&file, settings)?)
+        });
+        self.stats.record_scan(started.elapsed());
+        let result = match result? {
+            ScanResult::Virus(signature) if self.is_allowed(path) => {
+                info!("{path:?} has an allowed hash, ignoring {signature} detection");
+                ScanResult::Whitelisted
+            }
+            result => result,
+        };
+
+        if let Some(key) = key {
+            self.cache.lock().unwrap().insert(key, result.clone());
+        }
+
+        Ok(result)
+    }
+
+    fn scan_bytes_uncounted(&self, data: &[u8]) -> anyhow::Result<ScanResult> {
+        let size = data.len() as u64;
+        if let Some(max_scan_size) = self.max_scan_size.filter(|&max| size > max) {
+            debug!("Skipped scanning {size} buffered bytes: above {max_scan_size} limit");
+            self.stats.oversized.fetch_add(1, Ordering::Relaxed);
+            return Ok(self.oversized_policy.result());
+        }
+
+        if let Some(scan_rate) = self.scan_rate.as_ref() {
+            scan_rate.wait();
+        }
+
+        let started = Instant::now();
+        // Owned, a scan that runs out of time outlives the caller's buffer
+        let content = data.to_vec();
+        let result = self.scan_engine(
+            &format_args!("{size} buffered bytes"),
+            move |engine, settings| {
+                Ok(engine.scan_map(&Fmap::from(content.as_slice()), None, settings)?)
+            },
+        );
+        self.stats.record_scan(started.elapsed());
+
+        match result? {
+            ScanResult::Virus(signature) if self.allow_list.read().unwrap().allows_data(data) => {
+                info!("Buffered content has an allowed hash, ignoring {signature} detection");
+                Ok(ScanResult::Whitelisted)
+            }
+            result => Ok(result),
+        }
+    }
+
+    /// Runs a scan with the engine, on a thread of its own if there is a scan timeout.
+    ///
+    /// The thread of a scan that runs out of time is left to finish on its own, its verdict is
+    /// dropped in favor of the timeout policy one.
+    fn scan_engine<F>(&self, subject: &dyn fmt::Debug, scan: F) -> anyhow::Result<ScanResult>
+    where
+        F: FnOnce(&Engine, &mut ScanSettings) -> anyhow::Result<ScanResult> + Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine.read().unwrap());
+        let mut settings = self.settings.build();
+        let in_flight = InFlight::start(&self.in_flight);
+
+        let Some(scan_timeout) = self.scan_timeout else {
+            return scan(&engine, &mut settings);
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("rvfs-scan".to_owned())
+            .spawn(move || {
+                let _in_flight = in_flight;
+                let _ = sender.send(scan(&engine, &mut settings));
+            })
+            .context("Failed to spawn scan thread")?;
+
+        match receiver.recv_timeout(scan_timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(
+                    "Scan of {subject:?} took longer than {scan_timeout:?}, applying {:?} policy",
+                    self.timeout_policy
+                );
+                self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                Ok(self.timeout_policy.result())
+            }
+            Err(RecvTimeoutError::Disconnected) => bail!("Scan thread of {subject:?} panicked"),
+        }
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.allow_list
+            .read()
+            .unwrap()
+            .allows(path)
+            .unwrap_or_else(|err| {
+                error!("Failed to hash {path:?}: {err}");
+                false
+            })
+    }
+
+    /// Whether the archive declares more content than the maximum ratio allows for its size.
+    fn exceeds_ratio(&self, path: &Path, size: Option<u64>) -> bool {
+        let max_ratio = self.archive_limits.max_ratio;
+        let Some(size) = size.filter(|&size| size > 0 && max_ratio > 0.0) else {
+            return false;
+        };
+
+        match archive::declared_size(path) {
+            Ok(Some(unpacked)) if unpacked as f64 > size as f64 * max_ratio => {
+                info!("{path:?} unpacks to {unpacked} bytes from {size}, above {max_ratio} ratio");
+                true
+            }
+            Ok(_) => false,
+            Err(err) => {
+                debug!("Failed to get unpacked size of {path:?}: {err}");
+                false
+            }
+        }
+    }
+}
+
+impl Scanner for ClamAV {
+    fn scan(&self, path: &Path) -> anyhow::Result<ScanResult> {
+        let result = self.scan_uncounted(path);
+        self.stats.record(&result);
+
+        result
+    }
+
+    fn scan_bytes(&self, data: &[u8]) -> anyhow::Result<ScanResult> {
+        let result = self.scan_bytes_uncounted(data);
+        self.stats.record(&result);
+
+        result
+    }
+
+    fn signature_count(&self) -> u32 {
+        self.signature_count.load(Ordering::Relaxed)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    fn report(&self) -> String {
+        format!(
+            "{}signatures: {}\ncache: {}\n",
+            self.stats,
+            self.signature_count.load(Ordering::Relaxed),
+            self.cache_stats()
+        )
+    }
+
+    fn stats(&self) -> Option<&ScanStats> {
+        Some(&self.stats)
+    }
+
+    /// The current engine keeps serving scans until the new one is ready. Allowed hashes are
+    /// read again as well.
+    fn reload(&self) -> anyhow::Result<u32> {
+        let _reloading = self.reloading.lock().unwrap();
+        let allow_list = load_allow_list(self.allow_hash_file.as_deref())?;
+        let (engine, signature_count) = load_engine(&self.archive_limits)?;
+
+        *self.engine.write().unwrap() = Arc::new(engine);
+        *self.allow_list.write().unwrap() = allow_list;
+        let previous_count = self
+            .signature_count
+            .swap(signature_count, Ordering::Relaxed);
+        // Verdicts of the previous signatures are outdated
+        self.cache.lock().unwrap().clear();
+
+        info!("Reloaded signatures: {previous_count} before, {signature_count} now");
+
+        Ok(signature_count)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Waits up to `timeout` for running engine scans to finish, so none is cut off by the unmount.
+///
+/// Returns how many scans were running at first, or how many are still running once the timeout
+/// is reached.
+pub fn wait_for_scans(scanner: &dyn Scanner, timeout: Duration) -> Result<usize, usize> {
+    let deadline = Instant::now() + timeout;
+    let running = scanner.in_flight();
 
-        Ok(Self { engine, settings })
+    loop {
+        let in_flight = scanner.in_flight();
+        if in_flight == 0 {
+            return Ok(running);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(in_flight);
+        }
+
+        debug!("Waiting for {in_flight} scans to finish...");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Loads the engine the same way a mount does and scans the EICAR test file with it.
+pub fn check(config: &Config) -> anyhow::Result<()> {
+    // Type filtering would skip EICAR, it's text
+    let config = Config {
+        scan_types: ScanTypes::default(),
+        ..config.clone()
+    };
+    let scanner = ClamAV::new(&config).context("Failed to set up ClamAV")?;
+    if !config.json {
+        println!("signatures: {}", scanner.signature_count());
+    }
+
+    let dir = TempDir::new("rvfs-check").context("Failed to create EICAR directory")?;
+    let eicar = dir.path().join("eicar.com");
+    fs::write(&eicar, EICAR).with_context(|| format!("Failed to write {eicar:?}"))?;
+
+    let signature = match scanner.scan(&eicar).context("Failed to scan EICAR")? {
+        ScanResult::Virus(signature) => Some(signature),
+        ScanResult::Clean | ScanResult::Whitelisted => None,
+    };
+    // Written data is scanned from memory before it reaches the origin
+    let memory_signature = match scanner
+        .scan_bytes(EICAR.as_bytes())
+        .context("Failed to scan EICAR in memory")?
+    {
+        ScanResult::Virus(signature) => Some(signature),
+        ScanResult::Clean | ScanResult::Whitelisted => None,
+    };
+
+    if config.json {
+        let report = json!({
+            "signatures": scanner.signature_count(),
+            "eicar_detected": signature.is_some(),
+            "eicar_signature": signature,
+            "eicar_memory_detected": memory_signature.is_some(),
+        });
+        println!("{report:#}");
+    } else {
+        match signature.as_ref() {
+            Some(signature) => println!("EICAR: detected as {signature}"),
+            None => println!("EICAR: not detected"),
+        }
+        match memory_signature.as_ref() {
+            Some(signature) => println!("EICAR in memory: detected as {signature}"),
+            None => println!("EICAR in memory: not detected"),
+        }
+    }
+
+    if signature.is_none() || memory_signature.is_none() {
+        bail!("EICAR wasn't detected, check the signature databases");
+    }
+
+    Ok(())
+}
+
+fn load_allow_list(path: Option<&Path>) -> anyhow::Result<AllowList> {
+    let Some(path) = path else {
+        return Ok(AllowList::default());
+    };
+
+    let allow_list = AllowList::load(path)?;
+    info!("Loaded {} allowed hashes", allow_list.len());
+
+    Ok(allow_list)
+}
+
+fn load_engine(archive_limits: &ArchiveLimits) -> anyhow::Result<(Engine, u32)> {
+    let engine = Engine::new();
+    engine.set_max_recursion(archive_limits.max_depth)?;
+    engine.set_max_files(archive_limits.max_files)?;
+    let stats = engine.load_databases(&db::default_directory())?;
+    engine.compile()?;
+
+    Ok((engine, stats.signature_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClamAV with the system signature databases, `None` if it can't be set up here.
+    fn clamav(config: &Config) -> Option<ClamAV> {
+        match ClamAV::new(config) {
+            Ok(scanner) => Some(scanner),
+            Err(err) => {
+                eprintln!("Skipped, ClamAV can't be set up: {err:#}");
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn eicar_is_detected_in_memory() {
+        let Some(scanner) = clamav(&Config::default()) else {
+            return;
+        };
+        if scanner.signature_count() == 0 {
+            eprintln!("Skipped, there are no signature databases");
+            return;
+        }
+
+        let verdict = scanner.scan_bytes(EICAR.as_bytes()).unwrap();
+        assert!(matches!(verdict, ScanResult::Virus(_)), "{verdict:?}");
+        let verdict = scanner.scan_bytes(b"clean").unwrap();
+        assert!(matches!(verdict, ScanResult::Clean), "{verdict:?}");
+    }
+
+    #[test]
+    fn allowed_eicar_is_whitelisted() {
+        let dir = TempDir::new("rvfs-allowed-eicar").unwrap();
+        let eicar = dir.path().join("eicar.com");
+        fs::write(&eicar, EICAR).unwrap();
+        let allow_file = dir.path().join("allowed");
+        let hash = "275a021bbfb6489e54d471899f7db9d1663fc695ec2fe2a2c4538aabf651fd0f";
+        fs::write(&allow_file, hash).unwrap();
+
+        let config = Config {
+            allow_hash_file: Some(allow_file),
+            ..Config::default()
+        };
+        let Some(scanner) = clamav(&config) else {
+            return;
+        };
+        if scanner.signature_count() == 0 {
+            eprintln!("Skipped, there are no signature databases");
+            return;
+        }
+
+        let verdict = scanner.scan(&eicar).unwrap();
+        assert!(matches!(verdict, ScanResult::Whitelisted), "{verdict:?}");
     }
 
-    pub fn scan(&mut self, path: &Path) -> anyhow::Result<ScanResult> {
-        self.engine
-            .scan_file(path.as_os_str().to_str().unwrap(), &mut self.settings)
-            .map_err(Into::into)
+    #[test]
+    fn oversized_files_get_the_policy_verdict() {
+        let dir = TempDir::new("rvfs-oversized").unwrap();
+        let large = dir.path().join("large");
+        fs::write(&large, [0; 2048]).unwrap();
+        let small = dir.path().join("small");
+        fs::write(&small, "small").unwrap();
+
+        for (policy, expected) in [
+            (OversizedPolicy::Block, Some(OVERSIZED_SIGNATURE)),
+            (OversizedPolicy::Allow, None),
+        ] {
+            let config = Config {
+                max_scan_size: Some(1024),
+                oversized_policy: policy,
+                ..Config::default()
+            };
+            let Some(scanner) = clamav(&config) else {
+                return;
+            };
+
+            let signature = match scanner.scan(&large).unwrap() {
+                ScanResult::Virus(signature) => Some(signature),
+                ScanResult::Clean | ScanResult::Whitelisted => None,
+            };
+            assert_eq!(signature.as_deref(), expected);
+            // Files within the limit go through the engine
+            let verdict = scanner.scan(&small).unwrap();
+            assert!(matches!(verdict, ScanResult::Clean), "{verdict:?}");
+
+            assert_eq!(scanner.stats.oversized.load(Ordering::Relaxed), 1);
+            assert_eq!(scanner.stats.scanned.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn only_executables_are_scanned() {
+        let dir = TempDir::new("rvfs-scan-types").unwrap();
+        let files = [
+            ("notes.txt", &b"plain text"[..], false),
+            ("program", b"\x7fELF\x02\x01\x01", true),
+            ("script", b"#!/bin/sh\necho", true),
+            ("archive.gz", b"\x1f\x8b\x08", false),
+        ];
+        for (name, content, _) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+
+        let config = Config {
+            scan_types: "exec".parse().unwrap(),
+            ..Config::default()
+        };
+        for (name, _, scanned) in files {
+            assert_eq!(
+                config.scan_types.matches(&dir.path().join(name)),
+                scanned,
+                "{name}"
+            );
+        }
+
+        let Some(scanner) = clamav(&config) else {
+            return;
+        };
+        for (name, _, _) in files {
+            let verdict = scanner.scan(&dir.path().join(name)).unwrap();
+            assert!(matches!(verdict, ScanResult::Clean), "{name}: {verdict:?}");
+        }
+        assert_eq!(scanner.stats.skipped_type.load(Ordering::Relaxed), 2);
+        assert_eq!(scanner.stats.scanned.load(Ordering::Relaxed), 2);
+    }
+
+    /// Gzip member with a single stored deflate block.
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let crc = !data.iter().fold(!0u32, |mut crc, &byte| {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            }
+            crc
+        });
+        let len = u16::try_from(data.len()).unwrap();
+
+        let mut member = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3, 1];
+        member.extend(len.to_le_bytes());
+        member.extend((!len).to_le_bytes());
+        member.extend(data);
+        member.extend(crc.to_le_bytes());
+        member.extend((data.len() as u32).to_le_bytes());
+        member
+    }
+
+    #[test]
+    fn nested_archive_over_the_depth_limit_is_blocked() {
+        let dir = TempDir::new("rvfs-archive-depth").unwrap();
+        let shallow = dir.path().join("shallow.gz");
+        fs::write(&shallow, gzip(b"content")).unwrap();
+        let nested = (0..4).fold(b"content".to_vec(), |data, _| gzip(&data));
+        let deep = dir.path().join("deep.gz");
+        fs::write(&deep, nested).unwrap();
+
+        let config = Config {
+            archive_limits: ArchiveLimits {
+                max_depth: 2,
+                ..ArchiveLimits::default()
+            },
+            ..Config::default()
+        };
+        let Some(scanner) = clamav(&config) else {
+            return;
+        };
+        if scanner.signature_count() == 0 {
+            eprintln!("Skipped, there are no signature databases");
+            return;
+        }
+
+        let verdict = scanner.scan(&shallow).unwrap();
+        assert!(matches!(verdict, ScanResult::Clean), "{verdict:?}");
+        let verdict = scanner.scan(&deep).unwrap();
+        assert!(matches!(verdict, ScanResult::Virus(_)), "{verdict:?}");
+    }
+
+    #[test]
+    fn archive_over_the_ratio_is_blocked() {
+        let dir = TempDir::new("rvfs-archive-ratio").unwrap();
+        let archive = dir.path().join("bomb.gz");
+        let mut bomb = gzip(b"content");
+        // Declares 1GiB of content
+        let size_offset = bomb.len() - 4;
+        bomb[size_offset..].copy_from_slice(&(1u32 << 30).to_le_bytes());
+        fs::write(&archive, bomb).unwrap();
+
+        let Some(scanner) = clamav(&Config::default()) else {
+            return;
+        };
+        let signature = match scanner.scan(&archive).unwrap() {
+            ScanResult::Virus(signature) => signature,
+            verdict => panic!("{verdict:?}"),
+        };
+        assert_eq!(signature, ARCHIVE_RATIO_SIGNATURE);
+        assert_eq!(scanner.stats.scanned.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn slow_scan_gets_the_timeout_verdict() {
+        for (policy, expected) in [
+            (TimeoutPolicy::Block, Some(TIMEOUT_SIGNATURE)),
+            (TimeoutPolicy::Allow, None),
+        ] {
+            let config = Config {
+                scan_timeout: Some(Duration::from_millis(50)),
+                timeout_policy: policy,
+                ..Config::default()
+            };
+            let Some(scanner) = clamav(&config) else {
+                return;
+            };
+
+            // Stands for an engine stuck on a malformed file until it's released
+            let (release, released) = mpsc::channel::<()>();
+            let verdict = scanner
+                .scan_engine(&"slow", move |_, _| {
+                    let _ = released.recv();
+                    Ok(ScanResult::Virus("Late-Verdict".to_owned()))
+                })
+                .unwrap();
+            let signature = match verdict {
+                ScanResult::Virus(signature) => Some(signature),
+                ScanResult::Clean | ScanResult::Whitelisted => None,
+            };
+            assert_eq!(signature.as_deref(), expected);
+            assert_eq!(scanner.stats.timed_out.load(Ordering::Relaxed), 1);
+
+            // The abandoned scan keeps running until its thread ends
+            assert_eq!(scanner.in_flight(), 1);
+            drop(release);
+            assert_eq!(wait_for_scans(&scanner, Duration::from_secs(5)), Ok(1));
+        }
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_scans() {
+        let config = Config {
+            scan_timeout: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let Some(scanner) = clamav(&config) else {
+            return;
+        };
+        let scanner = Arc::new(scanner);
+
+        let (release, released) = mpsc::channel::<()>();
+        let scanning = Arc::clone(&scanner);
+        let scan = thread::spawn(move || {
+            scanning.scan_engine(&"running", move |_, _| {
+                let _ = released.recv();
+                Ok(ScanResult::Clean)
+            })
+        });
+        while scanner.in_flight() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Stuck scans give up once the timeout is reached
+        assert_eq!(wait_for_scans(&*scanner, Duration::from_millis(50)), Err(1));
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(release);
+        });
+        assert_eq!(wait_for_scans(&*scanner, Duration::from_secs(5)), Ok(1));
+        let verdict = scan.join().unwrap().unwrap();
+        assert!(matches!(verdict, ScanResult::Clean), "{verdict:?}");
+        assert_eq!(scanner.stats.timed_out.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn scan_rate_lets_a_burst_through_then_waits() {
+        let started = Instant::now();
+        let scan_rate = ScanRate::new(10);
+
+        // A full bucket of scans starts right away
+        for _ in 0..10 {
+            scan_rate.wait();
+        }
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // The next ones wait for the bucket to refill, 100ms per scan
+        scan_rate.wait();
+        scan_rate.wait();
+        assert!(started.elapsed() >= Duration::from_millis(200));
     }
 }
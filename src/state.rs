@@ -0,0 +1,74 @@
+//! Snapshot of the inode graph that is kept between mounts, see `--state-file`.
+//!
+//! The file is a bincode encoded [`State`]. Items are listed parents first with paths relative to
+//! the proxy root, so a restore can rebuild the graph in a single pass. Snapshots made by another
+//! [`STATE_VERSION`], with other signatures or other scan settings are ignored as a whole.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{ensure, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::inode::ScanState;
+
+/// Bumped on every change of the format.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    /// Signature count of the engine the verdicts were made with.
+    pub signature_count: u32,
+    /// Scan settings that decide which files are scanned at all, e.g. exclude patterns.
+    pub scan_settings: String,
+    pub items: Vec<StateItem>,
+}
+
+/// Origin item as it was when the snapshot was made, it's restored only if it's still the same.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateItem {
+    /// Path relative to the proxy root.
+    pub path: PathBuf,
+    /// Ino at the time of the snapshot, names of a hard linked item share it.
+    pub ino: u64,
+    pub directory: bool,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub scan_state: ScanState,
+}
+
+impl State {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {path:?} state"))?;
+        let state: State = bincode::deserialize_from(BufReader::new(file))
+            .with_context(|| format!("Failed to decode {path:?} state"))?;
+        ensure!(
+            state.version == STATE_VERSION,
+            "{path:?} state has {} version, expected {STATE_VERSION}",
+            state.version
+        );
+
+        Ok(state)
+    }
+
+    /// Writes the snapshot next to `path` first, so a crash never leaves a truncated state behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let temporary = path.with_extension("tmp");
+        let file = File::create(&temporary)
+            .with_context(|| format!("Failed to create {temporary:?} state"))?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self)
+            .with_context(|| format!("Failed to encode {temporary:?} state"))?;
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write {temporary:?} state"))?;
+
+        fs::rename(&temporary, path)
+            .with_context(|| format!("Failed to move {temporary:?} state to {path:?}"))
+    }
+}
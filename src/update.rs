@@ -0,0 +1,145 @@
+//! Signature updates without a system freshclam service, see `--auto-update`.
+
+use std::{process::Command, sync::Arc, thread, time::Duration};
+
+use anyhow::{bail, Context};
+use log::{debug, error, info};
+
+use crate::scanner::Scanner;
+
+/// Exit status of freshclam when the databases are already up to date.
+const UP_TO_DATE: i32 = 1;
+
+/// Runs freshclam every `interval` and reloads the signatures once it downloaded new ones.
+pub fn spawn(interval: Duration, scanner: Arc<dyn Scanner>) -> anyhow::Result<()> {
+    spawn_with(interval, scanner, update)
+}
+
+/// [`spawn`] with `update` downloading the signatures instead of freshclam.
+fn spawn_with<U>(interval: Duration, scanner: Arc<dyn Scanner>, update: U) -> anyhow::Result<()>
+where
+    U: Fn() -> anyhow::Result<bool> + Send + 'static,
+{
+    thread::Builder::new()
+        .name("rvfs-update".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            match update() {
+                Ok(true) => {
+                    if let Err(err) = scanner.reload() {
+                        error!("Failed to reload updated signatures: {err:#}");
+                    }
+                }
+                Ok(false) => info!("Signatures are up to date"),
+                Err(err) => error!("Failed to update signatures: {err:#}"),
+            }
+        })
+        .context("Failed to spawn update thread")?;
+
+    Ok(())
+}
+
+/// Whether new signatures were downloaded.
+fn update() -> anyhow::Result<bool> {
+    info!("Updating signatures...");
+    let output = Command::new("freshclam")
+        .arg("--stdout")
+        .output()
+        .context("Failed to run freshclam")?;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        debug!("freshclam: {line}");
+    }
+
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(UP_TO_DATE) => Ok(false),
+        _ => bail!(
+            "freshclam exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+
+    use clamav_rs::engine::ScanResult;
+
+    use super::*;
+    use crate::{cache::CacheStats, scanner::ScanStats};
+
+    /// Counts reloads, scans aren't expected.
+    #[derive(Default)]
+    struct ReloadCounter(AtomicUsize);
+
+    impl Scanner for ReloadCounter {
+        fn scan(&self, _path: &Path) -> anyhow::Result<ScanResult> {
+            unreachable!()
+        }
+
+        fn scan_bytes(&self, _data: &[u8]) -> anyhow::Result<ScanResult> {
+            unreachable!()
+        }
+
+        fn signature_count(&self) -> u32 {
+            0
+        }
+
+        fn cache_stats(&self) -> CacheStats {
+            CacheStats::default()
+        }
+
+        fn report(&self) -> String {
+            String::new()
+        }
+
+        fn stats(&self) -> Option<&ScanStats> {
+            None
+        }
+
+        fn reload(&self) -> anyhow::Result<u32> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        }
+
+        fn in_flight(&self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn signatures_are_reloaded_after_updates() {
+        let scanner = Arc::new(ReloadCounter::default());
+        let updates = Arc::new(AtomicUsize::new(0));
+
+        // Every other one of the first updates downloads new signatures, the rest are up to date
+        // or fail
+        let counted = Arc::clone(&updates);
+        spawn_with(
+            Duration::from_millis(10),
+            scanner.clone(),
+            move || match counted.fetch_add(1, Ordering::SeqCst) {
+                0 | 2 | 4 => Ok(true),
+                3 => bail!("offline"),
+                _ => Ok(false),
+            },
+        )
+        .unwrap();
+
+        // Updates run one after another, reloads of the earlier ones are done by then
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while updates.load(Ordering::SeqCst) < 6 {
+            assert!(Instant::now() < deadline, "signatures weren't updated");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(scanner.0.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,118 @@
+//! Origin changes made behind the proxy's back, see `--watch`.
+//!
+//! Every origin directory the proxy lists gets an inotify watch. A background thread turns the
+//! events into a set of changed directories, which the session syncs with `add_folder` before it
+//! looks anything up, so new items are scanned and removed ones dropped like on a readdir.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Context;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::{debug, error, warn};
+
+/// Changes that add or remove directory entries, attributes are left to `--attr-passthrough`.
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ONLYDIR);
+
+pub struct Watcher {
+    watches: Watches,
+    /// Watched origin directories by their watch descriptors.
+    dirs: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>>,
+    /// Origin directories with changes since the last sync.
+    changed: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Set once the inotify watch limit is reached, no more watches are added after that.
+    exhausted: bool,
+}
+
+impl Watcher {
+    /// Starts the thread that reads inotify events, it runs for the rest of the process.
+    pub fn new() -> anyhow::Result<Self> {
+        let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+        let watches = inotify.watches();
+        let dirs: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>> = Arc::default();
+        let changed: Arc<Mutex<HashSet<PathBuf>>> = Arc::default();
+
+        let thread_dirs = Arc::clone(&dirs);
+        let thread_changed = Arc::clone(&changed);
+        thread::Builder::new()
+            .name("rvfs-watcher".to_owned())
+            .spawn(move || {
+                if let Err(err) = read_events(&mut inotify, &thread_dirs, &thread_changed) {
+                    error!("Failed to read inotify events, origin changes aren't watched: {err}");
+                }
+            })
+            .context("Failed to spawn watcher thread")?;
+
+        Ok(Self {
+            watches,
+            dirs,
+            changed,
+            exhausted: false,
+        })
+    }
+
+    /// Watches an origin directory, watching it again is a no-op.
+    ///
+    /// Running out of watches isn't fatal: directories that didn't get one are still synced when
+    /// they're listed.
+    pub fn watch(&mut self, dir: &Path) {
+        if self.exhausted {
+            return;
+        }
+
+        match self.watches.add(dir, WATCH_MASK) {
+            Ok(wd) => {
+                self.dirs.lock().unwrap().insert(wd, dir.to_path_buf());
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                warn!(
+                    "inotify watch limit reached at {dir:?}, further directories are synced only \
+                     when listed, raise fs.inotify.max_user_watches to watch them all"
+                );
+                self.exhausted = true;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to watch {dir:?}: {err}"),
+        }
+    }
+
+    /// Origin directories that changed since the last call.
+    pub fn take_changed(&self) -> Vec<PathBuf> {
+        self.changed.lock().unwrap().drain().collect()
+    }
+}
+
+/// Marks directories of incoming events as changed until reading fails.
+fn read_events(
+    inotify: &mut Inotify,
+    dirs: &Mutex<HashMap<WatchDescriptor, PathBuf>>,
+    changed: &Mutex<HashSet<PathBuf>>,
+) -> io::Result<()> {
+    let mut buffer = [0; 4096];
+    loop {
+        let events = inotify.read_events_blocking(&mut buffer)?;
+
+        let mut dirs = dirs.lock().unwrap();
+        let mut changed = changed.lock().unwrap();
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                warn!("inotify queue overflowed, syncing all watched directories");
+                changed.extend(dirs.values().cloned());
+            } else if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+            } else if let Some(dir) = dirs.get(&event.wd) {
+                debug!("{dir:?} changed on the origin: {:?}", event.name);
+                changed.insert(dir.clone());
+            }
+        }
+    }
+}